@@ -3,12 +3,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=migrations");
 
     println!("cargo:rerun-if-changed=proto/qcdn");
+    // every package is included into the same `grpc` module,
+    // so types shared from other packages have to be pointed there.
+    // Imported packages are regenerated without their messages,
+    // they are compiled afterwards to overwrite that output.
+    tonic_build::configure()
+        .extern_path(".qcdn.files", "crate::grpc")
+        .extern_path(".qcdn.nodes", "crate::grpc")
+        .compile(&["proto/qcdn/cluster.proto"], &["proto"])?;
+    tonic_build::configure()
+        .extern_path(".qcdn.files", "crate::grpc")
+        .compile(&["proto/qcdn/nodes.proto"], &["proto"])?;
     tonic_build::configure().compile(
-        &[
-            "proto/qcdn/general.proto",
-            "proto/qcdn/files.proto",
-            "proto/qcdn/nodes.proto",
-        ],
+        &["proto/qcdn/general.proto", "proto/qcdn/files.proto"],
         &["proto"],
     )?;
     Ok(())
@@ -0,0 +1,20 @@
+CREATE TABLE change_log(
+  seq             INTEGER PRIMARY KEY AUTOINCREMENT,
+  kind            INTEGER              NOT NULL,
+  file_version_id TEXT                 NOT NULL,
+  tag             TEXT                         ,
+  created_at      DATETIME             NOT NULL,
+  FOREIGN KEY (file_version_id) REFERENCES file_version(id)
+);
+
+-- kinds: 0 uploaded, 1 tagged, 2 deleted
+INSERT INTO change_log(kind, file_version_id, tag, created_at)
+SELECT kind, file_version_id, tag, ts
+FROM (
+  SELECT 0 kind, id file_version_id, NULL tag, created_at ts FROM file_version WHERE state = 2
+  UNION ALL
+  SELECT 1 kind, file_version_id, name tag, activated_at ts FROM file_version_tag
+  UNION ALL
+  SELECT 2 kind, id file_version_id, NULL tag, deleted_at ts FROM file_version WHERE deleted_at IS NOT NULL
+)
+ORDER BY ts, kind;
@@ -0,0 +1,6 @@
+-- replica side: seq of the last main server change applied locally
+CREATE TABLE sync_cursor(
+  id              INTEGER PRIMARY KEY CHECK (id = 0),
+  seq             INTEGER              NOT NULL,
+  updated_at      DATETIME             NOT NULL
+);
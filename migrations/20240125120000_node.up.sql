@@ -0,0 +1,9 @@
+CREATE TABLE node(
+  id              TEXT PRIMARY KEY     NOT NULL,
+  url             TEXT UNIQUE          NOT NULL,
+  ip_addr         TEXT                 NOT NULL,
+  seq             INTEGER              NOT NULL,
+  connected       BOOLEAN              NOT NULL,
+  first_seen_at   DATETIME             NOT NULL,
+  last_seen_at    DATETIME             NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE node DROP COLUMN longitude;
+ALTER TABLE node DROP COLUMN latitude;
@@ -0,0 +1,2 @@
+ALTER TABLE node ADD COLUMN latitude REAL;
+ALTER TABLE node ADD COLUMN longitude REAL;
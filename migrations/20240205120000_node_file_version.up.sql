@@ -0,0 +1,11 @@
+-- versions a node acknowledged to hold
+CREATE TABLE node_file_version(
+  node_id         TEXT                 NOT NULL,
+  file_version_id TEXT                 NOT NULL,
+  created_at      DATETIME             NOT NULL,
+  PRIMARY KEY (node_id, file_version_id),
+  FOREIGN KEY (node_id) REFERENCES node(id),
+  FOREIGN KEY (file_version_id) REFERENCES file_version(id)
+);
+
+CREATE INDEX node_file_version_file_version_id ON node_file_version(file_version_id);
@@ -0,0 +1 @@
+ALTER TABLE node ADD COLUMN grpc_url TEXT;
@@ -0,0 +1 @@
+ALTER TABLE node DROP COLUMN edge;
@@ -0,0 +1 @@
+ALTER TABLE node ADD COLUMN edge BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,7 @@
+-- main server cluster member: election term and vote, kept across restarts
+CREATE TABLE cluster_state(
+  id              INTEGER PRIMARY KEY CHECK (id = 0),
+  term            INTEGER              NOT NULL,
+  voted_for       TEXT                         ,
+  updated_at      DATETIME             NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE file_version DROP COLUMN sha256;
@@ -0,0 +1,2 @@
+-- hex sha256 of version bytes, unknown for versions uploaded before hashing
+ALTER TABLE file_version ADD COLUMN sha256 TEXT;
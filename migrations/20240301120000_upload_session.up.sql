@@ -0,0 +1,12 @@
+-- upload of a downloading version that can be resumed after its stream dropped
+CREATE TABLE upload_session(
+  id              TEXT PRIMARY KEY  NOT NULL,
+  received_bytes  INTEGER           NOT NULL,
+  consistency     INTEGER           NOT NULL,
+  min_replicas    INTEGER           NOT NULL,
+  timeout_ms      INTEGER                   ,
+  sha256          TEXT                      ,
+  created_at      DATETIME          NOT NULL,
+  updated_at      DATETIME          NOT NULL,
+  FOREIGN KEY (id) REFERENCES file_version(id)
+);
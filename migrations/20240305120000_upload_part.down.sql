@@ -0,0 +1,2 @@
+DROP TABLE upload_part;
+ALTER TABLE upload_session DROP COLUMN multipart;
@@ -0,0 +1,12 @@
+ALTER TABLE upload_session ADD COLUMN multipart INTEGER NOT NULL DEFAULT 0;
+
+-- stored part of a multipart upload, assembled into the version on completion
+CREATE TABLE upload_part(
+  upload_id       TEXT              NOT NULL,
+  part_number     INTEGER           NOT NULL,
+  size            INTEGER           NOT NULL,
+  sha256          TEXT              NOT NULL,
+  created_at      DATETIME          NOT NULL,
+  PRIMARY KEY (upload_id, part_number),
+  FOREIGN KEY (upload_id) REFERENCES upload_session(id)
+);
@@ -0,0 +1,13 @@
+-- bytes shared by versions with the same content, counted by live ready versions
+CREATE TABLE blob(
+  sha256          TEXT PRIMARY KEY  NOT NULL,
+  size         INTEGER              NOT NULL,
+  refs         INTEGER              NOT NULL,
+  created_at  DATETIME              NOT NULL
+);
+
+INSERT INTO blob(sha256, size, refs, created_at)
+SELECT sha256, MAX(size), COUNT(*), MIN(created_at)
+FROM file_version
+WHERE state = 2 AND deleted_at IS NULL AND sha256 IS NOT NULL
+GROUP BY sha256;
@@ -0,0 +1 @@
+ALTER TABLE change_log DROP COLUMN term;
@@ -0,0 +1,2 @@
+-- election term of the leader that appended the change, 0 outside a cluster
+ALTER TABLE change_log ADD COLUMN term INTEGER NOT NULL DEFAULT 0;
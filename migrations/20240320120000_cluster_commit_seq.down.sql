@@ -0,0 +1 @@
+ALTER TABLE cluster_state DROP COLUMN commit_seq;
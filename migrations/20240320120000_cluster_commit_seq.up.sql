@@ -0,0 +1,2 @@
+-- last seq the member knows most members hold, so a restarted cluster serves nodes right away
+ALTER TABLE cluster_state ADD COLUMN commit_seq INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+CREATE TABLE change_log_next(
+  seq             INTEGER PRIMARY KEY AUTOINCREMENT,
+  kind            INTEGER              NOT NULL,
+  file_version_id TEXT                 NOT NULL,
+  tag             TEXT                         ,
+  created_at      DATETIME             NOT NULL,
+  term            INTEGER              NOT NULL DEFAULT 0,
+  FOREIGN KEY (file_version_id) REFERENCES file_version(id)
+);
+
+INSERT INTO change_log_next(seq, kind, file_version_id, tag, created_at, term)
+SELECT seq, kind, file_version_id, tag, created_at, term FROM change_log;
+
+DROP TABLE change_log;
+ALTER TABLE change_log_next RENAME TO change_log;
@@ -0,0 +1,18 @@
+-- without AUTOINCREMENT the next seq follows the last change, seqs of changes a follower
+-- took back are used again and the log stays without gaps
+CREATE TABLE change_log_next(
+  seq             INTEGER PRIMARY KEY,
+  kind            INTEGER              NOT NULL,
+  file_version_id TEXT                 NOT NULL,
+  tag             TEXT                         ,
+  created_at      DATETIME             NOT NULL,
+  term            INTEGER              NOT NULL DEFAULT 0,
+  FOREIGN KEY (file_version_id) REFERENCES file_version(id)
+);
+
+INSERT INTO change_log_next(seq, kind, file_version_id, tag, created_at, term)
+SELECT seq, kind, file_version_id, tag, created_at, term FROM change_log;
+
+DROP TABLE change_log;
+ALTER TABLE change_log_next RENAME TO change_log;
+DELETE FROM sqlite_sequence WHERE name = 'change_log';
@@ -1,26 +1,38 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use axum::extract::FromRef;
 
-use crate::{config::CliConfig, database::Database, storage::Storage};
+use crate::{
+    cache::EdgeCache, config::CliConfig, database::Database, geo::GeoIp, storage::Storage,
+};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub storage: Storage,
     pub db: Database,
     pub config: Arc<CliConfig>,
+    pub geoip: Option<GeoIp>,
+    pub cache: Option<EdgeCache>,
 }
 
 impl AppState {
     pub async fn from_config(config: &CliConfig) -> Result<Self> {
         let (storage, db) = tokio::try_join!(
-            Storage::from_path(&config.storage_dir),
+            Storage::from_config(config),
             Database::create_and_migrate(&config.db_path)
         )?;
+        let geoip = config.geoip_db.as_deref().map(GeoIp::open).transpose()?;
+        let cache = match (config.edge_cache_size, &config.main_server_url) {
+            (Some(budget), Some(url)) => Some(EdgeCache::open(url, budget, &storage, &db).await?),
+            _ => None,
+        };
         let state = Self {
             storage,
             db,
             config: Arc::new(config.clone()),
+            geoip,
+            cache,
         };
         tracing::info!("{:?}", state);
         Ok(state)
@@ -32,3 +44,9 @@ impl AppState {
         Arc::new(self)
     }
 }
+
+impl FromRef<AppState> for Arc<CliConfig> {
+    fn from_ref(app_state: &AppState) -> Arc<CliConfig> {
+        app_state.config.clone()
+    }
+}
@@ -1,14 +1,231 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
 use qcdn::{
-    config::CliConfig,
+    config::{CliConfig, ReplicationMode},
     grpc::{
-        qcdn_files_client::QcdnFilesClient, qcdn_general_client::QcdnGeneralClient,
+        qcdn_files_client::QcdnFilesClient,
+        qcdn_files_server::QcdnFilesServer,
+        qcdn_general_client::QcdnGeneralClient,
+        qcdn_general_server::QcdnGeneralServer,
         qcdn_nodes_client::QcdnNodesClient,
+        qcdn_replica_server::QcdnReplicaServer,
+        server::{
+            files::FilesService, general::GeneralService, replica::ReplicaService,
+            upstream::Upstream,
+        },
+        AckRequest, ConnectionRequest, PingMessage,
+    },
+    setup_tracing_subscriber,
+    sync::{
+        backoff, cluster::leader_hint, hub::SyncHub, replica::Replica, status::ReplicationStatus,
     },
-    setup_tracing_subscriber, AppState,
+    web, AppState,
+};
+use tokio::{
+    sync::watch,
+    time::{Instant, MissedTickBehavior},
+};
+use tonic::{
+    transport::{Endpoint, Server},
+    Request,
 };
 
+async fn sync_with_main(
+    config: &CliConfig,
+    app_state: &AppState,
+    status: &ReplicationStatus,
+    addr: &str,
+    attempts: &mut u32,
+) -> Result<()> {
+    let addr = addr.to_string();
+    let mut general = QcdnGeneralClient::connect(addr.clone()).await?;
+    let ping = PingMessage {
+        timestamp: Some(SystemTime::now().into()),
+    };
+    let response = general.ping(Request::new(ping)).await?.into_inner();
+    tracing::info!("Main server is reachable {response:?}");
+
+    let files = QcdnFilesClient::connect(addr.clone()).await?;
+    let mut nodes = QcdnNodesClient::connect(addr).await?;
+
+    let mut replica =
+        Replica::load(app_state.clone().shared(), Some(files), status.clone()).await?;
+    if replica.cursor() == 0 {
+        tracing::info!("Bootstrapping from main server snapshot");
+        replica.bootstrap(&mut nodes).await?;
+    }
+
+    let mut stream = nodes
+        .connect_node(Request::new(ConnectionRequest::from_config(
+            config,
+            replica.cursor(),
+            app_state.cache.is_some(),
+        )))
+        .await?
+        .into_inner();
+
+    *attempts = 0;
+    status.connected();
+    tracing::info!(
+        "Connected to main server, replicating from seq {}",
+        replica.cursor()
+    );
+
+    // acks run alongside applying, so only the latest cursor is sent when main is slow
+    let (applied, mut to_ack) = watch::channel(replica.cursor());
+    let url = config.base_url.clone();
+
+    // reconciliation runs between applied messages, so repairs never race with sync
+    let mut reconcile_nodes = nodes.clone();
+    let reconcile_enabled = config.reconcile_interval > 0;
+    let reconcile_period = Duration::from_secs(config.reconcile_interval.max(1));
+    let mut reconcile =
+        tokio::time::interval_at(Instant::now() + reconcile_period, reconcile_period);
+    reconcile.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let apply = async move {
+        loop {
+            tokio::select! {
+                message = stream.message() => {
+                    let Some(message) = message? else {
+                        break;
+                    };
+                    tracing::trace!("Applying {} change at seq {}", message.kind(), message.seq);
+                    replica.apply(message).await?;
+                    applied.send_if_modified(|seq| {
+                        let changed = *seq != replica.cursor();
+                        *seq = replica.cursor();
+                        changed
+                    });
+                }
+                _ = reconcile.tick(), if reconcile_enabled => {
+                    if let Err(e) = replica.reconcile(&mut reconcile_nodes).await {
+                        tracing::warn!("Reconciliation with main server failed: {e}");
+                    }
+                }
+            }
+        }
+        anyhow::Ok(())
+    };
+    let ack = async move {
+        while to_ack.changed().await.is_ok() {
+            let seq = *to_ack.borrow_and_update();
+            nodes
+                .ack_sync(Request::new(AckRequest {
+                    url: url.clone(),
+                    seq,
+                }))
+                .await?;
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(apply, ack)?;
+
+    Ok(())
+}
+
+async fn replicate(
+    config: &CliConfig,
+    app_state: AppState,
+    status: ReplicationStatus,
+) -> Result<()> {
+    if config.replication_mode == ReplicationMode::Push {
+        tracing::info!("Waiting for main server to push changes");
+        return Ok(());
+    }
+
+    let min_delay = Duration::from_millis(config.reconnect_min_delay);
+    let max_delay = Duration::from_millis(config.reconnect_max_delay);
+    let mut attempts = 0;
+
+    // clustered main servers are tried in turn, unless one of them points to the leader
+    let main_servers = config.main_servers();
+    let mut next = 0;
+    let mut addr = main_servers
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("main_server_url or cluster_peers is required"))?;
+
+    loop {
+        status.connecting(attempts);
+        match sync_with_main(config, &app_state, &status, &addr, &mut attempts).await {
+            Ok(()) => {
+                tracing::warn!("Main server closed the sync stream");
+                status.disconnected(None);
+            }
+            Err(e) => {
+                tracing::warn!("Sync with main server {addr} failed: {e}");
+                status.disconnected(Some(e.to_string()));
+                match leader_hint(&e) {
+                    Some(leader) => {
+                        tracing::info!("Main server {addr} points to leader {leader}");
+                        addr = leader;
+                    }
+                    None => {
+                        next = (next + 1) % main_servers.len();
+                        addr = main_servers[next].clone();
+                    }
+                }
+            }
+        }
+        attempts += 1;
+
+        let delay = backoff::jittered(attempts, min_delay, max_delay);
+        tracing::info!("Reconnecting to main server {addr} in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn serve_grpc(
+    config: &CliConfig,
+    app_state: AppState,
+    status: ReplicationStatus,
+) -> Result<()> {
+    let addr = format!("{}:{}", config.host, config.grpc_port).parse()?;
+    // any cluster member forwards writes to its leader
+    let main_server_url = config
+        .main_servers()
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("main_server_url or cluster_peers is required"))?;
+    let read_your_writes = config.read_your_writes.then(|| {
+        (
+            status.clone(),
+            Duration::from_secs(config.replication_timeout),
+        )
+    });
+    let upstream = Upstream::new(
+        QcdnFilesClient::new(Endpoint::from_shared(main_server_url)?.connect_lazy()),
+        read_your_writes,
+    );
+    let replica = (config.replication_mode == ReplicationMode::Push).then(|| {
+        QcdnReplicaServer::new(ReplicaService::new(
+            app_state.clone().shared(),
+            status.clone(),
+        ))
+    });
+    let general = QcdnGeneralServer::new(GeneralService::with_replication(status));
+    // peers download versions this node holds, writes go to main server, nothing is published from a node
+    let files = QcdnFilesServer::new(FilesService::with_upstream(
+        app_state.shared(),
+        SyncHub::new(config.sync_buffer),
+        upstream,
+    ));
+
+    tracing::info!("Serving gRPC on: {addr}");
+    Server::builder()
+        .add_service(general)
+        .add_service(files)
+        .add_optional_service(replica)
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
 
     let config = CliConfig::init();
@@ -17,17 +234,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing::info!("{:?}", config);
 
-    let _app_state = AppState::from_config(&config).await?.shared();
-
-    let addr = config
-        .main_server_url
-        .expect("Master address must be present");
-
-    let _general = QcdnGeneralClient::connect(addr.clone()).await?;
-
-    let _files = QcdnFilesClient::connect(addr.clone()).await?;
+    let app_state = AppState::from_config(&config).await?;
+    let status = ReplicationStatus::new(Replica::stored_cursor(&app_state).await?);
 
-    let _nodes = QcdnNodesClient::connect(addr).await?;
+    tokio::try_join!(
+        web::run(&config, app_state.clone()),
+        serve_grpc(&config, app_state.clone(), status.clone()),
+        replicate(&config, app_state, status)
+    )?;
 
     Ok(())
 }
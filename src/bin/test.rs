@@ -47,13 +47,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing::info!("{response:?}");
 
-    let test_file: &[u8] = include_bytes!("../../data/input/test.txt");
+    let test_file: &[u8] = b"qcdn test upload\n";
     let init_message = upload_request::Request::Meta(UploadMeta {
         name: "test".to_string(),
         dir: "test".to_string(),
         size: test_file.len() as u64,
         file_type: FileType::Text.into(),
         version: "1".to_string(),
+        ..Default::default()
     });
     let chunked = tokio_stream::iter(test_file.chunks(4096))
         .map(|bytes| FilePart {
@@ -0,0 +1,458 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use axum::{body::Body, extract::FromRef};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, Endpoint};
+use uuid::Uuid;
+
+use crate::{
+    database::Database,
+    grpc::{qcdn_files_client::QcdnFilesClient, sync_message::MessageType, DownloadRequest},
+    storage::{self, Storage},
+    sync::reconcile::Catalogue,
+    AppState,
+};
+
+const STREAM_BUFFER: usize = 16;
+
+struct Entry {
+    dir: String,
+    size: u64,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    used: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, id: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(id) else {
+            return false;
+        };
+        self.tick += 1;
+        self.order.remove(&entry.tick);
+        self.order.insert(self.tick, id.to_string());
+        entry.tick = self.tick;
+        true
+    }
+
+    /// Returns evicted (dir, filename) pairs, an id stored already takes the new size
+    fn insert(&mut self, id: &str, dir: &str, size: u64, budget: u64) -> Vec<(String, String)> {
+        self.remove(id);
+        self.tick += 1;
+        self.order.insert(self.tick, id.to_string());
+        self.entries.insert(
+            id.to_string(),
+            Entry {
+                dir: dir.to_string(),
+                size,
+                tick: self.tick,
+            },
+        );
+        self.used += size;
+
+        let mut evicted = vec![];
+        while self.used > budget {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.remove(&oldest) {
+                evicted.push((entry.dir, oldest));
+            }
+        }
+        evicted
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Entry> {
+        let entry = self.entries.remove(id)?;
+        self.order.remove(&entry.tick);
+        self.used -= entry.size;
+        Some(entry)
+    }
+}
+
+/// Version bytes an edge node pulled from main server on request, keyed by storage filename
+/// so versions sharing a blob share an entry,
+/// least recently used entries are evicted once the byte budget is exceeded
+#[derive(Clone)]
+pub struct EdgeCache {
+    lru: Arc<Mutex<Lru>>,
+    upstream: QcdnFilesClient<Channel>,
+    storage: Storage,
+    budget: u64,
+}
+
+impl Debug for EdgeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lru = self.lru.lock().unwrap();
+        f.debug_struct("EdgeCache")
+            .field("budget", &self.budget)
+            .field("used", &lru.used)
+            .field("versions", &lru.entries.len())
+            .finish()
+    }
+}
+
+impl EdgeCache {
+    /// Indexes versions already in storage, oldest modified first
+    pub async fn open(
+        upstream_url: &str,
+        budget: u64,
+        storage: &Storage,
+        db: &Database,
+    ) -> Result<Self> {
+        let upstream =
+            QcdnFilesClient::new(Endpoint::from_shared(upstream_url.to_string())?.connect_lazy());
+
+        let mut connection = db.connect().await?;
+        let catalogue = Catalogue::load(&mut connection).await?;
+
+        let mut stored = vec![];
+        for message in catalogue.uploads() {
+            let Some(MessageType::Uploaded(uploaded)) = &message.message_type else {
+                continue;
+            };
+            let (dir, filename) = storage::version_location(
+                &uploaded.dir_id,
+                &uploaded.file_version_id,
+                uploaded.sha256.as_deref(),
+            );
+            let Some(stat) = storage.stat(&dir, &filename).await? else {
+                continue;
+            };
+            if stat.size != uploaded.size {
+                continue;
+            }
+            stored.push((stat.modified, dir, filename, uploaded.size));
+        }
+        stored.sort();
+
+        let cache = Self {
+            lru: Default::default(),
+            upstream,
+            storage: storage.clone(),
+            budget,
+        };
+        for (_, dir, filename, size) in stored {
+            cache.insert(&filename, &dir, size).await;
+        }
+
+        Ok(cache)
+    }
+
+    pub fn contains(&self, filename: &str) -> bool {
+        self.lru.lock().unwrap().entries.contains_key(filename)
+    }
+
+    /// Marks stored bytes as recently used, false on a miss
+    pub fn touch(&self, filename: &str) -> bool {
+        self.lru.lock().unwrap().touch(filename)
+    }
+
+    /// Versions larger than the whole budget are streamed without being kept
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.budget
+    }
+
+    pub async fn remove(&self, filename: &str) {
+        let entry = self.lru.lock().unwrap().remove(filename);
+        if let Some(entry) = entry {
+            self.storage.remove_stored(&entry.dir, filename).await.ok();
+        }
+    }
+
+    /// Pulls the whole version into storage under `dir`/`filename`
+    pub async fn fill(
+        &self,
+        dir: &str,
+        filename: &str,
+        file_version_id: &str,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<()> {
+        self.pull(dir, filename, file_version_id, size, sha256, None)
+            .await
+    }
+
+    /// Body streaming the version from main server while it is written into storage,
+    /// the pull keeps going when client goes away
+    pub fn stream(
+        &self,
+        dir: &str,
+        filename: &str,
+        file_version_id: &str,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Body {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let cache = self.clone();
+        let dir = dir.to_string();
+        let filename = filename.to_string();
+        let file_version_id = file_version_id.to_string();
+        let sha256 = sha256.map(str::to_string);
+        tokio::spawn(async move {
+            let pulled = cache
+                .pull(
+                    &dir,
+                    &filename,
+                    &file_version_id,
+                    size,
+                    sha256.as_deref(),
+                    Some(&tx),
+                )
+                .await;
+            if let Err(e) = pulled {
+                tracing::warn!("Pulling {file_version_id} from main server failed: {e}");
+                tx.send(Err(io::Error::other(e.to_string()))).await.ok();
+            }
+        });
+        Body::from_stream(ReceiverStream::new(rx))
+    }
+
+    async fn pull(
+        &self,
+        dir: &str,
+        filename: &str,
+        file_version_id: &str,
+        size: u64,
+        sha256: Option<&str>,
+        tx: Option<&mpsc::Sender<io::Result<Bytes>>>,
+    ) -> Result<()> {
+        // concurrent pulls of the same bytes write their own part files
+        let part = format!("{filename}.{}.part", Uuid::now_v7());
+        let mut file = self.storage.create_file(dir, &part).await?;
+
+        let res = async {
+            let mut stream = self
+                .upstream
+                .clone()
+                .download(DownloadRequest {
+                    file_version_id: file_version_id.to_string(),
+                })
+                .await?
+                .into_inner();
+
+            let mut hasher = Sha256::new();
+            let mut received_bytes = 0;
+            while let Some(part) = stream.message().await? {
+                received_bytes += part.bytes.len() as u64;
+                if received_bytes > size {
+                    bail!("file transmission corrupted, got more than expected {size} bytes")
+                }
+                hasher.update(&part.bytes);
+                file.write_all(&part.bytes).await?;
+                if let Some(tx) = tx {
+                    tx.send(Ok(Bytes::from(part.bytes))).await.ok();
+                }
+            }
+            file.flush().await?;
+
+            if received_bytes != size {
+                bail!("file transmission corrupted, expected {size} bytes, got {received_bytes}")
+            }
+            if let Some(expected) = sha256 {
+                let actual = hex::encode(hasher.finalize());
+                if !actual.eq_ignore_ascii_case(expected) {
+                    bail!("file content corrupted, expected sha256 {expected}, got {actual}")
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+
+        if res.is_err() || !self.fits(size) {
+            self.storage.remove_file(dir, &part).await.ok();
+            return res;
+        }
+
+        self.storage.store(dir, &part, dir, filename).await?;
+        self.insert(filename, dir, size).await;
+        tracing::debug!("Cached {file_version_id}");
+
+        Ok(())
+    }
+
+    async fn insert(&self, filename: &str, dir: &str, size: u64) {
+        let evicted = self
+            .lru
+            .lock()
+            .unwrap()
+            .insert(filename, dir, size, self.budget);
+        for (dir, filename) in evicted {
+            tracing::debug!("Evicted {filename} from cache");
+            self.storage.remove_stored(&dir, &filename).await.ok();
+        }
+    }
+}
+
+impl FromRef<AppState> for Option<EdgeCache> {
+    fn from_ref(app_state: &AppState) -> Option<EdgeCache> {
+        app_state.cache.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        config::CliConfig,
+        grpc::{upload_request, FilePart, FileType, UploadMeta, UploadRequest, UploadResponse},
+        manager,
+    };
+
+    fn evicted(ids: &[&str]) -> Vec<(String, String)> {
+        ids.iter()
+            .map(|id| ("dir".to_string(), id.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted_first() {
+        let mut lru = Lru::default();
+        assert!(lru.insert("a", "dir", 4, 10).is_empty());
+        assert!(lru.insert("b", "dir", 4, 10).is_empty());
+        assert_eq!(lru.insert("c", "dir", 4, 10), evicted(&["a"]));
+        assert_eq!(lru.used, 8);
+
+        assert!(lru.touch("b"));
+        assert!(!lru.touch("a"));
+        assert_eq!(lru.insert("d", "dir", 4, 10), evicted(&["c"]));
+        assert_eq!(lru.used, 8);
+
+        // one entry over the budget takes every older one with it
+        assert_eq!(lru.insert("e", "dir", 9, 10), evicted(&["b", "d"]));
+        assert_eq!(lru.used, 9);
+    }
+
+    #[test]
+    fn insert_of_stored_id_takes_new_size() {
+        let mut lru = Lru::default();
+        lru.insert("a", "dir", 4, 10);
+        lru.insert("b", "dir", 4, 10);
+
+        assert_eq!(lru.insert("a", "dir", 8, 10), evicted(&["b"]));
+        assert_eq!(lru.used, 8);
+        assert_eq!(lru.entries["a"].size, 8);
+
+        assert!(lru.insert("a", "dir", 2, 10).is_empty());
+        assert_eq!(lru.used, 2);
+        assert_eq!(lru.order.len(), 1);
+    }
+
+    #[test]
+    fn remove_releases_bytes() {
+        let mut lru = Lru::default();
+        lru.insert("a", "dir", 4, 10);
+        lru.insert("b", "dir", 4, 10);
+
+        assert_eq!(lru.remove("a").map(|entry| entry.size), Some(4));
+        assert!(lru.remove("a").is_none());
+        assert_eq!(lru.used, 4);
+        assert_eq!(lru.order.values().collect::<Vec<_>>(), ["b"]);
+
+        // b is the only one left to evict
+        assert_eq!(lru.insert("c", "dir", 8, 10), evicted(&["b"]));
+        assert_eq!(lru.used, 8);
+    }
+
+    async fn upload(url: &str, bytes: &[u8]) -> UploadResponse {
+        let requests = vec![
+            UploadRequest {
+                request: Some(upload_request::Request::Meta(UploadMeta {
+                    dir: "assets".to_string(),
+                    name: "a.txt".to_string(),
+                    file_type: FileType::Text.into(),
+                    version: "1".to_string(),
+                    size: bytes.len() as u64,
+                    ..Default::default()
+                })),
+            },
+            UploadRequest {
+                request: Some(upload_request::Request::Part(FilePart {
+                    bytes: bytes.to_vec(),
+                })),
+            },
+        ];
+        for _ in 0..100 {
+            if let Ok(mut files) = QcdnFilesClient::connect(url.to_string()).await {
+                return files
+                    .upload(tokio_stream::iter(requests))
+                    .await
+                    .unwrap()
+                    .into_inner();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("main server on {url} did not start")
+    }
+
+    #[tokio::test]
+    async fn larger_than_budget_is_streamed_but_not_kept() {
+        let root = std::env::temp_dir().join("qcdn-test-edge-cache");
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let main = CliConfig::try_parse_from([
+            "manager".to_string(),
+            format!("--port={port}"),
+            format!("--db-path={}", root.join("main.db").display()),
+            format!("--storage-dir={}", root.join("main").display()),
+        ])
+        .unwrap();
+        tokio::spawn(async move { manager::run(&main).await });
+        let url = format!("http://127.0.0.1:{port}");
+        let uploaded = upload(&url, b"0123456789").await;
+        let sha256 = uploaded.sha256.as_str();
+        let (dir, filename) =
+            storage::version_location(&uploaded.dir_id, &uploaded.file_version_id, Some(sha256));
+
+        let edge = CliConfig::try_parse_from([
+            "node".to_string(),
+            format!("--storage-dir={}", root.join("edge").display()),
+        ])
+        .unwrap();
+        let storage = Storage::from_config(&edge).await.unwrap();
+        let db = Database::temporary("edge-cache").await.unwrap();
+
+        for (budget, kept) in [(5, false), (10, true)] {
+            let cache = EdgeCache::open(&url, budget, &storage, &db).await.unwrap();
+            let body = cache.stream(&dir, &filename, &uploaded.file_version_id, 10, Some(sha256));
+            let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            assert_eq!(&bytes[..], b"0123456789");
+
+            assert_eq!(cache.contains(&filename), kept);
+            assert_eq!(cache.lru.lock().unwrap().used, if kept { 10 } else { 0 });
+            let stored = storage.stat(&dir, &filename).await.unwrap();
+            assert_eq!(stored.is_some(), kept);
+            // no part file is left behind
+            let files = std::fs::read_dir(root.join("edge").join(&dir))
+                .map(|files| files.count())
+                .unwrap_or(0);
+            assert_eq!(files, usize::from(kept));
+        }
+
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -1,8 +1,32 @@
 use std::path::PathBuf;
 
-use clap::{arg, command, value_parser, Parser};
+use clap::{value_parser, Parser, ValueEnum};
 use tracing_subscriber::filter;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WebMode {
+    /// Serve files from local storage
+    Serve,
+    /// Redirect to the closest node holding the file, serving locally otherwise
+    Redirect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReplicationMode {
+    /// Node connects to main server and pulls changes
+    Pull,
+    /// Main server dials node and pushes changes
+    Push,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageBackendKind {
+    /// Files under storage dir
+    Fs,
+    /// Objects in S3 compatible bucket, storage dir keeps files being written
+    S3,
+}
+
 #[derive(Debug, Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct CliConfig {
@@ -24,6 +48,28 @@ pub struct CliConfig {
     )]
     pub storage_dir: PathBuf,
 
+    #[arg(
+        long,
+        help = "Where finished files are stored",
+        env = "FS_STORAGE_BACKEND",
+        value_enum,
+        default_value = "fs"
+    )]
+    pub storage_backend: StorageBackendKind,
+
+    #[arg(long, help = "S3 bucket", env = "FS_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    #[arg(
+        long,
+        help = "S3 compatible endpoint, AWS when not set",
+        env = "FS_S3_ENDPOINT"
+    )]
+    pub s3_endpoint: Option<String>,
+
+    #[arg(long, help = "S3 region", env = "FS_S3_REGION")]
+    pub s3_region: Option<String>,
+
     #[arg(
         short,
         long,
@@ -54,12 +100,263 @@ pub struct CliConfig {
     #[arg(long, help = "Log level", env = "FS_LOG_LEVEL", default_value = "info")]
     pub log_level: filter::LevelFilter,
 
-    #[arg(short, long, help = "Url to main server", env = "FS_MAIN_SERVER_URL")]
+    #[arg(
+        short,
+        long,
+        help = "Url to main server",
+        env = "FS_MAIN_SERVER_URL",
+        required_if_eq("replication_mode", "push")
+    )]
     pub main_server_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Cache max-age in seconds for urls resolved by tag",
+        env = "FS_TAG_CACHE_TTL",
+        default_value = "60"
+    )]
+    pub tag_cache_ttl: u32,
+
+    #[arg(
+        long,
+        help = "Amount of live sync messages buffered for each connected node",
+        env = "FS_SYNC_BUFFER",
+        default_value = "1024"
+    )]
+    pub sync_buffer: usize,
+
+    #[arg(
+        long,
+        help = "Seconds upload waits for replicas when consistency is requested",
+        env = "FS_REPLICATION_TIMEOUT",
+        default_value = "30"
+    )]
+    pub replication_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Seconds a resumable or multipart upload is kept without progress before its bytes are dropped",
+        env = "FS_UPLOAD_SESSION_TTL",
+        default_value = "3600"
+    )]
+    pub upload_session_ttl: u64,
+
+    #[arg(
+        long,
+        help = "TCP port node serves gRPC api on",
+        env = "FS_GRPC_PORT",
+        default_value = "8090",
+        value_parser = value_parser!(u16).range(1..)
+    )]
+    pub grpc_port: u16,
+
+    #[arg(
+        long,
+        help = "Url other nodes reach node gRPC api on [default: http://<host>:<grpc_port>]",
+        env = "FS_GRPC_URL"
+    )]
+    pub grpc_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Initial delay in milliseconds before node reconnects to main server",
+        env = "FS_RECONNECT_MIN_DELAY",
+        default_value = "500"
+    )]
+    pub reconnect_min_delay: u64,
+
+    #[arg(
+        long,
+        help = "Max delay in milliseconds before node reconnects to main server",
+        env = "FS_RECONNECT_MAX_DELAY",
+        default_value = "30000"
+    )]
+    pub reconnect_max_delay: u64,
+
+    #[arg(
+        long,
+        help = "Interval in seconds between node catalogue reconciliations with main server, 0 disables",
+        env = "FS_RECONCILE_INTERVAL",
+        default_value = "300"
+    )]
+    pub reconcile_interval: u64,
+
+    #[arg(
+        long,
+        help = "Byte budget of pull-through cache, turns node into an edge keeping only requested versions",
+        env = "FS_EDGE_CACHE_SIZE",
+        requires = "main_server_url"
+    )]
+    pub edge_cache_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Node waits for forwarded writes to be applied locally before answering",
+        env = "FS_READ_YOUR_WRITES"
+    )]
+    pub read_your_writes: bool,
+
+    #[arg(
+        long,
+        help = "How node replicates from main server",
+        env = "FS_REPLICATION_MODE",
+        value_enum,
+        default_value = "pull"
+    )]
+    pub replication_mode: ReplicationMode,
+
+    #[arg(
+        long,
+        help = "gRPC urls of push mode nodes main server dials, comma separated",
+        env = "FS_PUSH_NODES",
+        value_delimiter = ','
+    )]
+    pub push_nodes: Vec<String>,
+
+    #[arg(
+        long,
+        help = "gRPC urls of the other main servers in the cluster, comma separated, nodes fall back to them when main server is unreachable",
+        env = "FS_CLUSTER_PEERS",
+        value_delimiter = ','
+    )]
+    pub cluster_peers: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Url other main servers reach this one on [default: http://<host>:<port>]",
+        env = "FS_CLUSTER_URL"
+    )]
+    pub cluster_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Milliseconds without a leader heartbeat before main server stands for election",
+        env = "FS_ELECTION_TIMEOUT",
+        default_value = "1000"
+    )]
+    pub election_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Path to MaxMind city database (.mmdb)",
+        env = "FS_GEOIP_DB"
+    )]
+    pub geoip_db: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "How web server answers file requests",
+        env = "FS_WEB_MODE",
+        value_enum,
+        default_value = "serve"
+    )]
+    pub web_mode: WebMode,
+
+    #[arg(
+        long,
+        help = "Node location latitude",
+        env = "FS_LATITUDE",
+        allow_negative_numbers = true
+    )]
+    pub latitude: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Node location longitude",
+        env = "FS_LONGITUDE",
+        allow_negative_numbers = true
+    )]
+    pub longitude: Option<f64>,
 }
 
 impl CliConfig {
     pub fn init() -> CliConfig {
         CliConfig::parse()
     }
+
+    pub fn grpc_url(&self) -> String {
+        self.grpc_url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}:{}", self.host, self.grpc_port))
+    }
+
+    pub fn cluster_url(&self) -> String {
+        self.cluster_url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}:{}", self.host, self.port))
+    }
+
+    /// Main servers a node replicates from and forwards writes to, `main_server_url` first
+    pub fn main_servers(&self) -> Vec<String> {
+        self.main_server_url
+            .iter()
+            .chain(&self.cluster_peers)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edge_cache_requires_main_server() {
+        assert!(CliConfig::try_parse_from(["node", "--edge-cache-size", "1024"]).is_err());
+
+        let config = CliConfig::try_parse_from([
+            "node",
+            "--edge-cache-size",
+            "1024",
+            "--main-server-url",
+            "http://main:8080",
+        ])
+        .unwrap();
+        assert_eq!(config.edge_cache_size, Some(1024));
+    }
+
+    #[test]
+    fn push_mode_requires_main_server() {
+        assert!(CliConfig::try_parse_from(["node", "--replication-mode", "push"]).is_err());
+
+        let config = CliConfig::try_parse_from([
+            "node",
+            "--replication-mode",
+            "push",
+            "--main-server-url",
+            "http://main:8080",
+        ])
+        .unwrap();
+        assert_eq!(config.replication_mode, ReplicationMode::Push);
+    }
+
+    #[test]
+    fn main_servers_fall_back_to_cluster_peers() {
+        let config = CliConfig::try_parse_from(["node"]).unwrap();
+        assert!(config.main_servers().is_empty());
+
+        let config = CliConfig::try_parse_from([
+            "node",
+            "--cluster-peers",
+            "http://main-a:8080,http://main-b:8080",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.main_servers(),
+            ["http://main-a:8080", "http://main-b:8080"]
+        );
+
+        let config = CliConfig::try_parse_from([
+            "node",
+            "--main-server-url",
+            "http://main:8080",
+            "--cluster-peers",
+            "http://main-a:8080",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.main_servers(),
+            ["http://main:8080", "http://main-a:8080"]
+        );
+    }
 }
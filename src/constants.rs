@@ -1 +1,6 @@
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const LATEST_TAG: &str = "latest";
+// change log seq of a write, set by main server on write responses
+pub const SEQ_METADATA_KEY: &str = "x-qcdn-seq";
+// url of the elected main server, set when a cluster member turns a request away
+pub const LEADER_METADATA_KEY: &str = "x-qcdn-leader";
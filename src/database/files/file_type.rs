@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::grpc::FileType as GrpcFileType;
 
-#[derive(Debug, sqlx::Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
 #[repr(i32)]
 pub enum FileType {
     Other,
@@ -13,6 +13,19 @@ pub enum FileType {
     Text,
 }
 
+impl FileType {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Other => "application/octet-stream",
+            Self::Stylesheets => "text/css",
+            Self::Javascript => "text/javascript",
+            Self::Image => "application/octet-stream",
+            Self::Font => "application/octet-stream",
+            Self::Text => "text/plain",
+        }
+    }
+}
+
 impl From<GrpcFileType> for FileType {
     fn from(value: GrpcFileType) -> Self {
         match value {
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row, SqliteConnection};
+
+use crate::database::utils;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobRecord {
+    pub sha256: String,
+    pub size: u64,
+    pub refs: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl BlobRecord {
+    pub async fn find(connection: &mut SqliteConnection, sha256: &str) -> Result<Option<Self>> {
+        let item = sqlx::query_as("SELECT * FROM blob WHERE sha256 = ?")
+            .bind(sha256)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    /// Counts one more live version referencing the blob
+    pub async fn acquire(
+        connection: &mut SqliteConnection,
+        sha256: &str,
+        size: u64,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let created_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO blob(sha256, size, refs, created_at)
+            VALUES (?, ?, 1, ?)
+            ON CONFLICT(sha256) DO UPDATE SET refs = refs + 1
+            RETURNING *
+            "#,
+        )
+        .bind(sha256)
+        .bind(size as i64)
+        .bind(created_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    /// Drops one reference, true when it was the last one and the blob can be removed
+    pub async fn release(connection: &mut SqliteConnection, sha256: &str) -> Result<bool> {
+        let refs: Option<(i64,)> =
+            sqlx::query_as("UPDATE blob SET refs = refs - 1 WHERE sha256 = ? RETURNING refs")
+                .bind(sha256)
+                .fetch_optional(&mut *connection)
+                .await?;
+
+        match refs {
+            Some((refs,)) if refs <= 0 => {
+                sqlx::query("DELETE FROM blob WHERE sha256 = ?")
+                    .bind(sha256)
+                    .execute(connection)
+                    .await?;
+                Ok(true)
+            }
+            // an unknown blob is kept, leaking bytes is better than losing them
+            _ => Ok(false),
+        }
+    }
+}
+
+impl FromRow<'_, SqliteRow> for BlobRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let size: i64 = row.try_get("size")?;
+        let refs: i64 = row.try_get("refs")?;
+
+        let created_at = utils::parse_timestamp(row, "created_at")?;
+
+        Ok(Self {
+            sha256: row.try_get("sha256")?,
+            size: size as u64,
+            refs: refs as u64,
+            created_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    #[tokio::test]
+    async fn last_release_drops_the_blob() {
+        let db = Database::temporary("blob-refs").await.unwrap();
+        let mut connection = db.connect().await.unwrap();
+
+        BlobRecord::acquire(&mut connection, "abc", 5, None)
+            .await
+            .unwrap();
+        let blob = BlobRecord::acquire(&mut connection, "abc", 5, None)
+            .await
+            .unwrap();
+        assert_eq!(blob.refs, 2);
+
+        assert!(!BlobRecord::release(&mut connection, "abc").await.unwrap());
+        let blob = BlobRecord::find(&mut connection, "abc").await.unwrap();
+        assert_eq!(blob.map(|blob| blob.refs), Some(1));
+
+        assert!(BlobRecord::release(&mut connection, "abc").await.unwrap());
+        assert!(BlobRecord::find(&mut connection, "abc")
+            .await
+            .unwrap()
+            .is_none());
+
+        // an unknown blob is never reported as removable
+        assert!(!BlobRecord::release(&mut connection, "abc").await.unwrap());
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row, SqliteConnection};
+use uuid::Uuid;
+
+use crate::database::utils;
+
+#[derive(Debug, Clone, Copy, sqlx::Type, Serialize, Deserialize, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ChangeKind {
+    Uploaded,
+    Tagged,
+    Deleted,
+}
+
+/// Where a change goes in the log, `seq` is given by a follower mirroring the leader log,
+/// otherwise the next one is used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogPosition {
+    pub seq: Option<u64>,
+    pub term: u64,
+}
+
+impl LogPosition {
+    pub fn next(term: u64) -> Self {
+        Self { seq: None, term }
+    }
+
+    pub fn at(seq: u64, term: u64) -> Self {
+        Self {
+            seq: Some(seq),
+            term,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeLogRecord {
+    pub seq: u64,
+    pub term: u64,
+    pub kind: ChangeKind,
+    pub file_version_id: Uuid,
+    pub tag: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ChangeLogRecord {
+    pub async fn append(
+        connection: &mut SqliteConnection,
+        position: LogPosition,
+        kind: ChangeKind,
+        file_version_id: &Uuid,
+        tag: Option<&str>,
+        ts: DateTime<Utc>,
+    ) -> Result<Self> {
+        let file_version_id = file_version_id.to_string();
+        let created_at = ts.timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO change_log(seq, term, kind, file_version_id, tag, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            RETURNING *
+            "#,
+        )
+        .bind(position.seq.map(|seq| seq as i64))
+        .bind(position.term as i64)
+        .bind(kind)
+        .bind(file_version_id)
+        .bind(tag)
+        .bind(created_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    pub async fn find_by_seq(connection: &mut SqliteConnection, seq: u64) -> Result<Option<Self>> {
+        let item = sqlx::query_as("SELECT * FROM change_log WHERE seq = ?")
+            .bind(seq as i64)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    /// Versions touched by changes past `seq`
+    pub async fn changed_after(connection: &mut SqliteConnection, seq: u64) -> Result<Vec<Uuid>> {
+        let ids: Vec<String> =
+            sqlx::query_scalar("SELECT DISTINCT file_version_id FROM change_log WHERE seq > ?")
+                .bind(seq as i64)
+                .fetch_all(connection)
+                .await?;
+
+        Ok(ids
+            .iter()
+            .map(|id| Uuid::parse_str(id))
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Latest tag change of the same tag on a version of the same file before this change
+    pub async fn find_previous_tagged(
+        connection: &mut SqliteConnection,
+        change: &ChangeLogRecord,
+    ) -> Result<Option<Self>> {
+        let item = sqlx::query_as(
+            r#"
+            SELECT cl.*
+            FROM
+                change_log cl
+                INNER JOIN file_version fv ON fv.id = cl.file_version_id
+            WHERE
+                cl.seq < ?1
+                AND cl.kind = ?2
+                AND cl.tag = ?3
+                AND fv.file_id = (SELECT file_id FROM file_version WHERE id = ?4)
+            ORDER BY cl.seq DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(change.seq as i64)
+        .bind(ChangeKind::Tagged)
+        .bind(&change.tag)
+        .bind(change.file_version_id.to_string())
+        .fetch_optional(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    pub async fn delete(&self, connection: &mut SqliteConnection) -> Result<()> {
+        sqlx::query("DELETE FROM change_log WHERE seq = ?")
+            .bind(self.seq as i64)
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Term of the change at `seq`, None past the end of the log, the empty log is at term 0
+    pub async fn term_at(connection: &mut SqliteConnection, seq: u64) -> Result<Option<u64>> {
+        if seq == 0 {
+            return Ok(Some(0));
+        }
+        let term: Option<i64> = sqlx::query_scalar("SELECT term FROM change_log WHERE seq = ?")
+            .bind(seq as i64)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(term.map(|term| term as u64))
+    }
+
+    /// Seq and term of the last change
+    pub async fn last(connection: &mut SqliteConnection) -> Result<(u64, u64)> {
+        let last: Option<(i64, i64)> =
+            sqlx::query_as("SELECT seq, term FROM change_log ORDER BY seq DESC LIMIT 1")
+                .fetch_optional(connection)
+                .await?;
+
+        Ok(last
+            .map(|(seq, term)| (seq as u64, term as u64))
+            .unwrap_or_default())
+    }
+
+    /// Seq of the latest change of a version, 0 without any
+    pub async fn latest_seq_of(
+        connection: &mut SqliteConnection,
+        file_version_id: &Uuid,
+    ) -> Result<u64> {
+        let seq: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(seq) FROM change_log WHERE file_version_id = ?")
+                .bind(file_version_id.to_string())
+                .fetch_one(connection)
+                .await?;
+
+        Ok(seq.unwrap_or_default() as u64)
+    }
+
+    pub async fn latest_seq(connection: &mut SqliteConnection) -> Result<u64> {
+        let seq: Option<i64> = sqlx::query_scalar("SELECT MAX(seq) FROM change_log")
+            .fetch_one(connection)
+            .await?;
+
+        Ok(seq.unwrap_or_default() as u64)
+    }
+}
+
+impl FromRow<'_, SqliteRow> for ChangeLogRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let seq: i64 = row.try_get("seq")?;
+        let term: i64 = row.try_get("term")?;
+        let file_version_id = utils::parse_uuid(row, "file_version_id")?;
+        let created_at = utils::parse_timestamp(row, "created_at")?;
+
+        Ok(Self {
+            seq: seq as u64,
+            term: term as u64,
+            kind: row.try_get("kind")?,
+            file_version_id,
+            tag: row.try_get("tag")?,
+            created_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        files::{
+            file_type::FileType,
+            records::{
+                dir_record::DirRecord,
+                file_record::FileRecord,
+                file_version_record::{FileVersionRecord, FileVersionState},
+            },
+        },
+        Database,
+    };
+
+    #[tokio::test]
+    async fn seq_of_deleted_tail_is_used_again() {
+        let db = Database::temporary("change-log-seq").await.unwrap();
+        let mut connection = db.connect().await.unwrap();
+        let dir = DirRecord::create(&mut connection, "dir", None)
+            .await
+            .unwrap();
+        let file = FileRecord::create(&mut connection, &dir.id, "a.txt", FileType::Text, None)
+            .await
+            .unwrap();
+        let file_version = FileVersionRecord::create(
+            &mut connection,
+            &file.id,
+            "1",
+            1,
+            FileVersionState::Ready,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut seqs = vec![];
+        for kind in [ChangeKind::Uploaded, ChangeKind::Tagged] {
+            let change = ChangeLogRecord::append(
+                &mut connection,
+                LogPosition::default(),
+                kind,
+                &file_version.id,
+                None,
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+            seqs.push(change.seq);
+        }
+        assert_eq!(seqs, [1, 2]);
+
+        let last = ChangeLogRecord::find_by_seq(&mut connection, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        last.delete(&mut connection).await.unwrap();
+
+        let change = ChangeLogRecord::append(
+            &mut connection,
+            LogPosition::default(),
+            ChangeKind::Deleted,
+            &file_version.id,
+            None,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(change.seq, 2);
+    }
+}
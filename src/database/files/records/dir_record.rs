@@ -15,6 +15,17 @@ pub struct DirRecord {
 }
 
 impl DirRecord {
+    pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
+        let id = id.to_string();
+
+        let item = sqlx::query_as("SELECT * FROM dir WHERE id = ?")
+            .bind(id)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
     pub async fn find_by_name(
         connection: &mut SqliteConnection,
         name: &str,
@@ -32,7 +43,16 @@ impl DirRecord {
         name: &str,
         ts: Option<DateTime<Utc>>,
     ) -> Result<Self> {
-        let uuid = uuid::Uuid::now_v7().to_string();
+        Self::create_with_id(connection, &uuid::Uuid::now_v7(), name, ts).await
+    }
+
+    pub async fn create_with_id(
+        connection: &mut SqliteConnection,
+        id: &Uuid,
+        name: &str,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let uuid = id.to_string();
         let created_at = ts.unwrap_or_else(Utc::now).timestamp();
 
         let item = sqlx::query_as(
@@ -18,6 +18,17 @@ pub struct FileRecord {
 }
 
 impl FileRecord {
+    pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
+        let id = id.to_string();
+
+        let item = sqlx::query_as("SELECT * FROM file WHERE id = ?")
+            .bind(id)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
     pub async fn find_by_name(
         connection: &mut SqliteConnection,
         dir_id: &Uuid,
@@ -41,7 +52,26 @@ impl FileRecord {
         file_type: FileType,
         ts: Option<DateTime<Utc>>,
     ) -> Result<Self> {
-        let id = uuid::Uuid::now_v7().to_string();
+        Self::create_with_id(
+            connection,
+            &uuid::Uuid::now_v7(),
+            dir_id,
+            name,
+            file_type,
+            ts,
+        )
+        .await
+    }
+
+    pub async fn create_with_id(
+        connection: &mut SqliteConnection,
+        id: &Uuid,
+        dir_id: &Uuid,
+        name: &str,
+        file_type: FileType,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let id = id.to_string();
         let dir_id = dir_id.to_string();
 
         let created_at = ts.unwrap_or_else(Utc::now).timestamp();
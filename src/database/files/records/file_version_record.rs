@@ -5,7 +5,7 @@ use sqlx::sqlite::SqliteRow;
 use sqlx::{Connection, FromRow, Row, SqliteConnection};
 use uuid::Uuid;
 
-use crate::database::utils;
+use crate::{database::utils, storage};
 
 #[derive(Debug, sqlx::Type, Serialize, Deserialize, PartialEq, Eq)]
 #[repr(i32)]
@@ -22,6 +22,7 @@ pub struct FileVersionRecord {
     pub size: u64,
     pub version: String,
     pub state: FileVersionState,
+    pub sha256: Option<String>,
     pub created_at: DateTime<Utc>,
     pub deleted_at: Option<DateTime<Utc>>,
 }
@@ -45,6 +46,20 @@ impl FileVersionRecord {
         Ok(item)
     }
 
+    pub async fn find_by_id_in_any_state(
+        connection: &mut SqliteConnection,
+        id: &Uuid,
+    ) -> Result<Option<Self>> {
+        let id = id.to_string();
+
+        let item = sqlx::query_as("SELECT * FROM file_version WHERE id = ?")
+            .bind(id)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
     pub async fn find_by_version(
         connection: &mut SqliteConnection,
         file_id: &Uuid,
@@ -60,6 +75,7 @@ impl FileVersionRecord {
                 file_id = ?
                 AND version = ?
                 AND state = ?
+            ORDER BY deleted_at IS NOT NULL, created_at DESC
             "#,
         )
         .bind(file_id)
@@ -71,6 +87,63 @@ impl FileVersionRecord {
         Ok(item)
     }
 
+    pub async fn find_by_tag(
+        connection: &mut SqliteConnection,
+        file_id: &Uuid,
+        tag: &str,
+    ) -> Result<Option<Self>> {
+        let file_id = file_id.to_string();
+
+        let item = sqlx::query_as(
+            r#"
+            SELECT fv.*
+            FROM
+                file_version fv
+                INNER JOIN file_version_tag fvt ON fvt.file_version_id = fv.id
+            WHERE
+                fv.file_id = ?
+                AND fvt.name = ?
+                AND fv.state = ?
+                AND fv.deleted_at IS NULL
+            ORDER BY fvt.activated_at DESC, fv.id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(file_id)
+        .bind(tag)
+        .bind(FileVersionState::Ready)
+        .fetch_optional(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    pub async fn find_latest(
+        connection: &mut SqliteConnection,
+        file_id: &Uuid,
+    ) -> Result<Option<Self>> {
+        let file_id = file_id.to_string();
+
+        let item = sqlx::query_as(
+            r#"
+            SELECT *
+            FROM file_version
+            WHERE
+                file_id = ?
+                AND state = ?
+                AND deleted_at IS NULL
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(file_id)
+        .bind(FileVersionState::Ready)
+        .fetch_optional(connection)
+        .await?;
+
+        Ok(item)
+    }
+
     pub async fn create(
         connection: &mut SqliteConnection,
         file_id: &Uuid,
@@ -79,7 +152,28 @@ impl FileVersionRecord {
         state: FileVersionState,
         ts: Option<DateTime<Utc>>,
     ) -> Result<Self> {
-        let id = uuid::Uuid::now_v7().to_string();
+        Self::create_with_id(
+            connection,
+            &uuid::Uuid::now_v7(),
+            file_id,
+            version,
+            size,
+            state,
+            ts,
+        )
+        .await
+    }
+
+    pub async fn create_with_id(
+        connection: &mut SqliteConnection,
+        id: &Uuid,
+        file_id: &Uuid,
+        version: &str,
+        size: u64,
+        state: FileVersionState,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let id = id.to_string();
         let file_id = file_id.to_string();
 
         let created_at = ts.unwrap_or_else(Utc::now).timestamp();
@@ -116,6 +210,7 @@ impl FileVersionRecord {
 }
 
 impl FileVersionRecord {
+    /// Storage dir and file name of version bytes
     pub async fn path(&self, connection: &mut SqliteConnection) -> Result<(String, String)> {
         let file_version_id = self.id.to_string();
 
@@ -131,7 +226,11 @@ impl FileVersionRecord {
         .fetch_one(connection)
         .await?;
 
-        Ok((r.dir_id, r.file_version_id))
+        Ok(storage::version_location(
+            &r.dir_id,
+            &r.file_version_id,
+            self.sha256.as_deref(),
+        ))
     }
 
     pub async fn update_state(
@@ -154,6 +253,26 @@ impl FileVersionRecord {
         Ok(())
     }
 
+    pub async fn update_sha256(
+        &mut self,
+        connection: &mut SqliteConnection,
+        sha256: &str,
+    ) -> Result<()> {
+        let file_version_id = self.id.to_string();
+
+        sqlx::query!(
+            "UPDATE file_version SET sha256 = ?2 WHERE id = ?1",
+            file_version_id,
+            sha256,
+        )
+        .execute(connection)
+        .await?;
+
+        self.sha256 = Some(sha256.to_string());
+
+        Ok(())
+    }
+
     pub async fn delete(
         &mut self,
         connection: &mut SqliteConnection,
@@ -176,11 +295,37 @@ impl FileVersionRecord {
         Ok(())
     }
 
+    /// Brings back a deleted version
+    pub async fn undelete(&mut self, connection: &mut SqliteConnection) -> Result<()> {
+        let file_version_id = self.id.to_string();
+
+        sqlx::query!(
+            "UPDATE file_version SET deleted_at = NULL WHERE id = ?1",
+            file_version_id,
+        )
+        .execute(connection)
+        .await?;
+
+        self.deleted_at = None;
+
+        Ok(())
+    }
+
     pub async fn unsafe_delete(&self, connection: &mut SqliteConnection) -> Result<()> {
         if self.state == FileVersionState::Ready {
             bail!("Versions with ready state cannot be deleted")
         }
 
+        self.purge(connection).await
+    }
+
+    /// Drops a ready version with its tags and replicas, for a follower taking back an upload
+    /// the cluster leader does not have
+    pub async fn unsafe_delete_ready(&self, connection: &mut SqliteConnection) -> Result<()> {
+        self.purge(connection).await
+    }
+
+    async fn purge(&self, connection: &mut SqliteConnection) -> Result<()> {
         let file_version_id = self.id.to_string();
 
         connection
@@ -193,6 +338,13 @@ impl FileVersionRecord {
                     .execute(&mut **tx)
                     .await?;
 
+                    sqlx::query!(
+                        "DELETE FROM node_file_version WHERE file_version_id = ?1",
+                        file_version_id,
+                    )
+                    .execute(&mut **tx)
+                    .await?;
+
                     sqlx::query!("DELETE FROM file_version WHERE id = ?1", file_version_id)
                         .execute(&mut **tx)
                         .await?;
@@ -215,7 +367,7 @@ impl FromRow<'_, SqliteRow> for FileVersionRecord {
         let size = size as u64;
 
         let created_at = utils::parse_timestamp(row, "created_at")?;
-        let deleted_at = utils::parse_timestamp(row, "deleted_at").ok();
+        let deleted_at = utils::parse_optional_timestamp(row, "deleted_at")?;
 
         Ok(Self {
             id,
@@ -223,6 +375,7 @@ impl FromRow<'_, SqliteRow> for FileVersionRecord {
             size,
             version: row.try_get("version")?,
             state: row.try_get("state")?,
+            sha256: row.try_get("sha256")?,
             created_at,
             deleted_at,
         })
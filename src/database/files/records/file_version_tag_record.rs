@@ -49,6 +49,34 @@ impl FileVersionTagRecord {
         Ok(item)
     }
 
+    pub async fn find_by_file(
+        connection: &mut SqliteConnection,
+        file_version_id: &Uuid,
+        name: &str,
+    ) -> Result<Option<Self>> {
+        let file_version_id = file_version_id.to_string();
+
+        let item = sqlx::query_as(
+            r#"
+            SELECT fvt.*
+            FROM
+                file_version_tag fvt
+                INNER JOIN file_version fv ON fv.id = fvt.file_version_id
+            WHERE
+                fv.file_id = (SELECT file_id FROM file_version WHERE id = ?)
+                AND fvt.name = ?
+            ORDER BY fvt.activated_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(file_version_id)
+        .bind(name)
+        .fetch_optional(connection)
+        .await?;
+
+        Ok(item)
+    }
+
     pub async fn create(
         connection: &mut SqliteConnection,
         file_version_id: &Uuid,
@@ -83,7 +111,7 @@ impl FileVersionTagRecord {
         name: &str,
         ts: Option<DateTime<Utc>>,
     ) -> Result<Self> {
-        let item = match Self::find_by_name(connection, file_version_id, name).await? {
+        let item = match Self::find_by_file(connection, file_version_id, name).await? {
             Some(mut tag) => {
                 tag.move_to_version(connection, file_version_id, ts).await?;
                 tag
@@ -96,6 +124,16 @@ impl FileVersionTagRecord {
 }
 
 impl FileVersionTagRecord {
+    pub async fn delete(&self, connection: &mut SqliteConnection) -> Result<()> {
+        let id = self.id.to_string();
+
+        sqlx::query!("DELETE FROM file_version_tag WHERE id = ?1", id)
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn move_to_version(
         &mut self,
         connection: &mut SqliteConnection,
@@ -105,7 +143,8 @@ impl FileVersionTagRecord {
         let id = self.id.to_string();
         let new_file_version_id = file_version_id.to_string();
 
-        let ts = ts.unwrap_or_else(Utc::now).timestamp();
+        let activated_at = ts.unwrap_or_else(Utc::now);
+        let ts = activated_at.timestamp();
 
         sqlx::query!(
             "UPDATE file_version_tag SET file_version_id = ?2, activated_at = ?3 WHERE id = ?1",
@@ -117,6 +156,7 @@ impl FileVersionTagRecord {
         .await?;
 
         self.file_version_id = *file_version_id;
+        self.activated_at = activated_at;
 
         Ok(())
     }
@@ -1,4 +1,9 @@
+pub mod blob_record;
+pub mod change_log_record;
 pub mod dir_record;
 pub mod file_record;
 pub mod file_version_record;
 pub mod file_version_tag_record;
+pub mod sync_cursor_record;
+pub mod upload_part_record;
+pub mod upload_session_record;
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row, SqliteConnection};
+
+use crate::database::utils;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncCursorRecord {
+    pub seq: u64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SyncCursorRecord {
+    pub async fn find(connection: &mut SqliteConnection) -> Result<Option<Self>> {
+        let item = sqlx::query_as("SELECT * FROM sync_cursor WHERE id = 0")
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    pub async fn save(
+        connection: &mut SqliteConnection,
+        seq: u64,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let updated_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO sync_cursor(id, seq, updated_at)
+            VALUES (0, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET seq = excluded.seq, updated_at = excluded.updated_at
+            RETURNING *
+            "#,
+        )
+        .bind(seq as i64)
+        .bind(updated_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+}
+
+impl FromRow<'_, SqliteRow> for SyncCursorRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let seq: i64 = row.try_get("seq")?;
+        let updated_at = utils::parse_timestamp(row, "updated_at")?;
+
+        Ok(Self {
+            seq: seq as u64,
+            updated_at,
+        })
+    }
+}
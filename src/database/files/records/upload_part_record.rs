@@ -0,0 +1,91 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row, SqliteConnection};
+use uuid::Uuid;
+
+use crate::database::utils;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadPartRecord {
+    pub upload_id: Uuid,
+    pub part_number: u32,
+    pub size: u64,
+    pub sha256: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UploadPartRecord {
+    pub async fn get_by_upload(
+        connection: &mut SqliteConnection,
+        upload_id: &Uuid,
+    ) -> Result<Vec<Self>> {
+        let items =
+            sqlx::query_as("SELECT * FROM upload_part WHERE upload_id = ? ORDER BY part_number")
+                .bind(upload_id.to_string())
+                .fetch_all(connection)
+                .await?;
+
+        Ok(items)
+    }
+
+    pub async fn total_size(connection: &mut SqliteConnection, upload_id: &Uuid) -> Result<u64> {
+        let (size,): (i64,) =
+            sqlx::query_as("SELECT COALESCE(SUM(size), 0) FROM upload_part WHERE upload_id = ?")
+                .bind(upload_id.to_string())
+                .fetch_one(connection)
+                .await?;
+
+        Ok(size as u64)
+    }
+
+    /// Uploading the same part number again replaces it
+    pub async fn save(
+        connection: &mut SqliteConnection,
+        upload_id: &Uuid,
+        part_number: u32,
+        size: u64,
+        sha256: &str,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let created_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO upload_part(upload_id, part_number, size, sha256, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(upload_id, part_number) DO UPDATE
+            SET size = excluded.size, sha256 = excluded.sha256, created_at = excluded.created_at
+            RETURNING *
+            "#,
+        )
+        .bind(upload_id.to_string())
+        .bind(part_number)
+        .bind(size as i64)
+        .bind(sha256)
+        .bind(created_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+}
+
+impl FromRow<'_, SqliteRow> for UploadPartRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let upload_id = utils::parse_uuid(row, "upload_id")?;
+
+        let size: i64 = row.try_get("size")?;
+
+        let created_at = utils::parse_timestamp(row, "created_at")?;
+
+        Ok(Self {
+            upload_id,
+            part_number: row.try_get("part_number")?,
+            size: size as u64,
+            sha256: row.try_get("sha256")?,
+            created_at,
+        })
+    }
+}
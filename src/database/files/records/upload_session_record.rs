@@ -0,0 +1,149 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Connection, FromRow, Row, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{database::utils, grpc::UploadMeta};
+
+/// Resumable upload, shares id with the downloading version it writes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadSessionRecord {
+    pub id: Uuid,
+    pub received_bytes: u64,
+    pub consistency: i32,
+    pub min_replicas: u32,
+    pub timeout_ms: Option<u32>,
+    pub sha256: Option<String>,
+    // bytes arrive as numbered parts instead of a resumed stream
+    pub multipart: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl UploadSessionRecord {
+    pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
+        let id = id.to_string();
+
+        let item = sqlx::query_as("SELECT * FROM upload_session WHERE id = ?")
+            .bind(id)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    /// Sessions without progress since `before`
+    pub async fn get_stale(
+        connection: &mut SqliteConnection,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Self>> {
+        let items = sqlx::query_as("SELECT * FROM upload_session WHERE updated_at < ?")
+            .bind(before.timestamp())
+            .fetch_all(connection)
+            .await?;
+
+        Ok(items)
+    }
+
+    pub async fn create(
+        connection: &mut SqliteConnection,
+        file_version_id: &Uuid,
+        meta: &UploadMeta,
+        multipart: bool,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let id = file_version_id.to_string();
+        let created_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO upload_session(id, received_bytes, consistency, min_replicas, timeout_ms, sha256, multipart, created_at, updated_at)
+            VALUES (?, 0, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING *
+            "#,
+        )
+        .bind(id)
+        .bind(meta.consistency)
+        .bind(meta.min_replicas)
+        .bind(meta.timeout_ms)
+        .bind(&meta.sha256)
+        .bind(multipart)
+        .bind(created_at)
+        .bind(created_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    pub async fn save_progress(
+        &mut self,
+        connection: &mut SqliteConnection,
+        received_bytes: u64,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let updated_at = ts.unwrap_or_else(Utc::now);
+
+        sqlx::query("UPDATE upload_session SET received_bytes = ?, updated_at = ? WHERE id = ?")
+            .bind(received_bytes as i64)
+            .bind(updated_at.timestamp())
+            .bind(self.id.to_string())
+            .execute(connection)
+            .await?;
+
+        self.received_bytes = received_bytes;
+        self.updated_at = updated_at;
+        Ok(())
+    }
+
+    /// Removes the session with its parts
+    pub async fn delete(&self, connection: &mut SqliteConnection) -> Result<()> {
+        let id = self.id.to_string();
+
+        connection
+            .transaction(|tx| {
+                Box::pin(async move {
+                    sqlx::query("DELETE FROM upload_part WHERE upload_id = ?")
+                        .bind(&id)
+                        .execute(&mut **tx)
+                        .await?;
+
+                    sqlx::query("DELETE FROM upload_session WHERE id = ?")
+                        .bind(&id)
+                        .execute(&mut **tx)
+                        .await?;
+
+                    anyhow::Ok(())
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl FromRow<'_, SqliteRow> for UploadSessionRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let id = utils::parse_uuid(row, "id")?;
+
+        let received_bytes: i64 = row.try_get("received_bytes")?;
+        let timeout_ms: Option<i64> = row.try_get("timeout_ms")?;
+
+        let created_at = utils::parse_timestamp(row, "created_at")?;
+        let updated_at = utils::parse_timestamp(row, "updated_at")?;
+
+        Ok(Self {
+            id,
+            received_bytes: received_bytes as u64,
+            consistency: row.try_get("consistency")?,
+            min_replicas: row.try_get("min_replicas")?,
+            timeout_ms: timeout_ms.map(|ms| ms as u32),
+            sha256: row.try_get("sha256")?,
+            multipart: row.try_get("multipart")?,
+            created_at,
+            updated_at,
+        })
+    }
+}
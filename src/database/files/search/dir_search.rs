@@ -9,6 +9,7 @@ use crate::grpc::GetDirResponse;
 pub struct DirSearch {
     pub id: String,
     pub name: String,
+    pub created_at: i64,
 }
 
 impl From<DirSearch> for GetDirResponse {
@@ -16,13 +17,17 @@ impl From<DirSearch> for GetDirResponse {
         Self {
             id: value.id,
             name: value.name,
+            created_at: Some(prost_types::Timestamp {
+                seconds: value.created_at,
+                nanos: 0,
+            }),
         }
     }
 }
 
 impl DirSearch {
     pub async fn get_all(connection: &mut SqliteConnection) -> Result<Vec<Self>> {
-        let items = sqlx::query_as("SELECT id, dir FROM dir")
+        let items = sqlx::query_as("SELECT id, name, created_at FROM dir")
             .fetch_all(connection)
             .await?;
 
@@ -32,7 +37,7 @@ impl DirSearch {
     pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
         let id = id.to_string();
 
-        let item = sqlx::query_as("SELECT id, dir FROM dir WHERE id = ?")
+        let item = sqlx::query_as("SELECT id, name, created_at FROM dir WHERE id = ?")
             .bind(id)
             .fetch_optional(connection)
             .await?;
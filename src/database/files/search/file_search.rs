@@ -12,6 +12,7 @@ pub struct FileSearch {
     pub dir_id: String,
     pub name: String,
     pub file_type: FileType,
+    pub created_at: i64,
 }
 
 impl From<FileSearch> for GetFileResponse {
@@ -22,13 +23,17 @@ impl From<FileSearch> for GetFileResponse {
             dir_id: value.dir_id,
             name: value.name,
             file_type: file_type.into(),
+            created_at: Some(prost_types::Timestamp {
+                seconds: value.created_at,
+                nanos: 0,
+            }),
         }
     }
 }
 
 impl FileSearch {
     pub async fn get_all(connection: &mut SqliteConnection) -> Result<Vec<Self>> {
-        let items = sqlx::query_as("SELECT id, dir_id, name, file_type FROM file")
+        let items = sqlx::query_as("SELECT id, dir_id, name, file_type, created_at FROM file")
             .fetch_all(connection)
             .await?;
 
@@ -41,10 +46,12 @@ impl FileSearch {
     ) -> Result<Vec<Self>> {
         let dir_id = dir_id.to_string();
 
-        let items = sqlx::query_as("SELECT id, dir_id, name, file_type FROM file WHERE dir_id = ?")
-            .bind(dir_id)
-            .fetch_all(connection)
-            .await?;
+        let items = sqlx::query_as(
+            "SELECT id, dir_id, name, file_type, created_at FROM file WHERE dir_id = ?",
+        )
+        .bind(dir_id)
+        .fetch_all(connection)
+        .await?;
 
         Ok(items)
     }
@@ -52,10 +59,11 @@ impl FileSearch {
     pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
         let id = id.to_string();
 
-        let item = sqlx::query_as("SELECT id, dir_id, name, file_type FROM file WHERE id = ?")
-            .bind(id)
-            .fetch_optional(connection)
-            .await?;
+        let item =
+            sqlx::query_as("SELECT id, dir_id, name, file_type, created_at FROM file WHERE id = ?")
+                .bind(id)
+                .fetch_optional(connection)
+                .await?;
 
         Ok(item)
     }
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteConnection};
+use uuid::Uuid;
+
+use crate::grpc::FileVersionReplica;
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct FileVersionReplicaSearch {
+    pub file_version_id: String,
+    pub node_id: String,
+    pub url: String,
+    pub grpc_url: Option<String>,
+    pub connected: bool,
+    pub created_at: i64,
+}
+
+impl From<FileVersionReplicaSearch> for FileVersionReplica {
+    fn from(value: FileVersionReplicaSearch) -> Self {
+        Self {
+            node_id: value.node_id,
+            url: value.url,
+            grpc_url: value.grpc_url,
+            connected: value.connected,
+            created_at: Some(prost_types::Timestamp {
+                seconds: value.created_at,
+                nanos: 0,
+            }),
+        }
+    }
+}
+
+impl FileVersionReplicaSearch {
+    pub async fn find_by_file_id(
+        connection: &mut SqliteConnection,
+        file_id: &Uuid,
+    ) -> Result<Vec<Self>> {
+        let file_id = file_id.to_string();
+        let items = sqlx::query_as(
+            r#"
+            SELECT
+                nfv.file_version_id,
+                nfv.node_id,
+                n.url,
+                n.grpc_url,
+                n.connected,
+                nfv.created_at
+            FROM
+                node_file_version nfv
+                INNER JOIN node n ON n.id = nfv.node_id
+                INNER JOIN file_version fv ON fv.id = nfv.file_version_id
+            WHERE fv.file_id = ?
+            ORDER BY nfv.created_at"#,
+        )
+        .bind(file_id)
+        .fetch_all(connection)
+        .await?;
+
+        Ok(items)
+    }
+
+    pub async fn find_by_file_version_id(
+        connection: &mut SqliteConnection,
+        file_version_id: &Uuid,
+    ) -> Result<Vec<Self>> {
+        let file_version_id = file_version_id.to_string();
+        let items = sqlx::query_as(
+            r#"
+            SELECT
+                nfv.file_version_id,
+                nfv.node_id,
+                n.url,
+                n.grpc_url,
+                n.connected,
+                nfv.created_at
+            FROM
+                node_file_version nfv
+                INNER JOIN node n ON n.id = nfv.node_id
+            WHERE nfv.file_version_id = ?
+            ORDER BY nfv.created_at"#,
+        )
+        .bind(file_version_id)
+        .fetch_all(connection)
+        .await?;
+
+        Ok(items)
+    }
+
+    pub async fn count_connected_nodes(connection: &mut SqliteConnection) -> Result<u32> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM node WHERE connected AND NOT edge")
+                .fetch_one(connection)
+                .await?;
+
+        Ok(count as u32)
+    }
+}
@@ -3,7 +3,9 @@ use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, FromRow, Row, SqliteConnection};
 use uuid::Uuid;
 
-use crate::grpc::GetFileVersionResponse;
+use crate::grpc::{FileVersionReplica, GetFileVersionResponse};
+
+use super::file_version_replica_search::FileVersionReplicaSearch;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileVersionSearch {
@@ -11,8 +13,10 @@ pub struct FileVersionSearch {
     pub file_id: String,
     pub version: String,
     pub size: u64,
+    pub sha256: Option<String>,
     pub tags: Vec<String>,
     pub is_deleted: bool,
+    pub created_at: i64,
 }
 
 impl From<FileVersionSearch> for GetFileVersionResponse {
@@ -24,6 +28,35 @@ impl From<FileVersionSearch> for GetFileVersionResponse {
             size: value.size,
             tags: value.tags,
             is_deleted: value.is_deleted,
+            created_at: Some(prost_types::Timestamp {
+                seconds: value.created_at,
+                nanos: 0,
+            }),
+            replicas: vec![],
+            missing_replicas: 0,
+            sha256: value.sha256,
+        }
+    }
+}
+
+impl FileVersionSearch {
+    pub fn with_replicas(
+        self,
+        replicas: Vec<FileVersionReplicaSearch>,
+        connected_nodes: u32,
+    ) -> GetFileVersionResponse {
+        let is_deleted = self.is_deleted;
+        let replicas: Vec<FileVersionReplica> = replicas.into_iter().map(|r| r.into()).collect();
+        let held = replicas.iter().filter(|r| r.connected).count() as u32;
+
+        GetFileVersionResponse {
+            replicas,
+            missing_replicas: if is_deleted {
+                0
+            } else {
+                connected_nodes.saturating_sub(held)
+            },
+            ..self.into()
         }
     }
 }
@@ -41,6 +74,8 @@ impl FileVersionSearch {
                 fv.file_id,
                 fv.version,
                 fv.size,
+                fv.sha256,
+                fv.created_at,
                 GROUP_CONCAT(fvt.name) tags,
                 fv.deleted_at IS NOT NULL is_deleted
             FROM
@@ -66,6 +101,8 @@ impl FileVersionSearch {
                 fv.file_id,
                 fv.version,
                 fv.size,
+                fv.sha256,
+                fv.created_at,
                 GROUP_CONCAT(fvt.name) tags,
                 fv.deleted_at IS NOT NULL is_deleted
             FROM
@@ -92,7 +129,9 @@ impl FromRow<'_, SqliteRow> for FileVersionSearch {
             file_id: row.try_get("file_id")?,
             version: row.try_get("version")?,
             size,
+            sha256: row.try_get("sha256")?,
             is_deleted: row.try_get("is_deleted")?,
+            created_at: row.try_get("created_at")?,
             tags: row
                 .try_get("tags")
                 .map(|s: &str| s.split(',').map(String::from).collect())?,
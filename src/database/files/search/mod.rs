@@ -1,3 +1,4 @@
 pub mod dir_search;
 pub mod file_search;
+pub mod file_version_replica_search;
 pub mod file_version_search;
@@ -1,164 +1,502 @@
-use std::time::SystemTime;
-
-use anyhow::Result;
-use chrono::{DateTime, Utc};
-use sqlx::{Row, SqliteConnection};
-
-use crate::{
-    database::utils,
-    grpc::{self, sync_message, DeletedVersion, UploadedVersion, VersionTagged},
-};
-
-use super::records::file_version_record::FileVersionState;
-
-#[derive(Debug)]
-pub enum FileSyncAction {
-    UploadedVersion { dir_id: String, file_id: String },
-    VersionTagged { tag: String },
-    DeletedVersion,
-}
-
-#[derive(Debug)]
-pub struct FileSync {
-    pub action: FileSyncAction,
-    pub file_version_id: String,
-    pub timestamp: DateTime<Utc>,
-}
-
-impl From<FileSync> for grpc::SyncMessage {
-    fn from(value: FileSync) -> Self {
-        let file_version_id = value.file_version_id;
-        let message_type = match value.action {
-            FileSyncAction::UploadedVersion { dir_id, file_id } => {
-                sync_message::MessageType::Uploaded(UploadedVersion {
-                    dir_id,
-                    file_id,
-                    file_version_id,
-                })
-            }
-            FileSyncAction::VersionTagged { tag } => {
-                sync_message::MessageType::Tagged(VersionTagged {
-                    tag,
-                    file_version_id,
-                })
-            }
-            FileSyncAction::DeletedVersion => {
-                sync_message::MessageType::Deleted(DeletedVersion { file_version_id })
-            }
-        };
-        let ts: SystemTime = value.timestamp.into();
-        Self {
-            message_type: Some(message_type),
-            timestamp: Some(ts.into()),
-        }
-    }
-}
-
-impl FileSync {
-    pub async fn uploaded_from_ts(
-        connection: &mut SqliteConnection,
-        ts: &DateTime<Utc>,
-    ) -> Result<Vec<Self>> {
-        let ts = ts.timestamp();
-
-        sqlx::query(
-            r#"
-                SELECT
-                    fv.id file_version_id,
-                    f.id file_id,
-                    f.dir_id,
-                    fv.created_at
-                FROM
-                    file_version fv
-                    INNER JOIN file f ON f.id = fv.file_id
-                WHERE
-                    fv.state = ?
-                    AND fv.created_at > ?
-            "#,
-        )
-        .bind(FileVersionState::Ready)
-        .bind(ts)
-        .fetch_all(connection)
-        .await?
-        .into_iter()
-        .map(|row| {
-            let timestamp = utils::parse_timestamp(&row, "created_at")?;
-            let dir_id = row.try_get("dir_id")?;
-            let file_id = row.try_get("file_id")?;
-            let file_version_id = row.try_get("file_version_id")?;
-
-            Ok(FileSync {
-                action: FileSyncAction::UploadedVersion { dir_id, file_id },
-                file_version_id,
-                timestamp,
-            })
-        })
-        .collect::<Result<Vec<Self>>>()
-    }
-
-    pub async fn deleted_from_ts(
-        connection: &mut SqliteConnection,
-        ts: &DateTime<Utc>,
-    ) -> Result<Vec<Self>> {
-        let ts = ts.timestamp();
-
-        sqlx::query(
-            r#"
-                SELECT
-                    fv.id file_version_id,
-                    fv.deleted_at
-                FROM file_version fv
-                WHERE fv.deleted_at > ?
-            "#,
-        )
-        .bind(ts)
-        .fetch_all(connection)
-        .await?
-        .into_iter()
-        .map(|row| {
-            let timestamp = utils::parse_timestamp(&row, "deleted_at")?;
-            let file_version_id = row.try_get("file_version_id")?;
-
-            Ok(FileSync {
-                action: FileSyncAction::DeletedVersion,
-                file_version_id,
-                timestamp,
-            })
-        })
-        .collect::<Result<Vec<Self>>>()
-    }
-
-    pub async fn tagged_from_ts(
-        connection: &mut SqliteConnection,
-        ts: &DateTime<Utc>,
-    ) -> Result<Vec<Self>> {
-        let ts = ts.timestamp();
-
-        sqlx::query(
-            r#"
-                SELECT
-                    fvt.file_version_id,
-                    fvt.name,
-                    fvt.activated_at
-                FROM file_version_tag fvt
-                WHERE fvt.activated_at > ?
-            "#,
-        )
-        .bind(ts)
-        .fetch_all(connection)
-        .await?
-        .into_iter()
-        .map(|row| {
-            let timestamp = utils::parse_timestamp(&row, "activated_at")?;
-            let file_version_id = row.try_get("file_version_id")?;
-            let tag = row.try_get("name")?;
-
-            Ok(FileSync {
-                action: FileSyncAction::VersionTagged { tag },
-                file_version_id,
-                timestamp,
-            })
-        })
-        .collect::<Result<Vec<Self>>>()
-    }
-}
+use std::time::SystemTime;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{sqlite::SqliteRow, Connection, Row, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{
+    database::utils,
+    grpc::{
+        self, datetime_to_timestamp, snapshot_message, sync_message, DeletedVersion,
+        UploadedVersion, VersionTagged,
+    },
+};
+
+use super::{
+    file_type::FileType,
+    records::{
+        blob_record::BlobRecord,
+        change_log_record::{ChangeKind, ChangeLogRecord, LogPosition},
+        dir_record::DirRecord,
+        file_record::FileRecord,
+        file_version_record::{FileVersionRecord, FileVersionState},
+        file_version_tag_record::FileVersionTagRecord,
+    },
+};
+
+#[derive(Debug)]
+pub struct UploadedVersionMeta {
+    pub dir_id: String,
+    pub file_id: String,
+    pub dir: String,
+    pub name: String,
+    pub file_type: FileType,
+    pub version: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    pub deleted: bool,
+    pub dir_created_at: DateTime<Utc>,
+    pub file_created_at: DateTime<Utc>,
+}
+
+impl UploadedVersionMeta {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let size: i64 = row.try_get("size")?;
+        Ok(Self {
+            dir_id: row.try_get("dir_id")?,
+            file_id: row.try_get("file_id")?,
+            dir: row.try_get("dir")?,
+            name: row.try_get("name")?,
+            file_type: row.try_get("file_type")?,
+            version: row.try_get("version")?,
+            size: size as u64,
+            sha256: row.try_get("sha256")?,
+            deleted: row.try_get("deleted")?,
+            dir_created_at: utils::parse_timestamp(row, "dir_created_at")?,
+            file_created_at: utils::parse_timestamp(row, "file_created_at")?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FileSyncAction {
+    UploadedVersion(Box<UploadedVersionMeta>),
+    VersionTagged { tag: String },
+    DeletedVersion,
+}
+
+#[derive(Debug)]
+pub struct FileSync {
+    pub seq: u64,
+    pub term: u64,
+    pub action: FileSyncAction,
+    pub file_version_id: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl From<FileSync> for grpc::SnapshotMessage {
+    fn from(value: FileSync) -> Self {
+        let message: grpc::SyncMessage = value.into();
+        let item = message.message_type.and_then(|m| match m {
+            sync_message::MessageType::Uploaded(uploaded) => {
+                Some(snapshot_message::Item::Uploaded(uploaded))
+            }
+            sync_message::MessageType::Tagged(tagged) => {
+                Some(snapshot_message::Item::Tagged(tagged))
+            }
+            sync_message::MessageType::Deleted(_) => None,
+        });
+        Self {
+            item,
+            seq: message.seq,
+            timestamp: message.timestamp,
+        }
+    }
+}
+
+impl From<FileSync> for grpc::SyncMessage {
+    fn from(value: FileSync) -> Self {
+        let file_version_id = value.file_version_id;
+        let message_type = match value.action {
+            FileSyncAction::UploadedVersion(meta) => {
+                let file_type: grpc::FileType = meta.file_type.into();
+                sync_message::MessageType::Uploaded(UploadedVersion {
+                    dir_id: meta.dir_id,
+                    file_id: meta.file_id,
+                    file_version_id,
+                    dir: meta.dir,
+                    name: meta.name,
+                    file_type: file_type.into(),
+                    version: meta.version,
+                    size: meta.size,
+                    sha256: meta.sha256,
+                    deleted: meta.deleted,
+                    dir_created_at: datetime_to_timestamp(meta.dir_created_at),
+                    file_created_at: datetime_to_timestamp(meta.file_created_at),
+                    created_at: datetime_to_timestamp(value.timestamp),
+                })
+            }
+            FileSyncAction::VersionTagged { tag } => {
+                sync_message::MessageType::Tagged(VersionTagged {
+                    tag,
+                    file_version_id,
+                })
+            }
+            FileSyncAction::DeletedVersion => {
+                sync_message::MessageType::Deleted(DeletedVersion { file_version_id })
+            }
+        };
+        let ts: SystemTime = value.timestamp.into();
+        Self {
+            message_type: Some(message_type),
+            timestamp: Some(ts.into()),
+            seq: value.seq,
+            head_seq: value.seq,
+            term: value.term,
+        }
+    }
+}
+
+impl FileSync {
+    pub fn uploaded(
+        change: &ChangeLogRecord,
+        dir_record: &DirRecord,
+        file_record: &FileRecord,
+        file_version_record: &FileVersionRecord,
+    ) -> Self {
+        FileSync {
+            seq: change.seq,
+            term: change.term,
+            action: FileSyncAction::UploadedVersion(Box::new(UploadedVersionMeta {
+                dir_id: dir_record.id.to_string(),
+                file_id: file_record.id.to_string(),
+                dir: dir_record.name.clone(),
+                name: file_record.name.clone(),
+                file_type: file_record.file_type,
+                version: file_version_record.version.clone(),
+                size: file_version_record.size,
+                sha256: file_version_record.sha256.clone(),
+                deleted: file_version_record.deleted_at.is_some(),
+                dir_created_at: dir_record.created_at,
+                file_created_at: file_record.created_at,
+            })),
+            file_version_id: file_version_record.id.to_string(),
+            timestamp: file_version_record.created_at,
+        }
+    }
+
+    pub fn tagged(change: &ChangeLogRecord) -> Self {
+        FileSync {
+            seq: change.seq,
+            term: change.term,
+            action: FileSyncAction::VersionTagged {
+                tag: change.tag.clone().unwrap_or_default(),
+            },
+            file_version_id: change.file_version_id.to_string(),
+            timestamp: change.created_at,
+        }
+    }
+
+    pub fn deleted(change: &ChangeLogRecord) -> Self {
+        FileSync {
+            seq: change.seq,
+            term: change.term,
+            action: FileSyncAction::DeletedVersion,
+            file_version_id: change.file_version_id.to_string(),
+            timestamp: change.created_at,
+        }
+    }
+
+    pub async fn from_seq(
+        connection: &mut SqliteConnection,
+        seq: u64,
+        limit: u32,
+    ) -> Result<Vec<Self>> {
+        sqlx::query(
+            r#"
+                SELECT
+                    cl.seq,
+                    cl.term,
+                    cl.kind,
+                    cl.file_version_id,
+                    cl.tag,
+                    cl.created_at,
+                    fv.version,
+                    fv.size,
+                    fv.sha256,
+                    fv.deleted_at IS NOT NULL deleted,
+                    f.id file_id,
+                    f.name,
+                    f.file_type,
+                    f.created_at file_created_at,
+                    d.id dir_id,
+                    d.name dir,
+                    d.created_at dir_created_at
+                FROM
+                    change_log cl
+                    INNER JOIN file_version fv ON fv.id = cl.file_version_id
+                    INNER JOIN file f ON f.id = fv.file_id
+                    INNER JOIN dir d ON d.id = f.dir_id
+                WHERE cl.seq > ?
+                ORDER BY cl.seq
+                LIMIT ?
+            "#,
+        )
+        .bind(seq as i64)
+        .bind(limit)
+        .fetch_all(connection)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let seq: i64 = row.try_get("seq")?;
+            let term: i64 = row.try_get("term")?;
+            let kind: ChangeKind = row.try_get("kind")?;
+            let timestamp = utils::parse_timestamp(&row, "created_at")?;
+            let file_version_id = row.try_get("file_version_id")?;
+
+            let action = match kind {
+                ChangeKind::Uploaded => {
+                    FileSyncAction::UploadedVersion(Box::new(UploadedVersionMeta::from_row(&row)?))
+                }
+                ChangeKind::Tagged => FileSyncAction::VersionTagged {
+                    tag: row.try_get("tag")?,
+                },
+                ChangeKind::Deleted => FileSyncAction::DeletedVersion,
+            };
+
+            Ok(FileSync {
+                seq: seq as u64,
+                term: term as u64,
+                action,
+                file_version_id,
+                timestamp,
+            })
+        })
+        .collect::<Result<Vec<Self>>>()
+    }
+}
+
+impl FileSync {
+    /// Live catalogue as of the returned change log seq: ready versions that are not deleted,
+    /// followed by their tags
+    pub async fn snapshot(connection: &mut SqliteConnection) -> Result<(u64, Vec<Self>)> {
+        let mut tx = connection.begin().await?;
+        let seq = ChangeLogRecord::latest_seq(&mut tx).await?;
+
+        let uploaded = sqlx::query(
+            r#"
+                SELECT
+                    fv.id file_version_id,
+                    fv.version,
+                    fv.size,
+                    fv.sha256,
+                    fv.deleted_at IS NOT NULL deleted,
+                    fv.created_at,
+                    f.id file_id,
+                    f.name,
+                    f.file_type,
+                    f.created_at file_created_at,
+                    d.id dir_id,
+                    d.name dir,
+                    d.created_at dir_created_at
+                FROM
+                    file_version fv
+                    INNER JOIN file f ON f.id = fv.file_id
+                    INNER JOIN dir d ON d.id = f.dir_id
+                WHERE fv.state = ? AND fv.deleted_at IS NULL
+                ORDER BY fv.created_at
+            "#,
+        )
+        .bind(FileVersionState::Ready)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(FileSync {
+                seq,
+                term: 0,
+                action: FileSyncAction::UploadedVersion(Box::new(UploadedVersionMeta::from_row(
+                    &row,
+                )?)),
+                file_version_id: row.try_get("file_version_id")?,
+                timestamp: utils::parse_timestamp(&row, "created_at")?,
+            })
+        })
+        .collect::<Result<Vec<Self>>>()?;
+
+        let tagged = sqlx::query(
+            r#"
+                SELECT
+                    fvt.file_version_id,
+                    fvt.name,
+                    fvt.activated_at
+                FROM
+                    file_version_tag fvt
+                    INNER JOIN file_version fv ON fv.id = fvt.file_version_id
+                WHERE fv.state = ? AND fv.deleted_at IS NULL
+                ORDER BY fvt.activated_at
+            "#,
+        )
+        .bind(FileVersionState::Ready)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(FileSync {
+                seq,
+                term: 0,
+                action: FileSyncAction::VersionTagged {
+                    tag: row.try_get("name")?,
+                },
+                file_version_id: row.try_get("file_version_id")?,
+                timestamp: utils::parse_timestamp(&row, "activated_at")?,
+            })
+        })
+        .collect::<Result<Vec<Self>>>()?;
+
+        tx.commit().await?;
+
+        Ok((seq, uploaded.into_iter().chain(tagged).collect()))
+    }
+
+    pub async fn commit_uploaded(
+        connection: &mut SqliteConnection,
+        position: LogPosition,
+        dir_record: &DirRecord,
+        file_record: &FileRecord,
+        file_version_record: &mut FileVersionRecord,
+    ) -> Result<Self> {
+        let mut tx = connection.begin().await?;
+        file_version_record
+            .update_state(&mut tx, FileVersionState::Ready)
+            .await?;
+        // deleted on a replica only, the main server still has it
+        if file_version_record.deleted_at.is_some() {
+            file_version_record.undelete(&mut tx).await?;
+        }
+        // counted with the state change, a missed reference would let the blob go too early
+        if let Some(sha256) = &file_version_record.sha256 {
+            BlobRecord::acquire(&mut tx, sha256, file_version_record.size, None).await?;
+        }
+        let change = ChangeLogRecord::append(
+            &mut tx,
+            position,
+            ChangeKind::Uploaded,
+            &file_version_record.id,
+            None,
+            file_version_record.created_at,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(Self::uploaded(
+            &change,
+            dir_record,
+            file_record,
+            file_version_record,
+        ))
+    }
+
+    pub async fn commit_tagged(
+        connection: &mut SqliteConnection,
+        position: LogPosition,
+        file_version_id: &Uuid,
+        tag: &str,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let mut tx = connection.begin().await?;
+        let tag = FileVersionTagRecord::create_or_move(&mut tx, file_version_id, tag, ts).await?;
+        let change = ChangeLogRecord::append(
+            &mut tx,
+            position,
+            ChangeKind::Tagged,
+            &tag.file_version_id,
+            Some(&tag.name),
+            tag.activated_at,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(Self::tagged(&change))
+    }
+
+    /// True along with the change when the version held the last reference to its blob
+    pub async fn commit_deleted(
+        connection: &mut SqliteConnection,
+        position: LogPosition,
+        file_version_record: &mut FileVersionRecord,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<(Self, bool)> {
+        let mut tx = connection.begin().await?;
+        file_version_record.delete(&mut tx, ts).await?;
+        // released with the delete, a crash after it leaks the bytes only
+        let released = match &file_version_record.sha256 {
+            Some(sha256) => BlobRecord::release(&mut tx, sha256).await?,
+            None => false,
+        };
+        let change = ChangeLogRecord::append(
+            &mut tx,
+            position,
+            ChangeKind::Deleted,
+            &file_version_record.id,
+            None,
+            file_version_record.deleted_at.unwrap_or_else(Utc::now),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok((Self::deleted(&change), released))
+    }
+
+    /// Takes back the last change of the log, returns the version it was about.
+    /// References of its blob are counted again, bytes are left to the caller
+    pub async fn revert(
+        connection: &mut SqliteConnection,
+        change: &ChangeLogRecord,
+    ) -> Result<Option<FileVersionRecord>> {
+        let mut tx = connection.begin().await?;
+        change.delete(&mut tx).await?;
+        let Some(mut file_version_record) =
+            FileVersionRecord::find_by_id_in_any_state(&mut tx, &change.file_version_id).await?
+        else {
+            tx.commit().await?;
+            return Ok(None);
+        };
+
+        match change.kind {
+            ChangeKind::Uploaded => {
+                if let (None, Some(sha256)) =
+                    (file_version_record.deleted_at, &file_version_record.sha256)
+                {
+                    BlobRecord::release(&mut tx, sha256).await?;
+                }
+                file_version_record.unsafe_delete_ready(&mut tx).await?;
+                if let Some(file_record) =
+                    FileRecord::find_by_id(&mut tx, &file_version_record.file_id).await?
+                {
+                    file_record.delete_if_no_versions_exists(&mut tx).await?;
+                    if let Some(dir_record) =
+                        DirRecord::find_by_id(&mut tx, &file_record.dir_id).await?
+                    {
+                        dir_record.delete_if_no_files_exists(&mut tx).await?;
+                    }
+                }
+            }
+            ChangeKind::Tagged => {
+                let tag = change.tag.as_deref().unwrap_or_default();
+                match ChangeLogRecord::find_previous_tagged(&mut tx, change).await? {
+                    Some(previous) => {
+                        FileVersionTagRecord::create_or_move(
+                            &mut tx,
+                            &previous.file_version_id,
+                            tag,
+                            Some(previous.created_at),
+                        )
+                        .await?;
+                    }
+                    None => {
+                        if let Some(tag) = FileVersionTagRecord::find_by_name(
+                            &mut tx,
+                            &change.file_version_id,
+                            tag,
+                        )
+                        .await?
+                        {
+                            tag.delete(&mut tx).await?;
+                        }
+                    }
+                }
+            }
+            ChangeKind::Deleted => {
+                file_version_record.undelete(&mut tx).await?;
+                if let Some(sha256) = &file_version_record.sha256 {
+                    BlobRecord::acquire(&mut tx, sha256, file_version_record.size, None).await?;
+                }
+            }
+        }
+        tx.commit().await?;
+
+        Ok(Some(file_version_record))
+    }
+}
@@ -9,6 +9,7 @@ static MIGRATOR: Migrator = sqlx::migrate!();
 
 mod connection;
 pub mod files;
+pub mod nodes;
 mod utils;
 
 #[derive(Debug, Clone)]
@@ -53,3 +54,16 @@ impl Database {
         Ok(connection)
     }
 }
+
+#[cfg(test)]
+impl Database {
+    /// Fresh migrated db in the temp dir, `name` keeps tests running at once apart,
+    /// the next run of the test starts it over
+    pub async fn temporary(name: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("qcdn-test-{name}.db"));
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        Self::create_and_migrate(&path).await
+    }
+}
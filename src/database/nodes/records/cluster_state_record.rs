@@ -0,0 +1,93 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row, SqliteConnection};
+
+use crate::database::utils;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterStateRecord {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    pub commit_seq: u64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ClusterStateRecord {
+    pub async fn find(connection: &mut SqliteConnection) -> Result<Option<Self>> {
+        let item = sqlx::query_as("SELECT * FROM cluster_state WHERE id = 0")
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    pub async fn save(
+        connection: &mut SqliteConnection,
+        term: u64,
+        voted_for: Option<&str>,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let updated_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO cluster_state(id, term, voted_for, updated_at)
+            VALUES (0, ?, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+              term = excluded.term,
+              voted_for = excluded.voted_for,
+              updated_at = excluded.updated_at
+            RETURNING *
+            "#,
+        )
+        .bind(term as i64)
+        .bind(voted_for)
+        .bind(updated_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+
+    pub async fn save_commit_seq(
+        connection: &mut SqliteConnection,
+        commit_seq: u64,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let updated_at = ts.unwrap_or_else(Utc::now).timestamp();
+
+        let item = sqlx::query_as(
+            r#"
+            INSERT INTO cluster_state(id, term, commit_seq, updated_at)
+            VALUES (0, 0, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+              commit_seq = MAX(commit_seq, excluded.commit_seq),
+              updated_at = excluded.updated_at
+            RETURNING *
+            "#,
+        )
+        .bind(commit_seq as i64)
+        .bind(updated_at)
+        .fetch_one(connection)
+        .await?;
+
+        Ok(item)
+    }
+}
+
+impl FromRow<'_, SqliteRow> for ClusterStateRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let term: i64 = row.try_get("term")?;
+        let commit_seq: i64 = row.try_get("commit_seq")?;
+        let updated_at = utils::parse_timestamp(row, "updated_at")?;
+
+        Ok(Self {
+            term: term as u64,
+            voted_for: row.try_get("voted_for")?,
+            commit_seq: commit_seq as u64,
+            updated_at,
+        })
+    }
+}
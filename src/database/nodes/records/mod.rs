@@ -0,0 +1,2 @@
+pub mod cluster_state_record;
+pub mod node_record;
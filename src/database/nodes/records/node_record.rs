@@ -0,0 +1,263 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Connection, FromRow, Row, SqliteConnection};
+use uuid::Uuid;
+
+use crate::{
+    database::{files::records::change_log_record::ChangeKind, utils},
+    grpc::ConnectionRequest,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeRecord {
+    pub id: Uuid,
+    pub url: String,
+    pub grpc_url: Option<String>,
+    pub ip_addr: String,
+    pub seq: u64,
+    pub connected: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub edge: bool,
+    pub first_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+impl NodeRecord {
+    pub async fn find_by_id(connection: &mut SqliteConnection, id: &Uuid) -> Result<Option<Self>> {
+        let id = id.to_string();
+
+        let item = sqlx::query_as("SELECT * FROM node WHERE id = ?")
+            .bind(id)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    pub async fn get_connected(connection: &mut SqliteConnection) -> Result<Vec<Self>> {
+        let items = sqlx::query_as("SELECT * FROM node WHERE connected ORDER BY first_seen_at")
+            .fetch_all(connection)
+            .await?;
+
+        Ok(items)
+    }
+
+    pub async fn find_by_url(connection: &mut SqliteConnection, url: &str) -> Result<Option<Self>> {
+        let item = sqlx::query_as("SELECT * FROM node WHERE url = ?")
+            .bind(url)
+            .fetch_optional(connection)
+            .await?;
+
+        Ok(item)
+    }
+
+    /// Connected nodes that acknowledged holding the version
+    pub async fn get_holding(
+        connection: &mut SqliteConnection,
+        file_version_id: &Uuid,
+    ) -> Result<Vec<Self>> {
+        let file_version_id = file_version_id.to_string();
+
+        let items = sqlx::query_as(
+            r#"
+            SELECT n.*
+            FROM
+                node n
+                INNER JOIN node_file_version nfv ON nfv.node_id = n.id
+            WHERE n.connected AND nfv.file_version_id = ?
+            "#,
+        )
+        .bind(file_version_id)
+        .fetch_all(connection)
+        .await?;
+
+        Ok(items)
+    }
+
+    pub async fn get_all(connection: &mut SqliteConnection) -> Result<Vec<Self>> {
+        let items = sqlx::query_as("SELECT * FROM node ORDER BY first_seen_at")
+            .fetch_all(connection)
+            .await?;
+
+        Ok(items)
+    }
+
+    /// Registers node by its url or refreshes an already known one
+    pub async fn connect(
+        connection: &mut SqliteConnection,
+        request: &ConnectionRequest,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<Self> {
+        let grpc_url = Some(request.grpc_url.as_str()).filter(|url| !url.is_empty());
+        let uuid = Uuid::now_v7().to_string();
+        let ts = ts.unwrap_or_else(Utc::now);
+        let last_seen_at = ts.timestamp();
+
+        let mut tx = connection.begin().await?;
+        let mut item: Self = sqlx::query_as(
+            r#"
+            INSERT INTO node(
+                id, url, grpc_url, ip_addr, seq, connected, latitude, longitude, edge,
+                first_seen_at, last_seen_at
+            )
+            VALUES (?, ?, ?, ?, 0, TRUE, ?, ?, ?, ?, ?)
+            ON CONFLICT(url) DO UPDATE SET
+                grpc_url = excluded.grpc_url,
+                ip_addr = excluded.ip_addr,
+                connected = TRUE,
+                latitude = excluded.latitude,
+                longitude = excluded.longitude,
+                edge = excluded.edge,
+                last_seen_at = excluded.last_seen_at
+            RETURNING *
+            "#,
+        )
+        .bind(uuid)
+        .bind(&request.url)
+        .bind(grpc_url)
+        .bind(&request.ip_addr_v4)
+        .bind(request.latitude)
+        .bind(request.longitude)
+        .bind(request.edge)
+        .bind(last_seen_at)
+        .bind(last_seen_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        // the cursor node connects with is what it already holds
+        item.ack(&mut tx, request.seq, Some(ts)).await?;
+        tx.commit().await?;
+
+        Ok(item)
+    }
+
+    /// Moves node cursor, recording versions uploaded or deleted up to `seq` as held or gone
+    pub async fn ack(
+        &mut self,
+        connection: &mut SqliteConnection,
+        seq: u64,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let id = self.id.to_string();
+        let ts = ts.unwrap_or_else(Utc::now);
+        let last_seen_at = ts.timestamp();
+
+        let mut tx = connection.begin().await?;
+
+        // a node that went back, e.g. lost its db, holds only what it acks again
+        let from = if seq < self.seq {
+            sqlx::query("DELETE FROM node_file_version WHERE node_id = ?")
+                .bind(&id)
+                .execute(&mut *tx)
+                .await?;
+            0
+        } else {
+            self.seq
+        };
+
+        // edge nodes keep only requested versions, they are never counted as holding one
+        if !self.edge {
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO node_file_version(node_id, file_version_id, created_at)
+                SELECT ?, file_version_id, ?
+                FROM change_log
+                WHERE kind = ? AND seq > ? AND seq <= ?
+                "#,
+            )
+            .bind(&id)
+            .bind(last_seen_at)
+            .bind(ChangeKind::Uploaded)
+            .bind(from as i64)
+            .bind(seq as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM node_file_version
+            WHERE node_id = ? AND file_version_id IN (
+                SELECT file_version_id
+                FROM change_log
+                WHERE kind = ? AND seq > ? AND seq <= ?
+            )
+            "#,
+        )
+        .bind(&id)
+        .bind(ChangeKind::Deleted)
+        .bind(from as i64)
+        .bind(seq as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE node SET seq = ?, last_seen_at = ? WHERE id = ?")
+            .bind(seq as i64)
+            .bind(last_seen_at)
+            .bind(&id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.seq = seq;
+        self.last_seen_at = ts;
+
+        Ok(())
+    }
+
+    pub async fn disconnect(
+        &mut self,
+        connection: &mut SqliteConnection,
+        ts: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let id = self.id.to_string();
+        let ts = ts.unwrap_or_else(Utc::now);
+        let last_seen_at = ts.timestamp();
+
+        sqlx::query("UPDATE node SET connected = FALSE, last_seen_at = ? WHERE id = ?")
+            .bind(last_seen_at)
+            .bind(id)
+            .execute(connection)
+            .await?;
+
+        self.connected = false;
+        self.last_seen_at = ts;
+
+        Ok(())
+    }
+
+    /// Nothing is connected to a main server that just started
+    pub async fn disconnect_all(connection: &mut SqliteConnection) -> Result<()> {
+        sqlx::query("UPDATE node SET connected = FALSE WHERE connected")
+            .execute(connection)
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl FromRow<'_, SqliteRow> for NodeRecord {
+    fn from_row(row: &SqliteRow) -> sqlx::Result<Self> {
+        let id = utils::parse_uuid(row, "id")?;
+        let seq: i64 = row.try_get("seq")?;
+        let first_seen_at = utils::parse_timestamp(row, "first_seen_at")?;
+        let last_seen_at = utils::parse_timestamp(row, "last_seen_at")?;
+
+        Ok(Self {
+            id,
+            url: row.try_get("url")?,
+            grpc_url: row.try_get("grpc_url")?,
+            ip_addr: row.try_get("ip_addr")?,
+            seq: seq as u64,
+            connected: row.try_get("connected")?,
+            latitude: row.try_get("latitude")?,
+            longitude: row.try_get("longitude")?,
+            edge: row.try_get("edge")?,
+            first_seen_at,
+            last_seen_at,
+        })
+    }
+}
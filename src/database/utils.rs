@@ -23,3 +23,14 @@ pub fn parse_timestamp(row: &SqliteRow, field_name: &str) -> Result<DateTime<Utc
         }
     }
 }
+
+pub fn parse_optional_timestamp(
+    row: &SqliteRow,
+    field_name: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let value: Option<i64> = row.try_get(field_name)?;
+    match value {
+        Some(_) => parse_timestamp(row, field_name).map(Some),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,272 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use sqlx::SqliteConnection;
+
+use crate::{
+    database::{
+        files::{
+            records::{
+                change_log_record::LogPosition, dir_record::DirRecord, file_record::FileRecord,
+                file_version_record::FileVersionRecord,
+            },
+            sync::{FileSync, FileSyncAction},
+        },
+        Database,
+    },
+    AppState, Storage,
+};
+
+/// Moves bytes written to `<dir_id>/<file_version_id>` into the blob store and marks the version
+/// as ready, identical bytes stored already are dropped, versions without sha256 keep the path
+pub async fn commit_uploaded(
+    storage: &Storage,
+    connection: &mut SqliteConnection,
+    position: LogPosition,
+    dir_record: &DirRecord,
+    file_record: &FileRecord,
+    file_version_record: &mut FileVersionRecord,
+) -> Result<FileSync> {
+    let _blobs = storage.lock_blobs().await;
+
+    let dir_id = dir_record.id.to_string();
+    let file_version_id = file_version_record.id.to_string();
+    let stored = match &file_version_record.sha256 {
+        Some(sha256) => {
+            storage
+                .store_blob(&dir_id, &file_version_id, sha256)
+                .await?
+        }
+        None => {
+            storage
+                .store(&dir_id, &file_version_id, &dir_id, &file_version_id)
+                .await?;
+            false
+        }
+    };
+
+    match FileSync::commit_uploaded(
+        connection,
+        position,
+        dir_record,
+        file_record,
+        file_version_record,
+    )
+    .await
+    {
+        Ok(update) => Ok(update),
+        Err(e) => {
+            if let (true, Some(sha256)) = (stored, &file_version_record.sha256) {
+                storage.remove_blob(sha256).await.ok();
+            }
+            bail!(e)
+        }
+    }
+}
+
+/// Marks the version as deleted, its blob is removed with the last live version referencing it
+pub async fn commit_deleted(
+    app_state: &AppState,
+    connection: &mut SqliteConnection,
+    position: LogPosition,
+    file_version_record: &mut FileVersionRecord,
+    ts: Option<DateTime<Utc>>,
+) -> Result<FileSync> {
+    let _blobs = app_state.storage.lock_blobs().await;
+
+    let (update, released) =
+        FileSync::commit_deleted(connection, position, file_version_record, ts).await?;
+
+    // bytes go after the delete is committed, a crash in between leaks them instead of losing them
+    match &file_version_record.sha256 {
+        Some(sha256) => {
+            if released {
+                match &app_state.cache {
+                    Some(cache) => cache.remove(sha256).await,
+                    None => {
+                        app_state.storage.remove_blob(sha256).await.ok();
+                    }
+                }
+                tracing::debug!("Removed blob {sha256}");
+            }
+        }
+        None => {
+            if let Some(cache) = &app_state.cache {
+                cache.remove(&file_version_record.id.to_string()).await;
+            }
+        }
+    }
+
+    Ok(update)
+}
+
+/// Moves bytes of live versions left in the local storage dir into the backend, the ones
+/// stored before blobs go into the blob store, their references are counted by the blob migration
+pub async fn adopt_version_files(db: &Database, storage: &Storage) -> Result<usize> {
+    let mut connection = db.connect().await?;
+    let (_, items) = FileSync::snapshot(&mut connection).await?;
+
+    let _blobs = storage.lock_blobs().await;
+    let mut adopted = 0;
+    for item in items {
+        let FileSyncAction::UploadedVersion(meta) = item.action else {
+            continue;
+        };
+        if !storage.exists(&meta.dir_id, &item.file_version_id).await {
+            continue;
+        }
+        match &meta.sha256 {
+            Some(sha256) => {
+                storage
+                    .store_blob(&meta.dir_id, &item.file_version_id, sha256)
+                    .await?;
+            }
+            // the local backend keeps them in place already
+            None => {
+                if storage
+                    .stat(&meta.dir_id, &item.file_version_id)
+                    .await?
+                    .is_some()
+                {
+                    continue;
+                }
+                storage
+                    .store(
+                        &meta.dir_id,
+                        &item.file_version_id,
+                        &meta.dir_id,
+                        &item.file_version_id,
+                    )
+                    .await?;
+            }
+        }
+        adopted += 1;
+    }
+
+    Ok(adopted)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{
+        config::CliConfig,
+        database::files::{
+            file_type::FileType,
+            records::{blob_record::BlobRecord, file_version_record::FileVersionState},
+        },
+    };
+
+    async fn uploaded(
+        app_state: &AppState,
+        connection: &mut SqliteConnection,
+        dir: &DirRecord,
+        file: &FileRecord,
+        version: &str,
+        bytes: &[u8],
+    ) -> FileVersionRecord {
+        let mut file_version = FileVersionRecord::create(
+            connection,
+            &file.id,
+            version,
+            bytes.len() as u64,
+            FileVersionState::Downloading,
+            None,
+        )
+        .await
+        .unwrap();
+        let sha256 = hex::encode(Sha256::digest(bytes));
+        file_version
+            .update_sha256(connection, &sha256)
+            .await
+            .unwrap();
+        let mut written = app_state
+            .storage
+            .create_file(&dir.id.to_string(), &file_version.id.to_string())
+            .await
+            .unwrap();
+        written.write_all(bytes).await.unwrap();
+        written.flush().await.unwrap();
+
+        commit_uploaded(
+            &app_state.storage,
+            connection,
+            LogPosition::default(),
+            dir,
+            file,
+            &mut file_version,
+        )
+        .await
+        .unwrap();
+        file_version
+    }
+
+    #[tokio::test]
+    async fn shared_blob_goes_with_its_last_version() {
+        let root = std::env::temp_dir().join("qcdn-test-blobs");
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        let config = CliConfig::try_parse_from([
+            "manager".to_string(),
+            format!("--db-path={}", root.join("qcdn.db").display()),
+            format!("--storage-dir={}", root.join("storage").display()),
+        ])
+        .unwrap();
+        let app_state = AppState::from_config(&config).await.unwrap();
+        let mut connection = app_state.db.connect().await.unwrap();
+
+        let dir = DirRecord::create(&mut connection, "assets", None)
+            .await
+            .unwrap();
+        let file = FileRecord::create(&mut connection, &dir.id, "a.txt", FileType::Text, None)
+            .await
+            .unwrap();
+        let mut first = uploaded(&app_state, &mut connection, &dir, &file, "1", b"same").await;
+        let mut second = uploaded(&app_state, &mut connection, &dir, &file, "2", b"same").await;
+        let sha256 = first.sha256.clone().unwrap();
+        assert_eq!(second.sha256.as_ref(), Some(&sha256));
+        // the second upload dropped its copy of the bytes
+        assert!(
+            !app_state
+                .storage
+                .exists(&dir.id.to_string(), &second.id.to_string())
+                .await
+        );
+        let blob = BlobRecord::find(&mut connection, &sha256).await.unwrap();
+        assert_eq!(blob.map(|blob| blob.refs), Some(2));
+
+        commit_deleted(
+            &app_state,
+            &mut connection,
+            LogPosition::default(),
+            &mut first,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(app_state.storage.has_blob(&sha256).await);
+        let blob = BlobRecord::find(&mut connection, &sha256).await.unwrap();
+        assert_eq!(blob.map(|blob| blob.refs), Some(1));
+
+        commit_deleted(
+            &app_state,
+            &mut connection,
+            LogPosition::default(),
+            &mut second,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!app_state.storage.has_blob(&sha256).await);
+        assert!(BlobRecord::find(&mut connection, &sha256)
+            .await
+            .unwrap()
+            .is_none());
+
+        drop(connection);
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -1,23 +1,126 @@
-use std::time::SystemTime;
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::{bail, Result};
-use async_channel::Sender;
-use tokio::{fs, io::AsyncWriteExt};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
 use uuid::Uuid;
 
 use crate::{
     database::{
         files::records::{
+            change_log_record::LogPosition,
             dir_record::DirRecord,
             file_record::FileRecord,
             file_version_record::{FileVersionRecord, FileVersionState},
+            upload_part_record::UploadPartRecord,
+            upload_session_record::UploadSessionRecord,
         },
         Database,
     },
-    grpc::{sync_message::MessageType, FilePart, SyncMessage, UploadMeta, UploadedVersion},
+    grpc::{
+        self, CompleteMultipartUploadRequest, FilePart, ResumeUpload, UploadMeta, UploadPartMeta,
+    },
+    sync::{
+        hub::SyncHub,
+        quorum::{self, Quorum},
+    },
     DatabasePoolConnection, Storage,
 };
 
+use super::blobs;
+
+// session progress is saved after this many bytes
+const CHECKPOINT_BYTES: u64 = 1024 * 1024;
+const COPY_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum UploadSessionError {
+    NotFound,
+    Busy,
+    OffsetMismatch { offset: u64 },
+    Incomplete { offset: u64, size: u64 },
+    Multipart,
+    NotMultipart,
+    PartsOrder,
+    MissingPart { part_number: u32 },
+    PartMismatch { part_number: u32 },
+    PartsSize { size: u64, expected: u64 },
+}
+
+impl std::fmt::Display for UploadSessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "upload session not found or expired"),
+            Self::Busy => write!(f, "upload session is written by another stream"),
+            Self::OffsetMismatch { offset } => {
+                write!(f, "upload session continues from offset {offset}")
+            }
+            Self::Incomplete { offset, size } => write!(
+                f,
+                "received {offset} of {size} bytes, resume from offset {offset}"
+            ),
+            Self::Multipart => write!(f, "multipart upload takes bytes in parts"),
+            Self::NotMultipart => write!(f, "upload session is not multipart"),
+            Self::PartsOrder => write!(f, "parts have to be listed in ascending order"),
+            Self::MissingPart { part_number } => write!(f, "part {part_number} was not uploaded"),
+            Self::PartMismatch { part_number } => {
+                write!(f, "part {part_number} sha256 does not match uploaded part")
+            }
+            Self::PartsSize { size, expected } => {
+                write!(f, "parts hold {size} bytes, version expects {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UploadSessionError {}
+
+/// Sessions currently written by a stream
+#[derive(Debug, Clone, Default)]
+pub struct UploadSessions(Arc<Mutex<HashSet<Uuid>>>);
+
+pub struct UploadSessionClaim {
+    sessions: UploadSessions,
+    id: Uuid,
+}
+
+impl UploadSessions {
+    /// None while another stream holds the session
+    pub fn claim(&self, id: Uuid) -> Option<UploadSessionClaim> {
+        if !self
+            .0
+            .lock()
+            .expect("upload sessions lock poisoned")
+            .insert(id)
+        {
+            return None;
+        }
+        Some(UploadSessionClaim {
+            sessions: self.clone(),
+            id,
+        })
+    }
+}
+
+impl Drop for UploadSessionClaim {
+    fn drop(&mut self) {
+        self.sessions
+            .0
+            .lock()
+            .expect("upload sessions lock poisoned")
+            .remove(&self.id);
+    }
+}
+
 pub struct FileUploadRequested {
     connection: DatabasePoolConnection,
 }
@@ -26,11 +129,13 @@ pub struct FileUploading {
     storage: Storage,
     connection: DatabasePoolConnection,
     received_bytes: u64,
+    hasher: Sha256,
     meta: UploadMeta,
     file: fs::File,
     dir_record: DirRecord,
     file_record: FileRecord,
     file_version_record: FileVersionRecord,
+    session: Option<UploadSessionRecord>,
 }
 
 impl FileUploadRequested {
@@ -132,79 +237,588 @@ impl FileUploadRequested {
             storage,
             connection: self.connection,
             received_bytes: 0,
+            hasher: Sha256::new(),
             meta,
             file,
             dir_record,
             file_record,
             file_version_record,
+            session: None,
+        })
+    }
+
+    /// Registers the version for a resumable or multipart upload, bytes arrive in later streams
+    pub async fn start(
+        self,
+        storage: Storage,
+        meta: UploadMeta,
+        multipart: bool,
+    ) -> Result<UploadSessionRecord> {
+        let mut uploading = self.got_meta(storage, meta).await?;
+
+        match UploadSessionRecord::create(
+            &mut uploading.connection,
+            &uploading.file_version_record.id,
+            &uploading.meta,
+            multipart,
+            None,
+        )
+        .await
+        {
+            Ok(session) => Ok(session),
+            Err(e) => {
+                uploading.cleanup().await?;
+                bail!(e)
+            }
+        }
+    }
+
+    /// Continues a session from its committed offset
+    pub async fn resume(self, storage: Storage, resume: ResumeUpload) -> Result<FileUploading> {
+        let mut uploading = self.load_session(storage, &resume.upload_id).await?;
+        if uploading.session.as_ref().is_some_and(|s| s.multipart) {
+            bail!(UploadSessionError::Multipart)
+        }
+        if resume.offset != uploading.received_bytes {
+            bail!(UploadSessionError::OffsetMismatch {
+                offset: uploading.received_bytes
+            })
+        }
+
+        // bytes stored by earlier streams are part of the hash
+        let mut stored = (&mut uploading.file).take(uploading.received_bytes);
+        let mut buf = vec![0; COPY_BUFFER];
+        loop {
+            let n = stored.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            uploading.hasher.update(&buf[..n]);
+        }
+        uploading
+            .file
+            .seek(SeekFrom::Start(uploading.received_bytes))
+            .await?;
+
+        Ok(uploading)
+    }
+
+    /// Stores one part of a multipart upload next to the version
+    pub async fn upload_part(
+        mut self,
+        storage: Storage,
+        meta: UploadPartMeta,
+    ) -> Result<PartUploading> {
+        let upload_id =
+            Uuid::parse_str(&meta.upload_id).map_err(|_| UploadSessionError::NotFound)?;
+        let session = UploadSessionRecord::find_by_id(&mut self.connection, &upload_id)
+            .await?
+            .ok_or(UploadSessionError::NotFound)?;
+        if !session.multipart {
+            bail!(UploadSessionError::NotMultipart)
+        }
+        let file_version_record =
+            FileVersionRecord::find_by_id_in_any_state(&mut self.connection, &upload_id)
+                .await?
+                .ok_or(UploadSessionError::NotFound)?;
+        let file_record =
+            FileRecord::find_by_id(&mut self.connection, &file_version_record.file_id)
+                .await?
+                .ok_or_else(|| anyhow!("File {} not found", file_version_record.file_id))?;
+
+        let dir_id = file_record.dir_id.to_string();
+        // concurrent uploads of the same part number write their own files, the last one wins
+        let filename = format!(
+            "{}.{}.part",
+            part_filename(&upload_id, meta.part_number),
+            Uuid::now_v7()
+        );
+        let file = storage.create_file(&dir_id, &filename).await?;
+
+        Ok(PartUploading {
+            storage,
+            connection: self.connection,
+            session,
+            meta,
+            dir_id,
+            filename,
+            file,
+            received_bytes: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Copies listed parts into the version, it is committed by `FileUploading::end`
+    pub async fn complete_multipart(
+        self,
+        storage: Storage,
+        request: CompleteMultipartUploadRequest,
+    ) -> Result<FileUploading> {
+        let mut uploading = self.load_session(storage, &request.upload_id).await?;
+        let Some(session) = &uploading.session else {
+            bail!(UploadSessionError::NotFound)
+        };
+        if !session.multipart {
+            bail!(UploadSessionError::NotMultipart)
+        }
+
+        let mut stored: HashMap<u32, UploadPartRecord> =
+            UploadPartRecord::get_by_upload(&mut uploading.connection, &session.id)
+                .await?
+                .into_iter()
+                .map(|part| (part.part_number, part))
+                .collect();
+        let mut parts = vec![];
+        let mut size = 0;
+        for completed in &request.parts {
+            if parts
+                .last()
+                .is_some_and(|p: &UploadPartRecord| p.part_number >= completed.part_number)
+            {
+                bail!(UploadSessionError::PartsOrder)
+            }
+            let part =
+                stored
+                    .remove(&completed.part_number)
+                    .ok_or(UploadSessionError::MissingPart {
+                        part_number: completed.part_number,
+                    })?;
+            if completed
+                .sha256
+                .as_ref()
+                .is_some_and(|sha256| !sha256.eq_ignore_ascii_case(&part.sha256))
+            {
+                bail!(UploadSessionError::PartMismatch {
+                    part_number: completed.part_number
+                })
+            }
+            size += part.size;
+            parts.push(part);
+        }
+        if size != uploading.meta.size {
+            bail!(UploadSessionError::PartsSize {
+                size,
+                expected: uploading.meta.size
+            })
+        }
+
+        if let Err(e) = uploading.assemble(&parts).await {
+            uploading.cleanup().await?;
+            bail!(e)
+        }
+        Ok(uploading)
+    }
+
+    /// Drops a resumable or multipart upload with everything it stored
+    pub async fn abort(self, storage: Storage, upload_id: &str) -> Result<()> {
+        let mut uploading = self.load_session(storage, upload_id).await?;
+        uploading.cleanup().await
+    }
+
+    async fn load_session(mut self, storage: Storage, upload_id: &str) -> Result<FileUploading> {
+        let id = Uuid::parse_str(upload_id).map_err(|_| UploadSessionError::NotFound)?;
+        let session = UploadSessionRecord::find_by_id(&mut self.connection, &id)
+            .await?
+            .ok_or(UploadSessionError::NotFound)?;
+
+        let file_version_record =
+            FileVersionRecord::find_by_id_in_any_state(&mut self.connection, &id)
+                .await?
+                .filter(|fv| fv.state == FileVersionState::Downloading)
+                .ok_or(UploadSessionError::NotFound)?;
+        let file_record =
+            FileRecord::find_by_id(&mut self.connection, &file_version_record.file_id)
+                .await?
+                .ok_or_else(|| anyhow!("File {} not found", file_version_record.file_id))?;
+        let dir_record = DirRecord::find_by_id(&mut self.connection, &file_record.dir_id)
+            .await?
+            .ok_or_else(|| anyhow!("Dir {} not found", file_record.dir_id))?;
+
+        let file = storage
+            .open_file_for_write(&dir_record.id.to_string(), &id.to_string())
+            .await?;
+
+        let file_type: grpc::FileType = file_record.file_type.into();
+        let meta = UploadMeta {
+            dir: dir_record.name.clone(),
+            name: file_record.name.clone(),
+            file_type: file_type.into(),
+            version: file_version_record.version.clone(),
+            size: file_version_record.size,
+            consistency: session.consistency,
+            min_replicas: session.min_replicas,
+            timeout_ms: session.timeout_ms,
+            sha256: session.sha256.clone(),
+        };
+
+        Ok(FileUploading {
+            storage,
+            connection: self.connection,
+            received_bytes: session.received_bytes,
+            hasher: Sha256::new(),
+            meta,
+            file,
+            dir_record,
+            file_record,
+            file_version_record,
+            session: Some(session),
         })
     }
 }
 
 impl FileUploading {
     pub async fn cleanup(&mut self) -> Result<()> {
+        // gone already when a failed commit moved it into the blob store
         self.storage
             .remove_file(
                 &self.dir_record.id.to_string(),
                 &self.file_version_record.id.to_string(),
             )
+            .await
+            .ok();
+
+        if let Some(session) = &self.session {
+            remove_parts(
+                &mut self.connection,
+                &self.storage,
+                &self.dir_record.id.to_string(),
+                &session.id,
+            )
             .await?;
+            session.delete(&mut self.connection).await?;
+        }
 
-        self.dir_record
-            .delete_if_no_files_exists(&mut self.connection)
+        self.file_version_record
+            .unsafe_delete(&mut self.connection)
             .await?;
 
         self.file_record
             .delete_if_no_versions_exists(&mut self.connection)
             .await?;
 
-        self.file_version_record
-            .unsafe_delete(&mut self.connection)
+        self.dir_record
+            .delete_if_no_files_exists(&mut self.connection)
             .await?;
 
         Ok(())
     }
 
+    /// Stream ended before the upload did, sessions keep stored bytes until they expire,
+    /// other uploads are removed
+    pub async fn interrupt(&mut self) -> Result<()> {
+        match self.session {
+            Some(_) => self.checkpoint().await,
+            None => self.cleanup().await,
+        }
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        self.file.flush().await?;
+        self.file.sync_data().await?;
+        session
+            .save_progress(&mut self.connection, self.received_bytes, None)
+            .await
+    }
+
+    async fn assemble(&mut self, parts: &[UploadPartRecord]) -> Result<()> {
+        let dir_id = self.dir_record.id.to_string();
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.received_bytes = 0;
+
+        let mut buf = vec![0; COPY_BUFFER];
+        for part in parts {
+            let mut part_file = self
+                .storage
+                .open_file(&dir_id, &part_filename(&part.upload_id, part.part_number))
+                .await?;
+            loop {
+                let n = part_file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                self.file.write_all(&buf[..n]).await?;
+                self.hasher.update(&buf[..n]);
+                self.received_bytes += n as u64;
+            }
+        }
+        self.file.flush().await?;
+
+        remove_parts(
+            &mut self.connection,
+            &self.storage,
+            &dir_id,
+            &self.file_version_record.id,
+        )
+        .await
+    }
+
     pub async fn got_part(&mut self, part: FilePart) -> Result<()> {
-        self.received_bytes += part.bytes.len() as u64;
-        if let Err(e) = self.file.write(&part.bytes).await {
-            self.cleanup().await?;
+        if self.received_bytes + part.bytes.len() as u64 > self.meta.size {
+            self.interrupt().await?;
+            bail!(
+                "file transmission corrupted, got more than expected {} bytes",
+                self.meta.size
+            )
+        }
+        if let Err(e) = self.file.write_all(&part.bytes).await {
+            self.interrupt().await?;
             bail!(e)
         }
+        self.received_bytes += part.bytes.len() as u64;
+        self.hasher.update(&part.bytes);
+
+        if self
+            .session
+            .as_ref()
+            .is_some_and(|s| self.received_bytes - s.received_bytes >= CHECKPOINT_BYTES)
+        {
+            self.checkpoint().await?;
+        }
         Ok(())
     }
 
-    pub async fn end(mut self, sync: Sender<SyncMessage>) -> Result<(Uuid, Uuid, Uuid)> {
+    pub async fn end(
+        mut self,
+        position: LogPosition,
+        db: &Database,
+        sync: &SyncHub,
+        replication_timeout: Duration,
+    ) -> Result<(Uuid, Uuid, Uuid, String, u64)> {
+        if self.session.is_some() && self.received_bytes < self.meta.size {
+            self.checkpoint().await?;
+            bail!(UploadSessionError::Incomplete {
+                offset: self.received_bytes,
+                size: self.meta.size
+            })
+        }
         if self.meta.size != self.received_bytes {
             self.cleanup().await?;
             bail!("file transmission corrupted")
         }
+        if let Err(e) = self.file.flush().await {
+            self.cleanup().await?;
+            bail!(e)
+        }
+        let sha256 = hex::encode(self.hasher.finalize_reset());
+        if let Some(expected) = self.meta.sha256.clone() {
+            if !sha256.eq_ignore_ascii_case(&expected) {
+                self.cleanup().await?;
+                bail!("file content corrupted, expected sha256 {expected}, got {sha256}")
+            }
+        }
+        if let Some(session) = &self.session {
+            if let Err(e) = session.delete(&mut self.connection).await {
+                self.cleanup().await?;
+                bail!(e)
+            }
+        }
         if let Err(e) = self
             .file_version_record
-            .update_state(&mut self.connection, FileVersionState::Ready)
+            .update_sha256(&mut self.connection, &sha256)
             .await
         {
             self.cleanup().await?;
             bail!(e)
         }
-        let ts: SystemTime = self.file_version_record.created_at.into();
-        if let Err(e) = sync
-            .send(SyncMessage {
-                message_type: Some(MessageType::Uploaded(UploadedVersion {
-                    dir_id: self.dir_record.id.to_string(),
-                    file_id: self.file_record.id.to_string(),
-                    file_version_id: self.file_version_record.id.to_string(),
-                })),
-                timestamp: Some(ts.into()),
-            })
-            .await
+        let update = match blobs::commit_uploaded(
+            &self.storage,
+            &mut self.connection,
+            position,
+            &self.dir_record,
+            &self.file_record,
+            &mut self.file_version_record,
+        )
+        .await
         {
-            tracing::error!("{e:?}");
+            Ok(update) => update,
+            Err(e) => {
+                self.cleanup().await?;
+                bail!(e)
+            }
         };
+        let seq = update.seq;
+        sync.publish(update.into());
+
+        if let Some(quorum) = Quorum::from_meta(&self.meta) {
+            let timeout = self
+                .meta
+                .timeout_ms
+                .map(|ms| Duration::from_millis(ms.into()))
+                .unwrap_or(replication_timeout);
+            // the upload connection is not needed anymore, don't hold it while nodes catch up
+            drop(self.connection);
+            quorum::wait(db, sync, &self.file_version_record.id, quorum, timeout).await?;
+        }
+
         Ok((
             self.dir_record.id,
             self.file_record.id,
             self.file_version_record.id,
+            sha256,
+            seq,
         ))
     }
 }
+
+pub struct PartUploading {
+    storage: Storage,
+    connection: DatabasePoolConnection,
+    session: UploadSessionRecord,
+    meta: UploadPartMeta,
+    dir_id: String,
+    filename: String,
+    file: fs::File,
+    received_bytes: u64,
+    hasher: Sha256,
+}
+
+impl PartUploading {
+    pub async fn got_part(&mut self, part: FilePart) -> Result<()> {
+        if let Err(e) = self.file.write_all(&part.bytes).await {
+            self.cleanup().await;
+            bail!(e)
+        }
+        self.received_bytes += part.bytes.len() as u64;
+        self.hasher.update(&part.bytes);
+        Ok(())
+    }
+
+    pub async fn cleanup(&mut self) {
+        self.storage
+            .remove_file(&self.dir_id, &self.filename)
+            .await
+            .ok();
+    }
+
+    pub async fn end(mut self) -> Result<UploadPartRecord> {
+        if let Err(e) = self.file.flush().await {
+            self.cleanup().await;
+            bail!(e)
+        }
+        let sha256 = hex::encode(self.hasher.finalize_reset());
+        if let Some(expected) = self.meta.sha256.clone() {
+            if !sha256.eq_ignore_ascii_case(&expected) {
+                self.cleanup().await;
+                bail!("part content corrupted, expected sha256 {expected}, got {sha256}")
+            }
+        }
+
+        let filename = part_filename(&self.session.id, self.meta.part_number);
+        if let Err(e) = self
+            .storage
+            .rename_file(&self.dir_id, &self.filename, &filename)
+            .await
+        {
+            self.cleanup().await;
+            bail!(e)
+        }
+        // an aborted or expired session fails here, its part files are gone already
+        let part = match UploadPartRecord::save(
+            &mut self.connection,
+            &self.session.id,
+            self.meta.part_number,
+            self.received_bytes,
+            &sha256,
+            None,
+        )
+        .await
+        {
+            Ok(part) => part,
+            Err(e) => {
+                self.storage.remove_file(&self.dir_id, &filename).await.ok();
+                bail!(e)
+            }
+        };
+
+        let received_bytes =
+            UploadPartRecord::total_size(&mut self.connection, &self.session.id).await?;
+        self.session
+            .save_progress(&mut self.connection, received_bytes, None)
+            .await?;
+
+        Ok(part)
+    }
+}
+
+fn part_filename(upload_id: &Uuid, part_number: u32) -> String {
+    format!("{upload_id}.{part_number}")
+}
+
+async fn remove_parts(
+    connection: &mut DatabasePoolConnection,
+    storage: &Storage,
+    dir_id: &str,
+    upload_id: &Uuid,
+) -> Result<()> {
+    for part in UploadPartRecord::get_by_upload(connection, upload_id).await? {
+        storage
+            .remove_file(dir_id, &part_filename(upload_id, part.part_number))
+            .await
+            .ok();
+    }
+    Ok(())
+}
+
+/// Removes bytes and versions of sessions that made no progress for `ttl`,
+/// sessions written at the moment are left alone
+pub async fn expire_sessions(
+    db: &Database,
+    storage: &Storage,
+    sessions: &UploadSessions,
+    ttl: Duration,
+) -> Result<usize> {
+    let mut connection = db.connect().await?;
+    let before = Utc::now() - chrono::Duration::from_std(ttl)?;
+    let stale = UploadSessionRecord::get_stale(&mut connection, before).await?;
+
+    let mut expired = 0;
+    for session in stale {
+        let Some(_claim) = sessions.claim(session.id) else {
+            continue;
+        };
+        let Some(file_version_record) =
+            FileVersionRecord::find_by_id_in_any_state(&mut connection, &session.id).await?
+        else {
+            session.delete(&mut connection).await?;
+            continue;
+        };
+        let Some(file_record) =
+            FileRecord::find_by_id(&mut connection, &file_version_record.file_id).await?
+        else {
+            session.delete(&mut connection).await?;
+            continue;
+        };
+        remove_parts(
+            &mut connection,
+            storage,
+            &file_record.dir_id.to_string(),
+            &session.id,
+        )
+        .await?;
+        session.delete(&mut connection).await?;
+        storage
+            .remove_file(
+                &file_record.dir_id.to_string(),
+                &file_version_record.id.to_string(),
+            )
+            .await
+            .ok();
+        file_version_record.unsafe_delete(&mut connection).await?;
+        file_record
+            .delete_if_no_versions_exists(&mut connection)
+            .await?;
+        if let Some(dir_record) =
+            DirRecord::find_by_id(&mut connection, &file_record.dir_id).await?
+        {
+            dir_record
+                .delete_if_no_files_exists(&mut connection)
+                .await?;
+        }
+        expired += 1;
+    }
+
+    Ok(expired)
+}
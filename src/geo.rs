@@ -0,0 +1,215 @@
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+use anyhow::Result;
+use maxminddb::{geoip2, Reader};
+
+use crate::database::nodes::records::node_record::NodeRecord;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Coordinates {
+    pub fn new(latitude: Option<f64>, longitude: Option<f64>) -> Option<Self> {
+        Some(Self {
+            latitude: latitude?,
+            longitude: longitude?,
+        })
+    }
+
+    /// Great-circle distance by haversine formula
+    pub fn distance_km(&self, other: &Coordinates) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// Offline lookup in a MaxMind format (`.mmdb`) city database
+#[derive(Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database_type", &self.reader.metadata.database_type)
+            .field("build_epoch", &self.reader.metadata.build_epoch)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)?;
+        tracing::info!(
+            "Opened GeoIP database {:?} ({})",
+            path,
+            reader.metadata.database_type
+        );
+        Ok(Self {
+            reader: Arc::new(reader),
+        })
+    }
+}
+
+impl GeoIp {
+    pub fn locate(&self, ip: IpAddr) -> Option<Coordinates> {
+        let city: geoip2::City = match self.reader.lookup(ip) {
+            Ok(city) => city,
+            Err(e) => {
+                tracing::debug!("GeoIP lookup of {ip} failed: {e}");
+                return None;
+            }
+        };
+        let location = city.location?;
+        Coordinates::new(location.latitude, location.longitude)
+    }
+
+    /// Node nearest to the client, disconnected nodes and nodes without known coordinates
+    /// are skipped
+    pub fn closest_node(&self, ip: IpAddr, nodes: Vec<NodeRecord>) -> Option<NodeRecord> {
+        let origin = self.locate(ip)?;
+
+        nodes
+            .into_iter()
+            .filter(|node| node.connected)
+            .filter_map(|node| {
+                let coordinates = Coordinates::new(node.latitude, node.longitude)
+                    .or_else(|| self.locate(node.ip_addr.parse().ok()?))?;
+                Some((origin.distance_km(&coordinates), node))
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, node)| node)
+    }
+
+    /// Url of the node nearest to the client, `fallback` when none can be picked
+    pub fn closest_url(&self, ip: IpAddr, nodes: Vec<NodeRecord>, fallback: &str) -> String {
+        match self.closest_node(ip, nodes) {
+            Some(node) => node.url,
+            None => fallback.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+
+    const BERLIN: Coordinates = Coordinates {
+        latitude: 52.52,
+        longitude: 13.405,
+    };
+    const NEW_YORK: Coordinates = Coordinates {
+        latitude: 40.7128,
+        longitude: -74.006,
+    };
+    const TOKYO: Coordinates = Coordinates {
+        latitude: 35.6762,
+        longitude: 139.6503,
+    };
+
+    fn geoip() -> GeoIp {
+        GeoIp::open(Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/geoip-city.mmdb"
+        )))
+        .unwrap()
+    }
+
+    fn node(url: &str, ip_addr: &str, coordinates: Option<Coordinates>) -> NodeRecord {
+        NodeRecord {
+            id: Uuid::now_v7(),
+            url: url.to_string(),
+            grpc_url: None,
+            ip_addr: ip_addr.to_string(),
+            seq: 0,
+            connected: true,
+            latitude: coordinates.map(|c| c.latitude),
+            longitude: coordinates.map(|c| c.longitude),
+            edge: false,
+            first_seen_at: Utc::now(),
+            last_seen_at: Utc::now(),
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn haversine_distance() {
+        let distance = BERLIN.distance_km(&NEW_YORK);
+        assert!((distance - 6385.0).abs() < 10.0, "{distance}");
+        assert_eq!(BERLIN.distance_km(&BERLIN), 0.0);
+        assert_eq!(BERLIN.distance_km(&TOKYO), TOKYO.distance_km(&BERLIN));
+    }
+
+    #[test]
+    fn locates_ip_from_database() {
+        let geoip = geoip();
+        assert_eq!(geoip.locate(ip("1.2.3.4")), Some(BERLIN));
+        assert_eq!(geoip.locate(ip("3.3.3.3")), Some(TOKYO));
+        assert_eq!(geoip.locate(ip("9.9.9.9")), None);
+    }
+
+    #[test]
+    fn picks_nearest_node() {
+        let nodes = vec![
+            node("http://tokyo", "3.0.0.1", None),
+            node("http://berlin", "10.0.0.1", Some(BERLIN)),
+            node("http://new-york", "2.0.0.1", None),
+        ];
+        let closest = geoip().closest_node(ip("1.1.1.1"), nodes).unwrap();
+        assert_eq!(closest.url, "http://berlin");
+
+        let nodes = vec![
+            node("http://tokyo", "3.0.0.1", None),
+            node("http://new-york", "2.0.0.1", None),
+        ];
+        let closest = geoip().closest_node(ip("1.1.1.1"), nodes).unwrap();
+        assert_eq!(closest.url, "http://new-york");
+    }
+
+    #[test]
+    fn skips_unhealthy_and_unlocated_nodes() {
+        let mut berlin = node("http://berlin", "1.0.0.1", None);
+        berlin.connected = false;
+        let nodes = vec![
+            berlin,
+            node("http://unknown", "9.0.0.1", None),
+            node("http://tokyo", "3.0.0.1", None),
+        ];
+        let closest = geoip().closest_node(ip("1.1.1.1"), nodes).unwrap();
+        assert_eq!(closest.url, "http://tokyo");
+    }
+
+    #[test]
+    fn falls_back_to_main_server_url() {
+        let geoip = geoip();
+        let nodes = || vec![node("http://berlin", "1.0.0.1", None)];
+        assert_eq!(
+            geoip.closest_url(ip("9.9.9.9"), nodes(), "http://main"),
+            "http://main"
+        );
+        assert_eq!(
+            geoip.closest_url(ip("1.1.1.1"), vec![], "http://main"),
+            "http://main"
+        );
+        assert_eq!(
+            geoip.closest_url(ip("2.2.2.2"), nodes(), "http://main"),
+            "http://berlin"
+        );
+    }
+}
@@ -1,5 +1,53 @@
+// `tonic::Status` is large by design and is the error type of every service method
+#![allow(clippy::result_large_err)]
+// generated sync message oneof carries the whole upload payload
+#![allow(clippy::large_enum_variant)]
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::CliConfig;
+
 pub mod server;
 
 tonic::include_proto!("qcdn.general");
 tonic::include_proto!("qcdn.files");
 tonic::include_proto!("qcdn.nodes");
+tonic::include_proto!("qcdn.cluster");
+
+pub fn timestamp_to_datetime(ts: Option<prost_types::Timestamp>) -> Option<DateTime<Utc>> {
+    ts.and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos.try_into().unwrap_or_default()))
+}
+
+pub fn datetime_to_timestamp(ts: DateTime<Utc>) -> Option<prost_types::Timestamp> {
+    let ts: SystemTime = ts.into();
+    Some(ts.into())
+}
+
+impl ConnectionRequest {
+    /// How a node introduces itself to main server
+    pub fn from_config(config: &CliConfig, seq: u64, edge: bool) -> Self {
+        Self {
+            ip_addr_v4: config.host.clone(),
+            url: config.base_url.clone(),
+            grpc_url: config.grpc_url(),
+            seq,
+            latitude: config.latitude,
+            longitude: config.longitude,
+            edge,
+        }
+    }
+}
+
+impl SyncMessage {
+    /// Change kind for logs, the message itself carries the whole version
+    pub fn kind(&self) -> &'static str {
+        match self.message_type {
+            Some(sync_message::MessageType::Uploaded(_)) => "uploaded",
+            Some(sync_message::MessageType::Tagged(_)) => "tagged",
+            Some(sync_message::MessageType::Deleted(_)) => "deleted",
+            None => "unknown",
+        }
+    }
+}
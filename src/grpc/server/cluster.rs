@@ -0,0 +1,136 @@
+use std::{pin::Pin, sync::Arc};
+
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    database::files::records::change_log_record::ChangeLogRecord,
+    grpc::{
+        qcdn_cluster_server::QcdnCluster, ClusterRole, ClusterStatusResponse, FollowRequest,
+        HeartbeatRequest, HeartbeatResponse, SyncMessage, VoteRequest, VoteResponse,
+    },
+    sync::{cluster::Cluster, hub::SyncHub},
+    AppState,
+};
+
+use super::nodes::stream_changes;
+
+#[derive(Debug, Clone)]
+pub struct ClusterService {
+    app_state: Arc<AppState>,
+    sync: SyncHub,
+    cluster: Cluster,
+}
+
+impl ClusterService {
+    pub fn new(app_state: Arc<AppState>, sync: SyncHub, cluster: Cluster) -> Self {
+        Self {
+            app_state,
+            sync,
+            cluster,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl QcdnCluster for ClusterService {
+    type FollowStream = Pin<Box<dyn Stream<Item = Result<SyncMessage, Status>> + Send>>;
+
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> Result<Response<VoteResponse>, Status> {
+        let response = self
+            .cluster
+            .vote(request.into_inner())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let response = self
+            .cluster
+            .heartbeat(request.into_inner())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn follow(
+        &self,
+        request: Request<FollowRequest>,
+    ) -> Result<Response<Self::FollowStream>, Status> {
+        let leadership = self.cluster.leadership();
+        if leadership.role != ClusterRole::Leader {
+            return Err(self.cluster.not_leader());
+        }
+        let request = request.into_inner();
+        let db = self.app_state.db.clone();
+
+        let mut connection = db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let seq = ChangeLogRecord::latest_seq(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        // changes past the leader log were never committed, they cannot be served from here
+        if request.seq > seq {
+            return Err(Status::failed_precondition(format!(
+                "Follower is at seq {}, ahead of leader at {seq}",
+                request.seq
+            )));
+        }
+        let term = ChangeLogRecord::term_at(&mut connection, request.seq)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if term != Some(request.last_term) {
+            return Err(Status::failed_precondition(format!(
+                "Change at seq {} differs from leader",
+                request.seq
+            )));
+        }
+        drop(connection);
+
+        // subscribe before replaying, so nothing published during the replay is missed
+        let subscription = self.sync.subscribe(request.url.clone(), String::new());
+        let cluster = self.cluster.clone();
+
+        let (tx, rx) = mpsc::channel(128);
+
+        tokio::spawn(async move {
+            tokio::select! {
+                res = stream_changes(&db, &tx, subscription, request.seq, None) => {
+                    if let Err(e) = res {
+                        tracing::warn!("Follow stream to {} failed: {e}", request.url);
+                    }
+                }
+                // follower has to find the new leader on its own
+                _ = cluster.demoted(leadership.term) => {}
+            }
+            tracing::info!("Member {} stopped following", request.url);
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn get_cluster_status(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        let response = self
+            .cluster
+            .status()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(response))
+    }
+}
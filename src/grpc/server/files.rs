@@ -1,41 +1,196 @@
-use std::{pin::Pin, sync::Arc, time::SystemTime};
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
 
-use async_channel::Sender;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::io::ReaderStream;
-use tonic::{Request, Response, Status, Streaming};
+use tonic::{metadata::MetadataValue, Request, Response, Status, Streaming};
 use tracing::instrument;
 
 use crate::{
+    constants::SEQ_METADATA_KEY,
     database::files::{
         records::{
-            file_version_record::FileVersionRecord, file_version_tag_record::FileVersionTagRecord,
+            change_log_record::{ChangeLogRecord, LogPosition},
+            file_version_record::FileVersionRecord,
+            upload_session_record::UploadSessionRecord,
         },
         search::{
-            dir_search::DirSearch, file_search::FileSearch, file_version_search::FileVersionSearch,
+            dir_search::DirSearch, file_search::FileSearch,
+            file_version_replica_search::FileVersionReplicaSearch,
+            file_version_search::FileVersionSearch,
+        },
+        sync::FileSync,
+    },
+    entities::files::{
+        blobs,
+        upload_state::{
+            self, FileUploadRequested, UploadSessionClaim, UploadSessionError, UploadSessions,
         },
     },
-    entities::files::upload_state::FileUploadRequested,
     grpc::{
-        qcdn_files_server::QcdnFiles, sync_message::MessageType, upload_request,
-        DeleteFileVersionRequest, DeletedVersion, DownloadRequest, FilePart, GetDirRequest,
-        GetDirResponse, GetDirsResponse, GetFileRequest, GetFileResponse, GetFileVersionRequest,
-        GetFileVersionResponse, GetFileVersionsRequest, GetFileVersionsResponse, GetFilesRequest,
-        GetFilesResponse, SyncMessage, TagVersionRequest, UploadRequest, UploadResponse,
-        VersionTagged,
+        datetime_to_timestamp, qcdn_files_server::QcdnFiles, upload_part_request, upload_request,
+        AbortUploadRequest, CompleteMultipartUploadRequest, DeleteFileVersionRequest,
+        DownloadRequest, FilePart, GetDirRequest, GetDirResponse, GetDirsResponse, GetFileRequest,
+        GetFileResponse, GetFileVersionRequest, GetFileVersionResponse, GetFileVersionsRequest,
+        GetFileVersionsResponse, GetFilesRequest, GetFilesResponse, GetUploadStatusRequest,
+        TagVersionRequest, UploadMeta, UploadPartRequest, UploadPartResponse, UploadRequest,
+        UploadResponse, UploadSession,
     },
+    sync::{cluster::Cluster, hub::SyncHub, quorum::QuorumNotReached},
     AppState,
 };
 
+fn upload_status(e: anyhow::Error) -> Status {
+    if let Some(e) = e.downcast_ref::<UploadSessionError>() {
+        return match e {
+            UploadSessionError::NotFound => Status::not_found(e.to_string()),
+            UploadSessionError::Busy => Status::aborted(e.to_string()),
+            _ => Status::failed_precondition(e.to_string()),
+        };
+    }
+    match e.downcast_ref::<QuorumNotReached>() {
+        // the version is stored and keeps replicating, only confirmation timed out
+        Some(quorum) => Status::deadline_exceeded(format!("Upload is stored but {quorum}")),
+        None => Status::internal(e.to_string()),
+    }
+}
+
+use super::upstream::Upstream;
+
 #[derive(Debug, Clone)]
 pub struct FilesService {
     app_state: Arc<AppState>,
-    sync: Sender<SyncMessage>,
+    sync: SyncHub,
+    upstream: Option<Upstream>,
+    cluster: Option<Cluster>,
+    sessions: UploadSessions,
 }
 
 impl FilesService {
-    pub fn new(app_state: Arc<AppState>, sync: Sender<SyncMessage>) -> Self {
-        Self { app_state, sync }
+    pub fn new(app_state: Arc<AppState>, sync: SyncHub) -> Self {
+        Self {
+            app_state,
+            sync,
+            upstream: None,
+            cluster: None,
+            sessions: Default::default(),
+        }
+    }
+
+    /// Nodes serve reads locally and forward writes to main server
+    pub fn with_upstream(app_state: Arc<AppState>, sync: SyncHub, upstream: Upstream) -> Self {
+        Self {
+            app_state,
+            sync,
+            upstream: Some(upstream),
+            cluster: None,
+            sessions: Default::default(),
+        }
+    }
+
+    /// Clustered main servers take writes only while leading, followers forward them to the leader
+    pub fn in_cluster(mut self, cluster: Option<Cluster>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    fn upstream(&self) -> Result<Option<Upstream>, Status> {
+        if let Some(upstream) = &self.upstream {
+            return Ok(Some(upstream.clone()));
+        }
+        match &self.cluster {
+            Some(cluster) if !cluster.is_leader() => cluster
+                .leader_files()
+                .map(|files| Some(Upstream::new(files, None)))
+                .ok_or_else(|| cluster.not_leader()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Changes are appended in the current term of a cluster leader
+    fn log_position(&self) -> LogPosition {
+        let term = self
+            .cluster
+            .as_ref()
+            .map(|cluster| cluster.leadership().term)
+            .unwrap_or_default();
+        LogPosition::next(term)
+    }
+
+    /// Drops resumable uploads that made no progress for `upload_session_ttl`
+    pub async fn expire_upload_sessions(self) {
+        let ttl = Duration::from_secs(self.app_state.config.upload_session_ttl);
+        let mut interval = tokio::time::interval((ttl / 4).max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            match upload_state::expire_sessions(
+                &self.app_state.db,
+                &self.app_state.storage,
+                &self.sessions,
+                ttl,
+            )
+            .await
+            {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("Expired {expired} upload sessions"),
+                Err(e) => tracing::warn!("Expiring upload sessions failed: {e}"),
+            }
+        }
+    }
+
+    fn claim_session(&self, upload_id: &str) -> Result<UploadSessionClaim, Status> {
+        let id = uuid::Uuid::parse_str(upload_id)
+            .map_err(|_| upload_status(UploadSessionError::NotFound.into()))?;
+        self.sessions
+            .claim(id)
+            .ok_or_else(|| upload_status(UploadSessionError::Busy.into()))
+    }
+
+    async fn start_session(
+        &self,
+        meta: UploadMeta,
+        multipart: bool,
+    ) -> Result<Response<UploadSession>, Status> {
+        let size = meta.size;
+        let session = FileUploadRequested::init(self.app_state.db.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .start(self.app_state.storage.clone(), meta, multipart)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(self.upload_session(&session, size)))
+    }
+
+    fn upload_session(&self, session: &UploadSessionRecord, size: u64) -> UploadSession {
+        let ttl = Duration::from_secs(self.app_state.config.upload_session_ttl);
+        UploadSession {
+            upload_id: session.id.to_string(),
+            offset: session.received_bytes,
+            size,
+            expires_at: chrono::Duration::from_std(ttl)
+                .ok()
+                .and_then(|ttl| datetime_to_timestamp(session.updated_at + ttl)),
+        }
+    }
+
+    /// Attaches change log position `seq` of a write, forwarding nodes wait for it to be applied,
+    /// a cluster leader answers once most members hold it
+    async fn written<T>(&self, message: T, seq: u64) -> Result<Response<T>, Status> {
+        if let Some(cluster) = &self.cluster {
+            let timeout = Duration::from_secs(self.app_state.config.replication_timeout);
+            if !cluster.wait_committed(seq, timeout).await {
+                return Err(Status::deadline_exceeded(format!(
+                    "Change is stored on leader but seq {seq} is not on most main servers yet, \
+                     it is taken back unless the next leader has it"
+                )));
+            }
+        }
+
+        let mut response = Response::new(message);
+        response
+            .metadata_mut()
+            .insert(SEQ_METADATA_KEY, MetadataValue::from(seq));
+        Ok(response)
     }
 }
 
@@ -155,11 +310,28 @@ impl QcdnFiles for FilesService {
         let file_id = uuid::Uuid::parse_str(&file_id)
             .map_err(|e| Status::invalid_argument(format!("file_id is not valid uuid {e:?}")))?;
 
+        let mut replicas: HashMap<String, Vec<FileVersionReplicaSearch>> = HashMap::new();
+        for replica in FileVersionReplicaSearch::find_by_file_id(&mut connection, &file_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+        {
+            replicas
+                .entry(replica.file_version_id.clone())
+                .or_default()
+                .push(replica);
+        }
+        let connected_nodes = FileVersionReplicaSearch::count_connected_nodes(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
         let items = FileVersionSearch::find_by_file_id(&mut connection, &file_id)
             .await
             .map_err(|e| Status::internal(e.to_string()))?
             .into_iter()
-            .map(|item| item.into())
+            .map(|item| {
+                let held = replicas.remove(&item.id).unwrap_or_default();
+                item.with_replicas(held, connected_nodes)
+            })
             .collect();
 
         Ok(Response::new(GetFileVersionsResponse { items }))
@@ -184,8 +356,14 @@ impl QcdnFiles for FilesService {
         let item = FileVersionSearch::find_by_id(&mut connection, &id)
             .await
             .map_err(|e| Status::internal(e.to_string()))?
-            .ok_or(Status::not_found("FileVersionSearch not found"))?
-            .into();
+            .ok_or(Status::not_found("FileVersionSearch not found"))?;
+        let replicas = FileVersionReplicaSearch::find_by_file_version_id(&mut connection, &id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let connected_nodes = FileVersionReplicaSearch::count_connected_nodes(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let item = item.with_replicas(replicas, connected_nodes);
 
         Ok(Response::new(item))
     }
@@ -195,68 +373,269 @@ impl QcdnFiles for FilesService {
         &self,
         request: Request<Streaming<UploadRequest>>,
     ) -> Result<Response<UploadResponse>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.upload(request).await;
+        }
+
         let mut in_stream = request.into_inner();
 
         let state = FileUploadRequested::init(self.app_state.db.clone())
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let mut state = if let Some(result) = in_stream
-            .next()
-            .await
-            .and_then(|r| r.map(|r| r.request).transpose())
-            .transpose()?
-        {
-            match result {
-                upload_request::Request::Meta(meta) => state
-                    .got_meta(self.app_state.storage.clone(), meta)
+        // a resumed session is written by one stream at a time, released when upload returns
+        let mut _claim = None;
+        let mut state = match in_stream.message().await?.and_then(|r| r.request) {
+            Some(upload_request::Request::Meta(meta)) => state
+                .got_meta(self.app_state.storage.clone(), meta)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?,
+            Some(upload_request::Request::Resume(resume)) => {
+                _claim = Some(self.claim_session(&resume.upload_id)?);
+                state
+                    .resume(self.app_state.storage.clone(), resume)
                     .await
-                    .map_err(|e| Status::internal(e.to_string()))?,
-                _ => {
-                    return Err(Status::failed_precondition(
-                        "UploadFileMeta should be first message",
-                    ));
-                }
+                    .map_err(upload_status)?
+            }
+            Some(upload_request::Request::Part(_)) => {
+                return Err(Status::failed_precondition(
+                    "UploadFileMeta should be first message",
+                ));
             }
-        } else {
-            return Err(Status::failed_precondition("Message not received"));
+            None => return Err(Status::failed_precondition("Message not received")),
         };
 
-        while let Some(result) = in_stream
-            .next()
-            .await
-            .and_then(|r| r.map(|r| r.request).transpose())
-            .transpose()?
-        {
-            match result {
-                upload_request::Request::Meta(_) => {
+        loop {
+            let message = match in_stream.message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(status) => {
                     state
-                        .cleanup()
+                        .interrupt()
+                        .await
+                        .map_err(|e| Status::internal(e.to_string()))?;
+                    return Err(status);
+                }
+            };
+            match message.request {
+                Some(upload_request::Request::Part(part)) => state
+                    .got_part(part)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?,
+                Some(_) => {
+                    state
+                        .interrupt()
                         .await
                         .map_err(|e| Status::internal(e.to_string()))?;
                     return Err(Status::aborted(
                         "UploadFileMeta message cannot be sent twice",
                     ));
                 }
-                upload_request::Request::Part(part) => state
+                None => {}
+            };
+        }
+
+        let replication_timeout = Duration::from_secs(self.app_state.config.replication_timeout);
+        let (dir_id, file_id, file_version_id, sha256, seq) = state
+            .end(
+                self.log_position(),
+                &self.app_state.db,
+                &self.sync,
+                replication_timeout,
+            )
+            .await
+            .map_err(upload_status)?;
+
+        self.written(
+            UploadResponse {
+                dir_id: dir_id.to_string(),
+                file_id: file_id.to_string(),
+                file_version_id: file_version_id.to_string(),
+                sha256,
+            },
+            seq,
+        )
+        .await
+    }
+
+    async fn start_upload(
+        &self,
+        request: Request<UploadMeta>,
+    ) -> Result<Response<UploadSession>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.start_upload(request.into_inner()).await;
+        }
+
+        self.start_session(request.into_inner(), false).await
+    }
+
+    async fn get_upload_status(
+        &self,
+        request: Request<GetUploadStatusRequest>,
+    ) -> Result<Response<UploadSession>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.get_upload_status(request.into_inner()).await;
+        }
+
+        let not_found = || upload_status(UploadSessionError::NotFound.into());
+        let id = uuid::Uuid::parse_str(&request.get_ref().upload_id).map_err(|_| not_found())?;
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let session = UploadSessionRecord::find_by_id(&mut connection, &id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(not_found)?;
+        let file_version = FileVersionRecord::find_by_id_in_any_state(&mut connection, &id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(not_found)?;
+
+        Ok(Response::new(
+            self.upload_session(&session, file_version.size),
+        ))
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        request: Request<UploadMeta>,
+    ) -> Result<Response<UploadSession>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.create_multipart_upload(request.into_inner()).await;
+        }
+
+        self.start_session(request.into_inner(), true).await
+    }
+
+    async fn upload_part(
+        &self,
+        request: Request<Streaming<UploadPartRequest>>,
+    ) -> Result<Response<UploadPartResponse>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.upload_part(request).await;
+        }
+
+        let mut in_stream = request.into_inner();
+
+        let Some(upload_part_request::Request::Meta(meta)) =
+            in_stream.message().await?.and_then(|r| r.request)
+        else {
+            return Err(Status::failed_precondition(
+                "UploadPartMeta should be first message",
+            ));
+        };
+        if meta.part_number == 0 {
+            return Err(Status::invalid_argument("part_number starts at 1"));
+        }
+        // held for the whole part stream, complete or abort wait for it to end
+        let _claim = self.claim_session(&meta.upload_id)?;
+
+        let mut state = FileUploadRequested::init(self.app_state.db.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .upload_part(self.app_state.storage.clone(), meta)
+            .await
+            .map_err(upload_status)?;
+
+        loop {
+            let message = match in_stream.message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(status) => {
+                    state.cleanup().await;
+                    return Err(status);
+                }
+            };
+            match message.request {
+                Some(upload_part_request::Request::Part(part)) => state
                     .got_part(part)
                     .await
                     .map_err(|e| Status::internal(e.to_string()))?,
+                Some(_) => {
+                    state.cleanup().await;
+                    return Err(Status::aborted(
+                        "UploadPartMeta message cannot be sent twice",
+                    ));
+                }
+                None => {}
             };
         }
 
-        let (dir_id, file_id, file_version_id) = state
-            .end(self.sync.clone())
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+        let part = state.end().await.map_err(upload_status)?;
 
-        Ok(Response::new(UploadResponse {
-            dir_id: dir_id.to_string(),
-            file_id: file_id.to_string(),
-            file_version_id: file_version_id.to_string(),
+        Ok(Response::new(UploadPartResponse {
+            part_number: part.part_number,
+            size: part.size,
+            sha256: part.sha256,
         }))
     }
 
+    async fn complete_multipart_upload(
+        &self,
+        request: Request<CompleteMultipartUploadRequest>,
+    ) -> Result<Response<UploadResponse>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream
+                .complete_multipart_upload(request.into_inner())
+                .await;
+        }
+
+        let request = request.into_inner();
+        let _claim = self.claim_session(&request.upload_id)?;
+
+        let state = FileUploadRequested::init(self.app_state.db.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .complete_multipart(self.app_state.storage.clone(), request)
+            .await
+            .map_err(upload_status)?;
+
+        let replication_timeout = Duration::from_secs(self.app_state.config.replication_timeout);
+        let (dir_id, file_id, file_version_id, sha256, seq) = state
+            .end(
+                self.log_position(),
+                &self.app_state.db,
+                &self.sync,
+                replication_timeout,
+            )
+            .await
+            .map_err(upload_status)?;
+
+        self.written(
+            UploadResponse {
+                dir_id: dir_id.to_string(),
+                file_id: file_id.to_string(),
+                file_version_id: file_version_id.to_string(),
+                sha256,
+            },
+            seq,
+        )
+        .await
+    }
+
+    async fn abort_upload(
+        &self,
+        request: Request<AbortUploadRequest>,
+    ) -> Result<Response<()>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.abort_upload(request.into_inner()).await;
+        }
+
+        let _claim = self.claim_session(&request.get_ref().upload_id)?;
+        FileUploadRequested::init(self.app_state.db.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .abort(self.app_state.storage.clone(), &request.get_ref().upload_id)
+            .await
+            .map_err(upload_status)?;
+
+        Ok(Response::new(()))
+    }
+
     type DownloadStream = Pin<Box<dyn Stream<Item = Result<FilePart, Status>> + Send>>;
 
     #[instrument]
@@ -275,19 +654,18 @@ impl QcdnFiles for FilesService {
         let file_version_id = uuid::Uuid::parse_str(&file_version_id).map_err(|e| {
             Status::invalid_argument(format!("file_version_id is not valid uuid {e:?}"))
         })?;
-        let (dir_id, file_version_id) =
-            FileVersionRecord::find_by_id(&mut connection, &file_version_id)
-                .await
-                .map_err(|e| Status::internal(e.to_string()))?
-                .ok_or(Status::not_found("File version not found"))?
-                .path(&mut connection)
-                .await
-                .map_err(|e| Status::internal(e.to_string()))?;
+        let (dir, filename) = FileVersionRecord::find_by_id(&mut connection, &file_version_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("File version not found"))?
+            .path(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
 
         let file = self
             .app_state
             .storage
-            .open_file(&dir_id, &file_version_id)
+            .read(&dir, &filename, None)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
@@ -307,6 +685,10 @@ impl QcdnFiles for FilesService {
         &self,
         request: Request<TagVersionRequest>,
     ) -> Result<Response<()>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.tag_version(request.into_inner()).await;
+        }
+
         let mut connection = self
             .app_state
             .db
@@ -321,27 +703,20 @@ impl QcdnFiles for FilesService {
         })?;
         let tag = request.tag;
 
-        let t = FileVersionTagRecord::create_or_move(&mut connection, &file_version_id, &tag, None)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+        let update = FileSync::commit_tagged(
+            &mut connection,
+            self.log_position(),
+            &file_version_id,
+            &tag,
+            None,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        let seq = update.seq;
 
-        let ts: SystemTime = t.activated_at.into();
-        if let Err(e) = self
-            .sync
-            .clone()
-            .send(SyncMessage {
-                message_type: Some(MessageType::Tagged(VersionTagged {
-                    file_version_id: t.file_version_id.to_string(),
-                    tag: t.name,
-                })),
-                timestamp: Some(ts.into()),
-            })
-            .await
-        {
-            tracing::error!("{e:?}");
-        };
+        self.sync.publish(update.into());
 
-        Ok(Response::new(()))
+        self.written((), seq).await
     }
 
     #[instrument]
@@ -349,6 +724,10 @@ impl QcdnFiles for FilesService {
         &self,
         request: Request<DeleteFileVersionRequest>,
     ) -> Result<Response<()>, Status> {
+        if let Some(upstream) = self.upstream()? {
+            return upstream.delete_file_version(request.into_inner()).await;
+        }
+
         let mut connection = self
             .app_state
             .db
@@ -365,27 +744,27 @@ impl QcdnFiles for FilesService {
             .map_err(|e| Status::internal(e.to_string()))?
             .ok_or(Status::not_found("File version not found"))?;
 
-        fv.delete(&mut connection, None)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+        if fv.deleted_at.is_some() {
+            // the delete may still be on its way to most members
+            let seq = ChangeLogRecord::latest_seq_of(&mut connection, &fv.id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            return self.written((), seq).await;
+        }
 
-        if let Err(e) = self
-            .sync
-            .clone()
-            .send(SyncMessage {
-                message_type: Some(MessageType::Deleted(DeletedVersion {
-                    file_version_id: fv.id.to_string(),
-                })),
-                timestamp: fv
-                    .deleted_at
-                    .map(|ts| ts.into())
-                    .map(|ts: SystemTime| ts.into()),
-            })
-            .await
-        {
-            tracing::error!("{e:?}");
-        };
+        let update = blobs::commit_deleted(
+            &self.app_state,
+            &mut connection,
+            self.log_position(),
+            &mut fv,
+            None,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        let seq = update.seq;
 
-        Ok(Response::new(()))
+        self.sync.publish(update.into());
+
+        self.written((), seq).await
     }
 }
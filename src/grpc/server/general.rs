@@ -4,11 +4,24 @@ use tonic::{Request, Response, Status};
 
 use crate::{
     constants::VERSION,
-    grpc::{qcdn_general_server::QcdnGeneral, PingMessage, VersionResponse},
+    grpc::{
+        qcdn_general_server::QcdnGeneral, PingMessage, ReplicationStatusResponse, VersionResponse,
+    },
+    sync::status::ReplicationStatus,
 };
 
 #[derive(Debug, Default)]
-pub struct GeneralService {}
+pub struct GeneralService {
+    replication: Option<ReplicationStatus>,
+}
+
+impl GeneralService {
+    pub fn with_replication(replication: ReplicationStatus) -> Self {
+        Self {
+            replication: Some(replication),
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl QcdnGeneral for GeneralService {
@@ -34,4 +47,17 @@ impl QcdnGeneral for GeneralService {
 
         Ok(Response::new(reply))
     }
+
+    async fn replication_status(
+        &self,
+        _: Request<()>,
+    ) -> Result<Response<ReplicationStatusResponse>, Status> {
+        let Some(replication) = &self.replication else {
+            return Err(Status::failed_precondition(
+                "Replication status is only available on nodes",
+            ));
+        };
+
+        Ok(Response::new(replication.to_response()))
+    }
 }
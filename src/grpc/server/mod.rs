@@ -1,3 +1,6 @@
+pub mod cluster;
 pub mod files;
 pub mod general;
 pub mod nodes;
+pub mod replica;
+pub mod upstream;
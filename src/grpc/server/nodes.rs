@@ -1,16 +1,25 @@
-use std::{pin::Pin, sync::Arc};
+use std::{collections::HashSet, net::IpAddr, pin::Pin, sync::Arc, time::Duration};
 
-use async_channel::Receiver;
-use chrono::DateTime;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast::error::RecvError, mpsc, watch};
 use tokio_stream::{wrappers::ReceiverStream, Stream};
 use tonic::{Request, Response, Status};
+use uuid::Uuid;
 
 use crate::{
-    database::files::sync::FileSync,
+    database::{
+        files::{records::change_log_record::ChangeLogRecord, sync::FileSync},
+        nodes::records::node_record::NodeRecord,
+        Database,
+    },
     grpc::{
-        qcdn_nodes_server::QcdnNodes, ConnectionRequest, GetClosestUrlRequest,
-        GetClosestUrlResponse, SyncMessage,
+        datetime_to_timestamp, qcdn_nodes_server::QcdnNodes, AckRequest, ConnectionRequest,
+        GetClosestUrlRequest, GetClosestUrlResponse, GetNodeRequest, GetNodeResponse,
+        ListNodesResponse, ReconcileRequest, ReconcileResponse, SnapshotMessage, SyncMessage,
+    },
+    sync::{
+        cluster::Cluster,
+        hub::{Subscription, SyncHub},
+        reconcile::Catalogue,
     },
     AppState,
 };
@@ -18,51 +27,197 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct NodesService {
     app_state: Arc<AppState>,
-    sync: Receiver<SyncMessage>,
+    sync: SyncHub,
+    cluster: Option<Cluster>,
 }
 
 impl NodesService {
-    pub fn new(app_state: Arc<AppState>, sync: Receiver<SyncMessage>) -> Self {
-        Self { app_state, sync }
+    pub fn new(app_state: Arc<AppState>, sync: SyncHub) -> Self {
+        Self {
+            app_state,
+            sync,
+            cluster: None,
+        }
+    }
+
+    /// Nodes replicate from the cluster leader only, followers point them to it
+    pub fn in_cluster(mut self, cluster: Option<Cluster>) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    fn ensure_leader(&self) -> Result<Option<u64>, Status> {
+        match &self.cluster {
+            Some(cluster) if !cluster.is_leader() => Err(cluster.not_leader()),
+            Some(cluster) => Ok(Some(cluster.leadership().term)),
+            None => Ok(None),
+        }
+    }
+}
+
+const REPLAY_BATCH: u32 = 256;
+
+async fn replay(
+    db: &Database,
+    tx: &mpsc::Sender<Result<SyncMessage, Status>>,
+    cursor: &mut u64,
+    limit: Option<u64>,
+) -> anyhow::Result<()> {
+    let mut connection = db.connect().await?;
+    let mut head_seq = ChangeLogRecord::latest_seq(&mut connection).await?;
+    if let Some(limit) = limit {
+        head_seq = head_seq.min(limit);
+    }
+    loop {
+        let updates = FileSync::from_seq(&mut connection, *cursor, REPLAY_BATCH).await?;
+        if updates.is_empty() {
+            return Ok(());
+        }
+        for update in updates {
+            if limit.is_some_and(|limit| update.seq > limit) {
+                return Ok(());
+            }
+            *cursor = update.seq;
+            let mut message: SyncMessage = update.into();
+            message.head_seq = message.head_seq.max(head_seq);
+            tx.send(Ok(message)).await?;
+        }
+    }
+}
+
+/// Replays the change log past `cursor`, then sends live changes until `tx` is closed,
+/// with `committed` only changes most cluster members hold are sent
+pub async fn stream_changes(
+    db: &Database,
+    tx: &mpsc::Sender<Result<SyncMessage, Status>>,
+    mut subscription: Subscription,
+    mut cursor: u64,
+    committed: Option<watch::Receiver<u64>>,
+) -> anyhow::Result<()> {
+    if let Some(committed) = committed {
+        return stream_committed(db, tx, subscription, cursor, committed).await;
+    }
+    replay(db, tx, &mut cursor, None).await?;
+    loop {
+        let update = tokio::select! {
+            _ = tx.closed() => break,
+            update = subscription.recv() => update,
+        };
+        match update {
+            Ok(update) if update.seq <= cursor => continue,
+            Ok(update) if update.seq == cursor + 1 => {
+                cursor = update.seq;
+                tx.send(Ok(update)).await?;
+            }
+            // the log is the source of truth, any gap is filled from it
+            Ok(_) => replay(db, tx, &mut cursor, None).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    "Sync subscriber {:?} lagged by {skipped} messages",
+                    subscription.info
+                );
+                replay(db, tx, &mut cursor, None).await?;
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Changes a new leader could lack stay on the leader until they are committed,
+/// the log is replayed whenever the commit seq moves
+async fn stream_committed(
+    db: &Database,
+    tx: &mpsc::Sender<Result<SyncMessage, Status>>,
+    // kept while streaming, the node counts as subscribed
+    _subscription: Subscription,
+    mut cursor: u64,
+    mut committed: watch::Receiver<u64>,
+) -> anyhow::Result<()> {
+    loop {
+        let limit = *committed.borrow_and_update();
+        replay(db, tx, &mut cursor, Some(limit)).await?;
+        tokio::select! {
+            _ = tx.closed() => break,
+            res = committed.changed() => {
+                if res.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn node_response(node: NodeRecord, head_seq: u64) -> GetNodeResponse {
+    GetNodeResponse {
+        id: node.id.to_string(),
+        url: node.url,
+        grpc_url: node.grpc_url,
+        ip_addr_v4: node.ip_addr,
+        seq: node.seq,
+        lag: head_seq.saturating_sub(node.seq),
+        connected: node.connected,
+        first_seen_at: datetime_to_timestamp(node.first_seen_at),
+        last_seen_at: datetime_to_timestamp(node.last_seen_at),
+        latitude: node.latitude,
+        longitude: node.longitude,
+        edge: node.edge,
     }
 }
 
 #[tonic::async_trait]
 impl QcdnNodes for NodesService {
     type ConnectNodeStream = Pin<Box<dyn Stream<Item = Result<SyncMessage, Status>> + Send>>;
+    type GetSnapshotStream = Pin<Box<dyn Stream<Item = Result<SnapshotMessage, Status>> + Send>>;
 
     async fn connect_node(
         &self,
         request: Request<ConnectionRequest>,
     ) -> Result<Response<Self::ConnectNodeStream>, Status> {
-        let ts = request.into_inner().timestamp.and_then(|ts| {
-            DateTime::from_timestamp(ts.seconds, ts.nanos.try_into().unwrap_or_default())
-        });
+        let term = self.ensure_leader()?;
+        let request = request.into_inner();
+        let db = self.app_state.db.clone();
 
-        let mut connection = self
-            .app_state
-            .db
+        let mut connection = db
             .connect()
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
+        let mut node = NodeRecord::connect(&mut connection, &request, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        drop(connection);
 
-        let in_updates = self.sync.clone();
+        // subscribe before replaying, so nothing published during the replay is missed
+        let subscription = self.sync.subscribe(request.url, request.ip_addr_v4);
+        let sync = self.sync.clone();
+        let cluster = self.cluster.clone();
+        let committed = cluster.as_ref().map(Cluster::committed);
 
         let (tx, rx) = mpsc::channel(128);
 
         tokio::spawn(async move {
-            if let Some(ts) = ts {
-                let mut updates = FileSync::uploaded_from_ts(&mut connection, &ts).await?;
-                updates.extend(FileSync::tagged_from_ts(&mut connection, &ts).await?);
-                updates.extend(FileSync::deleted_from_ts(&mut connection, &ts).await?);
-                updates.sort_by_key(|u| u.timestamp);
-
-                for update in updates {
-                    tx.send(Ok(update.into())).await?;
+            let demoted = async {
+                match (&cluster, term) {
+                    (Some(cluster), Some(term)) => cluster.demoted(term).await,
+                    _ => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                res = stream_changes(&db, &tx, subscription, request.seq, committed) => {
+                    if let Err(e) = res {
+                        tracing::warn!("Sync stream to {} failed: {e}", node.url);
+                    }
                 }
+                // closing the stream sends the node looking for the new leader
+                _ = demoted => {}
             }
-            while let Ok(update) = in_updates.recv().await {
-                tx.send(Ok(update)).await?;
+            // the same node may have reconnected in the meantime
+            if !sync.subscribers().iter().any(|s| s.url == node.url) {
+                let mut connection = db.connect().await?;
+                node.disconnect(&mut connection, None).await?;
+                tracing::info!("Node {} disconnected", node.url);
+                sync.replicas_changed();
             }
             anyhow::Ok(())
         });
@@ -73,8 +228,203 @@ impl QcdnNodes for NodesService {
 
     async fn get_closest_url(
         &self,
-        _request: Request<GetClosestUrlRequest>,
+        request: Request<GetClosestUrlRequest>,
     ) -> Result<Response<GetClosestUrlResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+        let ip_addr: IpAddr =
+            request.into_inner().ip_addr_v4.parse().map_err(|e| {
+                Status::invalid_argument(format!("ip_addr_v4 is not valid ip {e:?}"))
+            })?;
+
+        let url = match &self.app_state.geoip {
+            Some(geoip) => {
+                let mut connection = self
+                    .app_state
+                    .db
+                    .connect()
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                let nodes = NodeRecord::get_connected(&mut connection)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                geoip.closest_url(ip_addr, nodes, &self.app_state.config.base_url)
+            }
+            None => self.app_state.config.base_url.clone(),
+        };
+        tracing::debug!("Closest url for {ip_addr} is {url}");
+
+        Ok(Response::new(GetClosestUrlResponse { url }))
+    }
+
+    async fn list_nodes(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ListNodesResponse>, Status> {
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let head_seq = ChangeLogRecord::latest_seq(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let items = NodeRecord::get_all(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(|node| node_response(node, head_seq))
+            .collect();
+
+        Ok(Response::new(ListNodesResponse { items }))
+    }
+
+    async fn get_node(
+        &self,
+        request: Request<GetNodeRequest>,
+    ) -> Result<Response<GetNodeResponse>, Status> {
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let id = request.into_inner().id;
+        let id = uuid::Uuid::parse_str(&id)
+            .map_err(|e| Status::invalid_argument(format!("id is not valid uuid {e:?}")))?;
+
+        let head_seq = ChangeLogRecord::latest_seq(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let node = NodeRecord::find_by_id(&mut connection, &id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("Node not found"))?;
+
+        Ok(Response::new(node_response(node, head_seq)))
+    }
+
+    async fn ack_sync(&self, request: Request<AckRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut node = NodeRecord::find_by_url(&mut connection, &request.url)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("Node not found"))?;
+
+        node.ack(&mut connection, request.seq, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        tracing::debug!("Node {} acknowledged seq {}", node.url, node.seq);
+        self.sync.replicas_changed();
+
+        Ok(Response::new(()))
+    }
+
+    async fn get_snapshot(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Self::GetSnapshotStream>, Status> {
+        self.ensure_leader()?;
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        // catalogue metadata is small, reading it upfront keeps the read transaction short
+        let (seq, items) = FileSync::snapshot(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if let Some(cluster) = &self.cluster {
+            let timeout = Duration::from_secs(self.app_state.config.replication_timeout);
+            if !cluster.wait_committed(seq, timeout).await {
+                return Err(Status::unavailable(format!(
+                    "Snapshot at seq {seq} is not on most main servers yet"
+                )));
+            }
+        }
+        tracing::info!("Streaming snapshot of {} items at seq {seq}", items.len());
+
+        let end = SnapshotMessage {
+            item: None,
+            seq,
+            timestamp: None,
+        };
+        let stream = tokio_stream::iter(
+            items
+                .into_iter()
+                .map(SnapshotMessage::from)
+                .chain(std::iter::once(end))
+                .map(Ok),
+        );
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn reconcile(
+        &self,
+        request: Request<ReconcileRequest>,
+    ) -> Result<Response<ReconcileResponse>, Status> {
+        self.ensure_leader()?;
+        let request = request.into_inner();
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut catalogue = Catalogue::load(&mut connection)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        if request.seq > catalogue.seq {
+            return Err(Status::failed_precondition(format!(
+                "Node is at seq {}, main server is at seq {}",
+                request.seq, catalogue.seq
+            )));
+        }
+
+        // versions changed past the node cursor are still on their way, both sides are compared
+        // without them
+        let lagging: HashSet<String> = ChangeLogRecord::changed_after(&mut connection, request.seq)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .iter()
+            .map(Uuid::to_string)
+            .collect();
+        catalogue.exclude(&lagging);
+
+        let response = if request.partial {
+            let mut dirs = request.dirs;
+            for dir in dirs.iter_mut() {
+                dir.versions
+                    .retain(|version| !lagging.contains(&version.file_version_id));
+            }
+            ReconcileResponse {
+                mismatched_dirs: vec![],
+                changes: catalogue.changes(&dirs),
+            }
+        } else {
+            ReconcileResponse {
+                mismatched_dirs: catalogue.mismatched(&request.dirs),
+                changes: vec![],
+            }
+        };
+
+        Ok(Response::new(response))
     }
 }
@@ -0,0 +1,208 @@
+use std::{pin::Pin, sync::Arc};
+
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+use crate::{
+    database::files::records::file_version_record::{FileVersionRecord, FileVersionState},
+    grpc::{
+        put_version_request, qcdn_replica_server::QcdnReplica, AckRequest, ConnectionRequest,
+        PutVersionRequest, SyncMessage,
+    },
+    sync::{
+        replica::{Replica, PUSHED_SUFFIX},
+        status::ReplicationStatus,
+    },
+    AppState,
+};
+
+/// Node side of push replication
+#[derive(Debug, Clone)]
+pub struct ReplicaService {
+    app_state: Arc<AppState>,
+    status: ReplicationStatus,
+    // changes are applied by a single push session at a time
+    session: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl ReplicaService {
+    pub fn new(app_state: Arc<AppState>, status: ReplicationStatus) -> Self {
+        Self {
+            app_state,
+            status,
+            session: Default::default(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl QcdnReplica for ReplicaService {
+    type PushStream = Pin<Box<dyn Stream<Item = Result<AckRequest, Status>> + Send>>;
+
+    async fn handshake(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ConnectionRequest>, Status> {
+        let seq = Replica::stored_cursor(&self.app_state)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(ConnectionRequest::from_config(
+            &self.app_state.config,
+            seq,
+            self.app_state.cache.is_some(),
+        )))
+    }
+
+    async fn put_version(
+        &self,
+        request: Request<Streaming<PutVersionRequest>>,
+    ) -> Result<Response<()>, Status> {
+        let mut in_stream = request.into_inner();
+
+        let Some(put_version_request::Request::Meta(meta)) =
+            in_stream.message().await?.and_then(|r| r.request)
+        else {
+            return Err(Status::failed_precondition(
+                "UploadedVersion should be first message",
+            ));
+        };
+
+        let file_version_id = Uuid::parse_str(&meta.file_version_id).map_err(|e| {
+            Status::invalid_argument(format!("file_version_id is not valid uuid {e:?}"))
+        })?;
+        let mut connection = self
+            .app_state
+            .db
+            .connect()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        // replays after reconnect push versions the node already holds
+        if FileVersionRecord::find_by_id_in_any_state(&mut connection, &file_version_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .is_some_and(|fv| fv.state == FileVersionState::Ready)
+        {
+            return Ok(Response::new(()));
+        }
+        drop(connection);
+        // identical bytes are already stored for another version
+        if let Some(sha256) = &meta.sha256 {
+            if self.app_state.storage.has_blob(sha256).await {
+                return Ok(Response::new(()));
+            }
+        }
+
+        let pushed = format!("{}{PUSHED_SUFFIX}", meta.file_version_id);
+        let mut file = self
+            .app_state
+            .storage
+            .create_file(&meta.dir_id, &pushed)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut hasher = Sha256::new();
+        let mut received_bytes = 0;
+        while let Some(message) = in_stream.message().await? {
+            let Some(put_version_request::Request::Part(part)) = message.request else {
+                return Err(Status::aborted(
+                    "UploadedVersion message cannot be sent twice",
+                ));
+            };
+            received_bytes += part.bytes.len() as u64;
+            hasher.update(&part.bytes);
+            file.write_all(&part.bytes)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+        file.flush()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        if received_bytes != meta.size {
+            self.app_state
+                .storage
+                .remove_file(&meta.dir_id, &pushed)
+                .await
+                .ok();
+            return Err(Status::data_loss(format!(
+                "file transmission corrupted, expected {} bytes, got {received_bytes}",
+                meta.size
+            )));
+        }
+        let sha256 = hex::encode(hasher.finalize());
+        if let Some(expected) = meta.sha256.filter(|e| !e.eq_ignore_ascii_case(&sha256)) {
+            self.app_state
+                .storage
+                .remove_file(&meta.dir_id, &pushed)
+                .await
+                .ok();
+            return Err(Status::data_loss(format!(
+                "file content corrupted, expected sha256 {expected}, got {sha256}"
+            )));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn push(
+        &self,
+        request: Request<Streaming<SyncMessage>>,
+    ) -> Result<Response<Self::PushStream>, Status> {
+        let session = self
+            .session
+            .clone()
+            .try_lock_owned()
+            .map_err(|_| Status::already_exists("Main server is already pushing to node"))?;
+
+        let mut in_stream = request.into_inner();
+        let mut replica = Replica::load(self.app_state.clone(), None, self.status.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let url = self.app_state.config.base_url.clone();
+        let status = self.status.clone();
+        status.connected();
+        tracing::info!(
+            "Main server connected, replicating from seq {}",
+            replica.cursor()
+        );
+
+        let (tx, rx) = mpsc::channel(128);
+
+        tokio::spawn(async move {
+            let _session = session;
+            let res = async {
+                while let Some(message) = in_stream.message().await? {
+                    tracing::trace!("Applying {} change at seq {}", message.kind(), message.seq);
+                    replica.apply(message).await?;
+                    let ack = AckRequest {
+                        url: url.clone(),
+                        seq: replica.cursor(),
+                    };
+                    if tx.send(Ok(ack)).await.is_err() {
+                        break;
+                    }
+                }
+                anyhow::Ok(())
+            }
+            .await;
+
+            match res {
+                Ok(()) => {
+                    tracing::warn!("Main server closed the push stream");
+                    status.disconnected(None);
+                }
+                Err(e) => {
+                    tracing::warn!("Push from main server failed: {e}");
+                    status.disconnected(Some(e.to_string()));
+                    tx.send(Err(Status::internal(e.to_string()))).await.ok();
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Request, Response, Status, Streaming};
+
+use crate::{
+    constants::SEQ_METADATA_KEY,
+    grpc::{
+        qcdn_files_client::QcdnFilesClient, AbortUploadRequest, CompleteMultipartUploadRequest,
+        DeleteFileVersionRequest, GetUploadStatusRequest, TagVersionRequest, UploadMeta,
+        UploadPartRequest, UploadPartResponse, UploadRequest, UploadResponse, UploadSession,
+    },
+    sync::status::ReplicationStatus,
+};
+
+const UPLOAD_BUFFER: usize = 16;
+
+/// Main server writes are forwarded to, from nodes and from cluster followers
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    files: QcdnFilesClient<Channel>,
+    // wait for forwarded writes to be applied locally, None answers right away
+    read_your_writes: Option<(ReplicationStatus, Duration)>,
+}
+
+impl Upstream {
+    pub fn new(
+        files: QcdnFilesClient<Channel>,
+        read_your_writes: Option<(ReplicationStatus, Duration)>,
+    ) -> Self {
+        Self {
+            files,
+            read_your_writes,
+        }
+    }
+}
+
+impl Upstream {
+    pub async fn upload(
+        &self,
+        request: Request<Streaming<UploadRequest>>,
+    ) -> Result<Response<UploadResponse>, Status> {
+        let (tx, rx) = mpsc::channel(UPLOAD_BUFFER);
+        let mut files = self.files.clone();
+        let forward = files.upload(ReceiverStream::new(rx));
+        let (response, ()) = tokio::try_join!(forward, pump(request.into_inner(), tx))?;
+
+        self.applied(response).await
+    }
+
+    pub async fn create_multipart_upload(
+        &self,
+        request: UploadMeta,
+    ) -> Result<Response<UploadSession>, Status> {
+        let response = self.files.clone().create_multipart_upload(request).await?;
+        Ok(Response::new(response.into_inner()))
+    }
+
+    pub async fn upload_part(
+        &self,
+        request: Request<Streaming<UploadPartRequest>>,
+    ) -> Result<Response<UploadPartResponse>, Status> {
+        let (tx, rx) = mpsc::channel(UPLOAD_BUFFER);
+        let mut files = self.files.clone();
+        let forward = files.upload_part(ReceiverStream::new(rx));
+        let (response, ()) = tokio::try_join!(forward, pump(request.into_inner(), tx))?;
+
+        Ok(Response::new(response.into_inner()))
+    }
+
+    pub async fn complete_multipart_upload(
+        &self,
+        request: CompleteMultipartUploadRequest,
+    ) -> Result<Response<UploadResponse>, Status> {
+        let response = self
+            .files
+            .clone()
+            .complete_multipart_upload(request)
+            .await?;
+        self.applied(response).await
+    }
+
+    pub async fn abort_upload(&self, request: AbortUploadRequest) -> Result<Response<()>, Status> {
+        self.files.clone().abort_upload(request).await?;
+        Ok(Response::new(()))
+    }
+
+    // sessions change nothing replicas see until the upload completes
+    pub async fn start_upload(
+        &self,
+        request: UploadMeta,
+    ) -> Result<Response<UploadSession>, Status> {
+        let response = self.files.clone().start_upload(request).await?;
+        Ok(Response::new(response.into_inner()))
+    }
+
+    pub async fn get_upload_status(
+        &self,
+        request: GetUploadStatusRequest,
+    ) -> Result<Response<UploadSession>, Status> {
+        let response = self.files.clone().get_upload_status(request).await?;
+        Ok(Response::new(response.into_inner()))
+    }
+
+    pub async fn tag_version(&self, request: TagVersionRequest) -> Result<Response<()>, Status> {
+        let response = self.files.clone().tag_version(request).await?;
+        self.applied(response).await
+    }
+
+    pub async fn delete_file_version(
+        &self,
+        request: DeleteFileVersionRequest,
+    ) -> Result<Response<()>, Status> {
+        let response = self.files.clone().delete_file_version(request).await?;
+        self.applied(response).await
+    }
+
+    async fn applied<T>(&self, response: Response<T>) -> Result<Response<T>, Status> {
+        let seq = response.metadata().get(SEQ_METADATA_KEY).cloned();
+
+        if let Some((replication, timeout)) = &self.read_your_writes {
+            let applied_seq = seq
+                .as_ref()
+                .and_then(|seq| seq.to_str().ok())
+                .and_then(|seq| seq.parse().ok());
+            if let Some(applied_seq) = applied_seq {
+                if !replication.wait_applied(applied_seq, *timeout).await {
+                    return Err(Status::deadline_exceeded(format!(
+                        "Change is stored on main server but seq {applied_seq} is not applied on node yet"
+                    )));
+                }
+            }
+        }
+
+        let mut forwarded = Response::new(response.into_inner());
+        if let Some(seq) = seq {
+            forwarded.metadata_mut().insert(SEQ_METADATA_KEY, seq);
+        }
+        Ok(forwarded)
+    }
+}
+
+// a broken client stream drops the forwarded call, so main server never commits it
+async fn pump<T>(mut in_stream: Streaming<T>, tx: mpsc::Sender<T>) -> Result<(), Status> {
+    while let Some(message) = in_stream.message().await? {
+        if tx.send(message).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -6,12 +6,16 @@ pub use crate::database::{DatabaseConnection, DatabasePoolConnection};
 pub use crate::storage::Storage;
 
 pub mod app_state;
+pub mod cache;
 pub mod config;
 pub mod constants;
 pub mod database;
 pub mod entities;
+pub mod geo;
 pub mod grpc;
+pub mod manager;
 pub mod storage;
+pub mod sync;
 pub mod web;
 
 pub fn setup_tracing_subscriber(log_level: LevelFilter) {
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use tonic::transport::Server;
+
+use crate::{
+    config::CliConfig,
+    database::nodes::records::node_record::NodeRecord,
+    entities::files::blobs,
+    grpc::{
+        qcdn_cluster_server::QcdnClusterServer,
+        qcdn_files_server::QcdnFilesServer,
+        qcdn_general_server::QcdnGeneralServer,
+        qcdn_nodes_server::QcdnNodesServer,
+        server::{
+            cluster::ClusterService, files::FilesService, general::GeneralService,
+            nodes::NodesService,
+        },
+    },
+    sync::{cluster::Cluster, hub::SyncHub, push},
+    AppState,
+};
+
+/// Serves main server gRPC api until it fails, instances sharing a process
+/// only need their own port, db and storage
+pub async fn run(config: &CliConfig) -> Result<()> {
+    let addr = format!("{}:{}", config.host, config.port).parse()?;
+    let app_state = AppState::from_config(config).await?.shared();
+    NodeRecord::disconnect_all(&mut *app_state.db.connect().await?).await?;
+    // blob lock only guards this process, web servers sharing the storage stay away from it
+    let adopted = blobs::adopt_version_files(&app_state.db, &app_state.storage).await?;
+    if adopted > 0 {
+        tracing::info!("Moved {adopted} stored versions into blob store");
+    }
+    let sync = SyncHub::new(config.sync_buffer);
+
+    let cluster = Cluster::from_config(config, app_state.db.clone()).await?;
+    if let Some(cluster) = cluster.clone() {
+        tokio::spawn(cluster.run(app_state.clone()));
+    }
+
+    for endpoint in config.push_nodes.iter().cloned() {
+        tokio::spawn(push::run(
+            app_state.clone(),
+            sync.clone(),
+            cluster.clone(),
+            endpoint,
+        ));
+    }
+
+    let files = FilesService::new(app_state.clone(), sync.clone()).in_cluster(cluster.clone());
+    tokio::spawn(files.clone().expire_upload_sessions());
+
+    let general = QcdnGeneralServer::new(GeneralService::default());
+    let file = QcdnFilesServer::new(files);
+    let node = QcdnNodesServer::new(
+        NodesService::new(app_state.clone(), sync.clone()).in_cluster(cluster.clone()),
+    );
+    let cluster = cluster
+        .map(|cluster| QcdnClusterServer::new(ClusterService::new(app_state, sync, cluster)));
+
+    tracing::info!("Serving gRPC on: {addr}");
+    Server::builder()
+        .add_service(general)
+        .add_service(file)
+        .add_service(node)
+        .add_optional_service(cluster)
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
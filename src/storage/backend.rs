@@ -0,0 +1,126 @@
+use std::{fmt::Debug, ops::Range, path::Path, time::SystemTime};
+
+use anyhow::Result;
+use axum::async_trait;
+use tokio::{
+    fs,
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Keeps finished version bytes, keys are `/` separated paths like `<dir>/<filename>`
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+    /// Reader over `range` of stored bytes, whole object without it
+    async fn open(&self, key: &str, range: Option<Range<u64>>) -> Result<Reader>;
+
+    /// Writer of a new object, complete once it is shut down
+    async fn create(&self, key: &str) -> Result<Writer>;
+
+    async fn remove(&self, key: &str) -> Result<()>;
+
+    /// None when nothing is stored under the key
+    async fn stat(&self, key: &str) -> Result<Option<Stat>>;
+
+    /// Keys stored under `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Moves finished local file under the key, replacing what was stored there
+    async fn store(&self, key: &str, path: &Path) -> Result<()> {
+        let mut file = fs::File::open(path).await?;
+        let mut writer = self.create(key).await?;
+        io::copy(&mut file, &mut writer).await?;
+        writer.shutdown().await?;
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use object_store::memory::InMemory;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::storage::{local::LocalBackend, s3::S3Backend};
+
+    async fn put(backend: &dyn StorageBackend, key: &str, bytes: &[u8]) {
+        let mut writer = backend.create(key).await.unwrap();
+        writer.write_all(bytes).await.unwrap();
+        writer.shutdown().await.unwrap();
+    }
+
+    async fn read(backend: &dyn StorageBackend, key: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut reader = backend.open(key, range).await.unwrap();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    async fn list(backend: &dyn StorageBackend, prefix: &str) -> Vec<String> {
+        let mut keys = backend.list(prefix).await.unwrap();
+        keys.sort();
+        keys
+    }
+
+    /// Same checks for every backend, `scratch` holds local files to store
+    async fn check(backend: &dyn StorageBackend, scratch: &Path) {
+        put(backend, "dir/a", b"hello world").await;
+        put(backend, "dir/nested/b", b"nested").await;
+        put(backend, "other/c", b"other").await;
+
+        let stat = backend.stat("dir/a").await.unwrap().unwrap();
+        assert_eq!(stat.size, 11);
+        assert!(backend.stat("dir/missing").await.unwrap().is_none());
+
+        assert_eq!(read(backend, "dir/a", None).await, b"hello world");
+        assert_eq!(read(backend, "dir/a", Some(0..5)).await, b"hello");
+        assert_eq!(read(backend, "dir/a", Some(6..11)).await, b"world");
+        assert_eq!(read(backend, "dir/a", Some(3..3)).await, b"");
+        assert!(backend.open("dir/missing", None).await.is_err());
+
+        assert_eq!(list(backend, "dir").await, ["dir/a", "dir/nested/b"]);
+        assert_eq!(list(backend, "dir/nested").await, ["dir/nested/b"]);
+        assert!(list(backend, "missing").await.is_empty());
+
+        let path = scratch.join("stored");
+        fs::write(&path, b"replaced").await.unwrap();
+        backend.store("dir/a", &path).await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(read(backend, "dir/a", None).await, b"replaced");
+
+        backend.remove("dir/a").await.unwrap();
+        assert!(backend.stat("dir/a").await.unwrap().is_none());
+        assert_eq!(list(backend, "dir").await, ["dir/nested/b"]);
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qcdn-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn local_backend() {
+        let root = scratch("local-backend");
+        check(&LocalBackend::new(&root.join("storage")), &root).await;
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn object_store_backend() {
+        let root = scratch("object-store-backend");
+        check(&S3Backend::new(Arc::new(InMemory::new())), &root).await;
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use axum::async_trait;
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
+
+use super::backend::{Reader, Stat, StorageBackend, Writer};
+
+/// Stores objects as files under `root`
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    async fn create_parent(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn open(&self, key: &str, range: Option<Range<u64>>) -> Result<Reader> {
+        let mut file = fs::File::open(self.root.join(key)).await?;
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(Box::new(file.take(range.end - range.start)))
+            }
+            None => Ok(Box::new(file)),
+        }
+    }
+
+    async fn create(&self, key: &str) -> Result<Writer> {
+        let path = self.root.join(key);
+        self.create_parent(&path).await?;
+        Ok(Box::new(fs::File::create(path).await?))
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        Ok(fs::remove_file(self.root.join(key)).await?)
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        match fs::metadata(self.root.join(key)).await {
+            Ok(metadata) => Ok(Some(Stat {
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut dirs = vec![prefix.trim_end_matches('/').to_string()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(self.root.join(&dir)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let key = match dir.as_str() {
+                    "" => name,
+                    dir => format!("{dir}/{name}"),
+                };
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(key);
+                } else {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn store(&self, key: &str, path: &Path) -> Result<()> {
+        let to = self.root.join(key);
+        self.create_parent(&to).await?;
+        Ok(fs::rename(path, to).await?)
+    }
+}
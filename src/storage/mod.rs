@@ -0,0 +1,220 @@
+use std::{fmt::Debug, ops::Range, path::PathBuf, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use tokio::{
+    fs,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{
+    config::{CliConfig, StorageBackendKind},
+    AppState,
+};
+
+pub use backend::{Reader, Stat, StorageBackend, Writer};
+
+pub mod backend;
+pub mod local;
+pub mod s3;
+
+const BLOBS_DIR: &str = "blobs";
+
+/// Content addressed location of bytes, fanned out by the first two hex digits
+pub fn blob_location(sha256: &str) -> (String, String) {
+    let fan_out = sha256.get(..2).unwrap_or(sha256);
+    (format!("{BLOBS_DIR}/{fan_out}"), sha256.to_string())
+}
+
+/// Where bytes of a ready version are kept, versions uploaded before hashing stay
+/// at `<dir_id>/<file_version_id>`
+pub fn version_location(
+    dir_id: &str,
+    file_version_id: &str,
+    sha256: Option<&str>,
+) -> (String, String) {
+    match sha256 {
+        Some(sha256) => blob_location(sha256),
+        None => (dir_id.to_string(), file_version_id.to_string()),
+    }
+}
+
+/// Files are working copies under the local storage dir: uploads in progress, parts, downloads.
+/// Once finished they are stored in the backend and read back from it
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: Arc<PathBuf>,
+    backend: Arc<dyn StorageBackend>,
+    // blobs are stored and removed together with their reference count changes
+    blobs: Arc<Mutex<()>>,
+}
+
+impl Storage {
+    pub async fn open_file(&self, dir: &str, filename: &str) -> Result<fs::File, anyhow::Error> {
+        let path = self.root.join(dir).join(filename);
+
+        Ok(fs::File::open(path).await?)
+    }
+
+    /// Opens existing file for reading and writing without truncating it
+    pub async fn open_file_for_write(
+        &self,
+        dir: &str,
+        filename: &str,
+    ) -> Result<fs::File, anyhow::Error> {
+        let path = self.root.join(dir).join(filename);
+
+        Ok(fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .await?)
+    }
+
+    pub async fn create_file(&self, dir: &str, filename: &str) -> Result<fs::File, anyhow::Error> {
+        let dir_path = self.root.join(dir);
+        if fs::read_dir(&dir_path).await.is_err() {
+            fs::create_dir_all(&dir_path).await?;
+        }
+        Ok(fs::File::create(dir_path.join(filename)).await?)
+    }
+
+    pub async fn rename_file(&self, dir: &str, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let dir_path = self.root.join(dir);
+        Ok(fs::rename(dir_path.join(from), dir_path.join(to)).await?)
+    }
+
+    pub async fn remove_file(&self, dir: &str, filename: &str) -> Result<(), anyhow::Error> {
+        let dir_path = self.root.join(dir);
+        Ok(fs::remove_file(dir_path.join(filename)).await?)
+    }
+
+    pub async fn exists(&self, dir: &str, filename: &str) -> bool {
+        fs::try_exists(self.root.join(dir).join(filename))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+impl Storage {
+    /// Reader over `range` of stored bytes, whole file without it
+    pub async fn read(
+        &self,
+        dir: &str,
+        filename: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Reader, anyhow::Error> {
+        self.backend.open(&key(dir, filename), range).await
+    }
+
+    pub async fn stat(&self, dir: &str, filename: &str) -> Result<Option<Stat>, anyhow::Error> {
+        self.backend.stat(&key(dir, filename)).await
+    }
+
+    /// Moves finished local file into the backend, replacing what was stored there
+    pub async fn store(
+        &self,
+        from_dir: &str,
+        from: &str,
+        to_dir: &str,
+        to: &str,
+    ) -> Result<(), anyhow::Error> {
+        let path = self.root.join(from_dir).join(from);
+        self.backend.store(&key(to_dir, to), &path).await
+    }
+
+    pub async fn remove_stored(&self, dir: &str, filename: &str) -> Result<(), anyhow::Error> {
+        self.backend.remove(&key(dir, filename)).await
+    }
+}
+
+impl Storage {
+    /// Held while a blob is stored or removed along with its reference count
+    pub async fn lock_blobs(&self) -> MutexGuard<'_, ()> {
+        self.blobs.lock().await
+    }
+
+    pub async fn has_blob(&self, sha256: &str) -> bool {
+        let (dir, filename) = blob_location(sha256);
+        matches!(self.stat(&dir, &filename).await, Ok(Some(_)))
+    }
+
+    /// Moves finished local file into the blob store, the file is dropped when the blob is
+    /// stored already, true when the blob is new
+    pub async fn store_blob(
+        &self,
+        dir: &str,
+        filename: &str,
+        sha256: &str,
+    ) -> Result<bool, anyhow::Error> {
+        if self.has_blob(sha256).await {
+            self.remove_file(dir, filename).await?;
+            return Ok(false);
+        }
+        let (blob_dir, blob) = blob_location(sha256);
+        self.store(dir, filename, &blob_dir, &blob).await?;
+        Ok(true)
+    }
+
+    pub async fn remove_blob(&self, sha256: &str) -> Result<(), anyhow::Error> {
+        let (dir, filename) = blob_location(sha256);
+        self.remove_stored(&dir, &filename).await
+    }
+}
+
+impl Storage {
+    pub async fn from_config(config: &CliConfig) -> Result<Self, anyhow::Error> {
+        let value = &config.storage_dir;
+        tracing::debug!("Checking if {:?} exists", value);
+        if !value.exists() {
+            tracing::debug!("Creating {:?}", value);
+            fs::create_dir(&value)
+                .await
+                .map_err(|e| anyhow::anyhow!("{:?}: {}", value, e))?;
+        }
+        if !value.is_dir() {
+            return Err(anyhow::anyhow!("{:?} is not a directory", value));
+        }
+
+        let backend: Arc<dyn StorageBackend> = match config.storage_backend {
+            StorageBackendKind::Fs => Arc::new(local::LocalBackend::new(value)),
+            StorageBackendKind::S3 => Arc::new(s3::S3Backend::from_config(config)?),
+        };
+        let storage = Self {
+            root: Arc::new(value.to_path_buf()),
+            backend,
+            blobs: Default::default(),
+        };
+        tracing::debug!("{:?} created", storage);
+        Ok(storage)
+    }
+}
+
+fn key(dir: &str, filename: &str) -> String {
+    format!("{dir}/{filename}")
+}
+
+impl FromRef<AppState> for Storage {
+    fn from_ref(app_state: &AppState) -> Storage {
+        app_state.storage.clone()
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Storage
+where
+    Storage: FromRef<AppState>,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let storage = Storage::from_ref(state);
+        Ok(storage)
+    }
+}
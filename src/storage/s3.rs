@@ -0,0 +1,97 @@
+use std::{io, ops::Range, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use object_store::{
+    aws::AmazonS3Builder, buffered::BufWriter, path::Path, GetOptions, GetRange, ObjectStore,
+};
+use tokio_stream::StreamExt;
+use tokio_util::io::StreamReader;
+
+use crate::config::CliConfig;
+
+use super::backend::{Reader, Stat, StorageBackend, Writer};
+
+/// Stores objects in a bucket of S3 compatible object store
+#[derive(Debug, Clone)]
+pub struct S3Backend {
+    store: Arc<dyn ObjectStore>,
+}
+
+impl S3Backend {
+    /// Credentials and settings missing in config are read from `AWS_*` environment variables
+    pub fn from_config(config: &CliConfig) -> Result<Self> {
+        let bucket = config
+            .s3_bucket
+            .as_deref()
+            .ok_or_else(|| anyhow!("s3_bucket is required for s3 storage backend"))?;
+
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Some(endpoint) = &config.s3_endpoint {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+        if let Some(region) = &config.s3_region {
+            builder = builder.with_region(region);
+        }
+
+        Ok(Self::new(Arc::new(builder.build()?)))
+    }
+
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn open(&self, key: &str, range: Option<Range<u64>>) -> Result<Reader> {
+        // object stores refuse empty ranges
+        if range.as_ref().is_some_and(|range| range.is_empty()) {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let options = GetOptions {
+            range: range.map(|range| GetRange::Bounded(range.start as usize..range.end as usize)),
+            ..Default::default()
+        };
+        let stream = self
+            .store
+            .get_opts(&Path::from(key), options)
+            .await?
+            .into_stream()
+            .map(|bytes| bytes.map_err(io::Error::other));
+        Ok(Box::new(StreamReader::new(stream)))
+    }
+
+    async fn create(&self, key: &str) -> Result<Writer> {
+        Ok(Box::new(BufWriter::new(
+            self.store.clone(),
+            Path::from(key),
+        )))
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        Ok(self.store.delete(&Path::from(key)).await?)
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Stat>> {
+        match self.store.head(&Path::from(key)).await {
+            Ok(meta) => Ok(Some(Stat {
+                size: meta.size as u64,
+                modified: meta.last_modified.into(),
+            })),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut stream = self.store.list(Some(&Path::from(prefix)));
+        let mut keys = vec![];
+        while let Some(meta) = stream.next().await {
+            keys.push(meta?.location.to_string());
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,11 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential delay for the given attempt (starting at 1), capped at `max`
+/// and randomized into its upper half so nodes don't reconnect in lockstep
+pub fn jittered(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let exp = base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+    let cap = exp.min(max).as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(cap / 2..=cap))
+}
@@ -0,0 +1,740 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use tokio::{
+    sync::{watch, Mutex, Notify},
+    task::JoinSet,
+    time::Instant,
+};
+use tonic::{
+    metadata::MetadataValue,
+    transport::{Channel, Endpoint},
+    Code, Status,
+};
+
+use crate::{
+    config::CliConfig,
+    constants::LEADER_METADATA_KEY,
+    database::{
+        files::records::change_log_record::ChangeLogRecord,
+        nodes::records::cluster_state_record::ClusterStateRecord, Database,
+    },
+    grpc::{
+        qcdn_cluster_client::QcdnClusterClient, qcdn_files_client::QcdnFilesClient, ClusterRole,
+        ClusterStatusResponse, FollowRequest, HeartbeatRequest, HeartbeatResponse, VoteRequest,
+        VoteResponse,
+    },
+    AppState,
+};
+
+use super::{replica::Replica, status::ReplicationStatus};
+
+/// Role of a member and the leader it knows of in a term
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leadership {
+    pub role: ClusterRole,
+    pub term: u64,
+    pub leader_url: Option<String>,
+}
+
+#[derive(Debug)]
+struct Election {
+    voted_for: Option<String>,
+    // last heartbeat from leader or vote granted, the election timeout counts from it,
+    // a leader keeps here when most members last answered its heartbeats
+    heard_at: Instant,
+    // latest seq of every follower, reported with heartbeats
+    matched: HashMap<String, u64>,
+}
+
+/// Membership of a main server in a cluster electing the one that accepts writes,
+/// followers mirror the leader change log and a write is committed once most members have it
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    url: String,
+    peers: Vec<(String, QcdnClusterClient<Channel>)>,
+    election_timeout: Duration,
+    db: Database,
+    // term and vote changes are persisted under this lock
+    election: Arc<Mutex<Election>>,
+    leadership: Arc<watch::Sender<Leadership>>,
+    committed: Arc<watch::Sender<u64>>,
+    heartbeat_now: Arc<Notify>,
+}
+
+impl Cluster {
+    /// None unless cluster peers are configured
+    pub async fn from_config(config: &CliConfig, db: Database) -> Result<Option<Self>> {
+        if config.cluster_peers.is_empty() {
+            return Ok(None);
+        }
+
+        let election_timeout = Duration::from_millis(config.election_timeout);
+        let peers = config
+            .cluster_peers
+            .iter()
+            .map(|url| {
+                let channel = Endpoint::from_shared(url.clone())?
+                    .connect_timeout(election_timeout / 2)
+                    .timeout(election_timeout / 2)
+                    .connect_lazy();
+                Ok((url.clone(), QcdnClusterClient::new(channel)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut connection = db.connect().await?;
+        let (term, voted_for, commit_seq) = ClusterStateRecord::find(&mut connection)
+            .await?
+            .map(|state| (state.term, state.voted_for, state.commit_seq))
+            .unwrap_or_default();
+        drop(connection);
+
+        Ok(Some(Self {
+            url: config.cluster_url(),
+            peers,
+            election_timeout,
+            db,
+            election: Arc::new(Mutex::new(Election {
+                voted_for,
+                heard_at: Instant::now(),
+                matched: HashMap::new(),
+            })),
+            leadership: Arc::new(
+                watch::channel(Leadership {
+                    role: ClusterRole::Follower,
+                    term,
+                    leader_url: None,
+                })
+                .0,
+            ),
+            // the log of a restarted member may hold changes that were never committed,
+            // only the persisted commit seq is trusted
+            committed: Arc::new(watch::channel(commit_seq).0),
+            heartbeat_now: Default::default(),
+        }))
+    }
+}
+
+impl Cluster {
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.borrow().clone()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leadership.borrow().role == ClusterRole::Leader
+    }
+
+    /// Files api of the leader, None while there is no leader or this member leads
+    pub fn leader_files(&self) -> Option<QcdnFilesClient<Channel>> {
+        let leadership = self.leadership();
+        if leadership.role == ClusterRole::Leader {
+            return None;
+        }
+        let channel = Endpoint::from_shared(leadership.leader_url?)
+            .ok()?
+            .connect_lazy();
+        Some(QcdnFilesClient::new(channel))
+    }
+
+    /// Answer of a member that only the leader may serve, points to the leader when known
+    pub fn not_leader(&self) -> Status {
+        let leader_url = self.leadership().leader_url;
+        let mut status = Status::unavailable(match &leader_url {
+            Some(url) => format!("Main server is not the leader, leader is {url}"),
+            None => "No main server leader is elected".to_string(),
+        });
+        if let Some(url) = leader_url.and_then(|url| MetadataValue::try_from(url).ok()) {
+            status.metadata_mut().insert(LEADER_METADATA_KEY, url);
+        }
+        status
+    }
+
+    /// Waits until this member leads, returns the term
+    pub async fn leading(&self) -> u64 {
+        let mut leadership = self.leadership.subscribe();
+        let leading = leadership
+            .wait_for(|l| l.role == ClusterRole::Leader)
+            .await
+            .map(|l| l.term);
+        // the sender lives as long as the cluster
+        leading.unwrap_or_default()
+    }
+
+    /// Resolves once this member stops leading in `term`
+    pub async fn demoted(&self, term: u64) {
+        let mut leadership = self.leadership.subscribe();
+        leadership
+            .wait_for(|l| l.role != ClusterRole::Leader || l.term != term)
+            .await
+            .ok();
+    }
+
+    /// Seq most members hold every change up to, moves forward only
+    pub fn committed(&self) -> watch::Receiver<u64> {
+        self.committed.subscribe()
+    }
+
+    /// Waits until most members hold every change up to `seq`,
+    /// false on timeout or when leadership is lost in the meantime
+    pub async fn wait_committed(&self, seq: u64, timeout: Duration) -> bool {
+        let mut committed = self.committed.subscribe();
+        let term = self.leadership().term;
+        self.heartbeat_now.notify_one();
+
+        let res = tokio::time::timeout(timeout, async {
+            tokio::select! {
+                res = committed.wait_for(|committed| *committed >= seq) => res.is_ok(),
+                _ = self.demoted(term) => false,
+            }
+        })
+        .await;
+        matches!(res, Ok(true))
+    }
+
+    /// Gives up leading in `term` once most members stopped answering for an election timeout,
+    /// the next election decides whether changes they lack stay: a new leader without them
+    /// makes this member take them back
+    async fn step_down(&self, term: u64) {
+        let mut election = self.election.lock().await;
+        let stepped_down = self.leadership.send_if_modified(|leadership| {
+            if leadership.role != ClusterRole::Leader || leadership.term != term {
+                return false;
+            }
+            leadership.role = ClusterRole::Follower;
+            leadership.leader_url = None;
+            true
+        });
+        if stepped_down {
+            election.heard_at = Instant::now();
+            tracing::warn!("Stepped down as leader in term {term}");
+        }
+    }
+
+    pub async fn status(&self) -> Result<ClusterStatusResponse> {
+        let leadership = self.leadership();
+        Ok(ClusterStatusResponse {
+            url: self.url.clone(),
+            role: leadership.role.into(),
+            term: leadership.term,
+            leader_url: leadership.leader_url,
+            seq: self.last_seq().await?,
+            commit_seq: *self.committed.borrow(),
+            peers: self.peers.iter().map(|(url, _)| url.clone()).collect(),
+        })
+    }
+
+    fn majority(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    async fn last_seq(&self) -> Result<u64> {
+        let mut connection = self.db.connect().await?;
+        ChangeLogRecord::latest_seq(&mut connection).await
+    }
+
+    /// Seq and term of the last change in the log
+    async fn last_change(&self) -> Result<(u64, u64)> {
+        let mut connection = self.db.connect().await?;
+        ChangeLogRecord::last(&mut connection).await
+    }
+
+    /// Whether this log has the change at `seq` from the same term,
+    /// logs agreeing on it hold the same changes up to it
+    async fn holds(&self, seq: u64, term: u64) -> Result<bool> {
+        let mut connection = self.db.connect().await?;
+        Ok(ChangeLogRecord::term_at(&mut connection, seq).await? == Some(term))
+    }
+
+    async fn save(&self, term: u64, voted_for: Option<&str>) -> Result<()> {
+        let mut connection = self.db.connect().await?;
+        ClusterStateRecord::save(&mut connection, term, voted_for, None).await?;
+        Ok(())
+    }
+
+    /// Any newer term seen turns this member into a follower of an unknown leader
+    async fn observe(&self, election: &mut Election, term: u64) -> Result<()> {
+        if term <= self.leadership.borrow().term {
+            return Ok(());
+        }
+        self.save(term, None).await?;
+        election.voted_for = None;
+        self.leadership.send_replace(Leadership {
+            role: ClusterRole::Follower,
+            term,
+            leader_url: None,
+        });
+        Ok(())
+    }
+}
+
+impl Cluster {
+    /// Grants a single vote per term, only to candidates with a log not behind this one:
+    /// last change from a later term, or from the same term at the same seq or further
+    pub async fn vote(&self, request: VoteRequest) -> Result<VoteResponse> {
+        let mut election = self.election.lock().await;
+        self.observe(&mut election, request.term).await?;
+
+        let term = self.leadership.borrow().term;
+        if request.term < term {
+            return Ok(VoteResponse {
+                term,
+                granted: false,
+            });
+        }
+
+        let (last_seq, last_term) = self.last_change().await?;
+        let granted = election
+            .voted_for
+            .as_ref()
+            .is_none_or(|url| *url == request.candidate_url)
+            && (request.last_term, request.last_seq) >= (last_term, last_seq);
+        if granted {
+            self.save(term, Some(&request.candidate_url)).await?;
+            election.voted_for = Some(request.candidate_url.clone());
+            election.heard_at = Instant::now();
+            tracing::info!("Voted for {} in term {term}", request.candidate_url);
+        }
+
+        Ok(VoteResponse { term, granted })
+    }
+
+    pub async fn heartbeat(&self, request: HeartbeatRequest) -> Result<HeartbeatResponse> {
+        let mut election = self.election.lock().await;
+        self.observe(&mut election, request.term).await?;
+
+        let term = self.leadership.borrow().term;
+        let (seq, last_term) = self.last_change().await?;
+        if request.term < term {
+            return Ok(HeartbeatResponse {
+                term,
+                accepted: false,
+                seq,
+                last_term,
+            });
+        }
+
+        election.heard_at = Instant::now();
+        let following = Leadership {
+            role: ClusterRole::Follower,
+            term,
+            leader_url: Some(request.leader_url),
+        };
+        self.leadership.send_if_modified(|leadership| {
+            if *leadership == following {
+                return false;
+            }
+            tracing::info!("Following leader {:?} in term {term}", following.leader_url);
+            *leadership = following;
+            true
+        });
+        self.commit(request.commit_seq).await?;
+
+        Ok(HeartbeatResponse {
+            term,
+            accepted: true,
+            seq,
+            last_term,
+        })
+    }
+
+    /// Keeps leading with heartbeats or stands for election once the leader goes silent,
+    /// followers mirror the leader alongside
+    pub async fn run(self, app_state: Arc<AppState>) {
+        tokio::spawn(self.clone().follow(app_state));
+
+        let mut leadership = self.leadership.subscribe();
+        loop {
+            let current = leadership.borrow_and_update().clone();
+            let res = if current.role == ClusterRole::Leader {
+                let res = self.heartbeats(current.term).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(self.election_timeout / 4) => {}
+                    _ = self.heartbeat_now.notified() => {}
+                    _ = leadership.changed() => {}
+                }
+                res
+            } else {
+                self.await_leader().await
+            };
+            if let Err(e) = res {
+                tracing::warn!("Cluster membership of {} failed: {e}", self.url);
+            }
+        }
+    }
+
+    async fn await_leader(&self) -> Result<()> {
+        // randomized, so members rarely stand for election at the same time
+        let timeout = self.election_timeout
+            + Duration::from_millis(
+                rand::thread_rng().gen_range(0..=self.election_timeout.as_millis() as u64),
+            );
+        let heard_at = self.election.lock().await.heard_at;
+        if heard_at.elapsed() < timeout {
+            tokio::time::sleep_until(heard_at + timeout).await;
+            return Ok(());
+        }
+        self.campaign().await
+    }
+
+    async fn campaign(&self) -> Result<()> {
+        let (term, (last_seq, last_term)) = {
+            let mut election = self.election.lock().await;
+            let term = self.leadership.borrow().term + 1;
+            self.save(term, Some(&self.url)).await?;
+            election.voted_for = Some(self.url.clone());
+            election.heard_at = Instant::now();
+            self.leadership.send_replace(Leadership {
+                role: ClusterRole::Candidate,
+                term,
+                leader_url: None,
+            });
+            (term, self.last_change().await?)
+        };
+        tracing::info!("Standing for election in term {term} at seq {last_seq}");
+
+        let mut requests = JoinSet::new();
+        for (url, client) in &self.peers {
+            let url = url.clone();
+            let mut client = client.clone();
+            let request = VoteRequest {
+                term,
+                candidate_url: self.url.clone(),
+                last_seq,
+                last_term,
+            };
+            requests.spawn(async move { (url, client.request_vote(request).await) });
+        }
+
+        let mut votes = 1;
+        while let Some(Ok((url, res))) = requests.join_next().await {
+            match res {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if response.term > term {
+                        let mut election = self.election.lock().await;
+                        return self.observe(&mut election, response.term).await;
+                    }
+                    if response.granted {
+                        votes += 1;
+                    }
+                }
+                Err(e) => tracing::debug!("Vote request to {url} failed: {e}"),
+            }
+            if votes >= self.majority() {
+                break;
+            }
+        }
+
+        let mut election = self.election.lock().await;
+        let current = self.leadership();
+        if votes >= self.majority()
+            && current.role == ClusterRole::Candidate
+            && current.term == term
+        {
+            election.matched.clear();
+            self.leadership.send_replace(Leadership {
+                role: ClusterRole::Leader,
+                term,
+                leader_url: Some(self.url.clone()),
+            });
+            tracing::info!("Elected leader in term {term} with {votes} votes");
+        }
+
+        Ok(())
+    }
+
+    async fn heartbeats(&self, term: u64) -> Result<()> {
+        let mut requests = JoinSet::new();
+        for (url, client) in &self.peers {
+            let url = url.clone();
+            let mut client = client.clone();
+            let request = HeartbeatRequest {
+                term,
+                leader_url: self.url.clone(),
+                commit_seq: *self.committed.borrow(),
+            };
+            requests.spawn(async move { (url, client.heartbeat(request).await) });
+        }
+
+        let mut answered = 1;
+        while let Some(Ok((url, res))) = requests.join_next().await {
+            match res {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if response.term > term {
+                        let mut election = self.election.lock().await;
+                        return self.observe(&mut election, response.term).await;
+                    }
+                    if response.accepted {
+                        answered += 1;
+                    }
+                    // a follower tail the leader does not have is not replicated
+                    if response.accepted && self.holds(response.seq, response.last_term).await? {
+                        self.election.lock().await.matched.insert(url, response.seq);
+                        self.advance_commit(term).await?;
+                    }
+                }
+                Err(e) => tracing::debug!("Heartbeat to {url} failed: {e}"),
+            }
+        }
+
+        // a slow write alone keeps the leader, losing most members for a whole
+        // election timeout does not
+        let mut election = self.election.lock().await;
+        if answered >= self.majority() {
+            election.heard_at = Instant::now();
+        } else if election.heard_at.elapsed() >= self.election_timeout {
+            drop(election);
+            self.step_down(term).await;
+        }
+
+        Ok(())
+    }
+
+    /// Counts the seq most members hold as committed, only once it is a change of the leader
+    /// term: a change of an earlier term held by most members may still be replaced by
+    /// a leader that never got it, it is committed along with the first change of this term
+    async fn advance_commit(&self, term: u64) -> Result<()> {
+        let mut seqs = vec![self.last_seq().await?];
+        {
+            let election = self.election.lock().await;
+            seqs.extend(
+                self.peers
+                    .iter()
+                    .map(|(url, _)| election.matched.get(url).copied().unwrap_or_default()),
+            );
+        }
+        seqs.sort_unstable_by(|a, b| b.cmp(a));
+        let commit_seq = seqs[self.majority() - 1];
+        if commit_seq <= *self.committed.borrow() {
+            return Ok(());
+        }
+
+        let mut connection = self.db.connect().await?;
+        if ChangeLogRecord::term_at(&mut connection, commit_seq).await? != Some(term) {
+            return Ok(());
+        }
+        drop(connection);
+        self.commit(commit_seq).await
+    }
+
+    /// Moves the commit seq forward and persists it
+    async fn commit(&self, seq: u64) -> Result<()> {
+        let modified = self.committed.send_if_modified(|committed| {
+            let modified = seq > *committed;
+            *committed = (*committed).max(seq);
+            modified
+        });
+        if modified {
+            let mut connection = self.db.connect().await?;
+            ClusterStateRecord::save_commit_seq(&mut connection, seq, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn follow(self, app_state: Arc<AppState>) {
+        let mut leadership = self.leadership.subscribe();
+        loop {
+            let current = leadership.borrow_and_update().clone();
+            let leader_url = current
+                .leader_url
+                .filter(|_| current.role == ClusterRole::Follower);
+            let Some(leader_url) = leader_url else {
+                if leadership.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            };
+
+            tokio::select! {
+                res = self.mirror(&app_state, &leader_url, current.term) => {
+                    match res {
+                        Ok(()) => tracing::warn!("Leader {leader_url} closed the follow stream"),
+                        Err(e) => tracing::warn!("Following leader {leader_url} failed: {e}"),
+                    }
+                    tokio::time::sleep(self.election_timeout / 4).await;
+                }
+                _ = leadership.changed() => {}
+            }
+        }
+    }
+
+    async fn mirror(&self, app_state: &Arc<AppState>, leader_url: &str, term: u64) -> Result<()> {
+        let channel = Endpoint::from_shared(leader_url.to_string())?
+            .connect()
+            .await?;
+        let files = QcdnFilesClient::new(channel.clone());
+        let mut leader = QcdnClusterClient::new(channel);
+
+        let mut replica =
+            Replica::mirror(app_state.clone(), files, ReplicationStatus::new(0)).await?;
+        // changes past the last one both logs share never reached most members,
+        // they are taken back one by one until the leader recognizes the last one
+        let mut stream = loop {
+            let mut connection = self.db.connect().await?;
+            let last_term = ChangeLogRecord::term_at(&mut connection, replica.cursor())
+                .await?
+                .unwrap_or_default();
+            drop(connection);
+
+            let request = FollowRequest {
+                term,
+                url: self.url.clone(),
+                seq: replica.cursor(),
+                last_term,
+            };
+            match leader.follow(request).await {
+                Ok(response) => break response.into_inner(),
+                Err(status)
+                    if status.code() == Code::FailedPrecondition && replica.cursor() > 0 =>
+                {
+                    tracing::debug!("Leader {leader_url} refused follow: {}", status.message());
+                    replica.undo_last().await?;
+                }
+                Err(status) => bail!(status),
+            }
+        };
+        tracing::info!(
+            "Mirroring leader {leader_url} from seq {}",
+            replica.cursor()
+        );
+
+        while let Some(message) = stream.message().await? {
+            tracing::trace!("Applying {} change at seq {}", message.kind(), message.seq);
+            replica.apply(message).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Leader url a cluster member answered with when turning a request away
+pub fn leader_hint(e: &anyhow::Error) -> Option<String> {
+    e.downcast_ref::<Status>()?
+        .metadata()
+        .get(LEADER_METADATA_KEY)?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use clap::Parser;
+
+    use super::*;
+    use crate::database::files::{
+        file_type::FileType,
+        records::{
+            change_log_record::{ChangeKind, LogPosition},
+            dir_record::DirRecord,
+            file_record::FileRecord,
+            file_version_record::{FileVersionRecord, FileVersionState},
+        },
+    };
+
+    const PEER: &str = "http://127.0.0.1:1";
+
+    async fn leader(db: &Database, term: u64) -> Cluster {
+        let config = CliConfig::try_parse_from([
+            "manager",
+            "--cluster-peers",
+            &format!("{PEER},http://127.0.0.1:2"),
+        ])
+        .unwrap();
+        let cluster = Cluster::from_config(&config, db.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        cluster.leadership.send_replace(Leadership {
+            role: ClusterRole::Leader,
+            term,
+            leader_url: Some(cluster.url.clone()),
+        });
+        cluster
+    }
+
+    async fn append(db: &Database, seq: u64, term: u64) {
+        let mut connection = db.connect().await.unwrap();
+        let dir = DirRecord::create(&mut connection, &format!("dir-{seq}"), None)
+            .await
+            .unwrap();
+        let file = FileRecord::create(&mut connection, &dir.id, "a.txt", FileType::Text, None)
+            .await
+            .unwrap();
+        let file_version = FileVersionRecord::create(
+            &mut connection,
+            &file.id,
+            "1",
+            1,
+            FileVersionState::Ready,
+            None,
+        )
+        .await
+        .unwrap();
+        ChangeLogRecord::append(
+            &mut connection,
+            LogPosition::at(seq, term),
+            ChangeKind::Uploaded,
+            &file_version.id,
+            None,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        // rows of an insert returning them arrive before the insert is done
+        assert_eq!(
+            ChangeLogRecord::latest_seq(&mut connection).await.unwrap(),
+            seq
+        );
+    }
+
+    async fn matched(cluster: &Cluster, seq: u64) {
+        let term = cluster.leadership().term;
+        cluster
+            .election
+            .lock()
+            .await
+            .matched
+            .insert(PEER.to_string(), seq);
+        cluster.advance_commit(term).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn earlier_term_commits_with_current_term() {
+        let db = Database::temporary("cluster-commit").await.unwrap();
+        append(&db, 1, 1).await;
+        append(&db, 2, 2).await;
+        let cluster = leader(&db, 3).await;
+
+        // a change of term 2 on most members can still be replaced by a leader without it
+        matched(&cluster, 1).await;
+        assert_eq!(*cluster.committed.borrow(), 0);
+        matched(&cluster, 2).await;
+        assert_eq!(*cluster.committed.borrow(), 0);
+
+        append(&db, 3, 3).await;
+        matched(&cluster, 3).await;
+        assert_eq!(*cluster.committed.borrow(), 3);
+
+        // persisted, a restarted member serves nodes up to it
+        let restarted = leader(&db, 3).await;
+        assert_eq!(*restarted.committed.borrow(), 3);
+    }
+
+    #[tokio::test]
+    async fn minority_does_not_commit() {
+        let db = Database::temporary("cluster-minority").await.unwrap();
+        let cluster = leader(&db, 1).await;
+        append(&db, 1, 1).await;
+
+        matched(&cluster, 0).await;
+        assert_eq!(*cluster.committed.borrow(), 0);
+
+        matched(&cluster, 1).await;
+        assert_eq!(*cluster.committed.borrow(), 1);
+    }
+}
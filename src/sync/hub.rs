@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+use uuid::Uuid;
+
+use crate::grpc::SyncMessage;
+
+#[derive(Debug, Clone)]
+pub struct SubscriberInfo {
+    pub id: Uuid,
+    pub url: String,
+    pub ip_addr: String,
+    pub connected_at: DateTime<Utc>,
+}
+
+type Subscribers = Arc<Mutex<HashMap<Uuid, SubscriberInfo>>>;
+
+#[derive(Debug, Clone)]
+pub struct SyncHub {
+    sender: broadcast::Sender<SyncMessage>,
+    subscribers: Subscribers,
+    // bumped whenever a node acknowledges changes or disconnects
+    replicas: Arc<watch::Sender<u64>>,
+}
+
+impl SyncHub {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        let (replicas, _) = watch::channel(0);
+        Self {
+            sender,
+            subscribers: Default::default(),
+            replicas: Arc::new(replicas),
+        }
+    }
+}
+
+impl SyncHub {
+    pub fn publish(&self, message: SyncMessage) {
+        // an error only means nobody is subscribed right now
+        if let Ok(receivers) = self.sender.send(message) {
+            tracing::debug!("Sync message published to {receivers} subscribers");
+        }
+    }
+
+    pub fn subscribe(&self, url: String, ip_addr: String) -> Subscription {
+        let info = SubscriberInfo {
+            id: Uuid::now_v7(),
+            url,
+            ip_addr,
+            connected_at: Utc::now(),
+        };
+        let receiver = self.sender.subscribe();
+
+        self.subscribers
+            .lock()
+            .expect("sync hub lock poisoned")
+            .insert(info.id, info.clone());
+        tracing::info!("Sync subscriber {:?} connected", info);
+
+        Subscription {
+            info,
+            receiver,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    pub fn replicas_changed(&self) {
+        self.replicas
+            .send_modify(|version| *version = version.wrapping_add(1));
+    }
+
+    pub fn watch_replicas(&self) -> watch::Receiver<u64> {
+        self.replicas.subscribe()
+    }
+
+    pub fn subscribers(&self) -> Vec<SubscriberInfo> {
+        let mut items: Vec<_> = self
+            .subscribers
+            .lock()
+            .expect("sync hub lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+        items.sort_by_key(|s| s.connected_at);
+        items
+    }
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    pub info: SubscriberInfo,
+    receiver: broadcast::Receiver<SyncMessage>,
+    subscribers: Subscribers,
+}
+
+impl Subscription {
+    // `RecvError::Lagged` means the subscriber overflowed its buffer and events were dropped,
+    // the dropped events have to be replayed from the change log
+    pub async fn recv(&mut self) -> Result<SyncMessage, RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.remove(&self.info.id);
+        }
+        tracing::info!("Sync subscriber {:?} disconnected", self.info);
+    }
+}
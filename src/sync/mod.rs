@@ -0,0 +1,8 @@
+pub mod backoff;
+pub mod cluster;
+pub mod hub;
+pub mod push;
+pub mod quorum;
+pub mod reconcile;
+pub mod replica;
+pub mod status;
@@ -0,0 +1,162 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::{
+    database::nodes::records::node_record::NodeRecord,
+    grpc::{
+        put_version_request, qcdn_replica_client::QcdnReplicaClient, server::nodes::stream_changes,
+        sync_message::MessageType, FilePart, PutVersionRequest, UploadedVersion,
+    },
+    storage, AppState,
+};
+
+use super::{backoff, cluster::Cluster, hub::SyncHub};
+
+const PUT_VERSION_BUFFER: usize = 16;
+
+/// Main server side of push replication, dials node and pushes changes it has not applied yet,
+/// in a cluster only the leader pushes
+pub async fn run(
+    app_state: Arc<AppState>,
+    sync: SyncHub,
+    cluster: Option<Cluster>,
+    endpoint: String,
+) {
+    let min_delay = Duration::from_millis(app_state.config.reconnect_min_delay);
+    let max_delay = Duration::from_millis(app_state.config.reconnect_max_delay);
+    let mut attempts = 0;
+
+    loop {
+        let res = match &cluster {
+            Some(cluster) => {
+                let term = cluster.leading().await;
+                tokio::select! {
+                    res = session(&app_state, &sync, Some(cluster.committed()), &endpoint, &mut attempts) => res,
+                    _ = cluster.demoted(term) => Ok(()),
+                }
+            }
+            None => session(&app_state, &sync, None, &endpoint, &mut attempts).await,
+        };
+        match res {
+            Ok(()) => tracing::warn!("Push stream to {endpoint} closed"),
+            Err(e) => tracing::warn!("Push to {endpoint} failed: {e}"),
+        }
+        attempts += 1;
+
+        let delay = backoff::jittered(attempts, min_delay, max_delay);
+        tracing::info!("Dialing {endpoint} again in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn session(
+    app_state: &AppState,
+    sync: &SyncHub,
+    committed: Option<watch::Receiver<u64>>,
+    endpoint: &str,
+    attempts: &mut u32,
+) -> Result<()> {
+    let channel = Endpoint::from_shared(endpoint.to_string())?
+        .connect()
+        .await?;
+    let mut replica = QcdnReplicaClient::new(channel);
+
+    let request = replica.handshake(()).await?.into_inner();
+    let db = app_state.db.clone();
+    let mut node = NodeRecord::connect(&mut *db.connect().await?, &request, None).await?;
+    *attempts = 0;
+    tracing::info!("Pushing to node {} from seq {}", request.url, request.seq);
+
+    // subscribe before replaying, so nothing published during the replay is missed
+    let subscription = sync.subscribe(request.url.clone(), request.ip_addr_v4.clone());
+    let (changes_tx, mut changes) = mpsc::channel(128);
+    let (push_tx, push_rx) = mpsc::channel(128);
+    let mut acks = replica
+        .clone()
+        .push(ReceiverStream::new(push_rx))
+        .await?
+        .into_inner();
+
+    let produce = stream_changes(&db, &changes_tx, subscription, request.seq, committed);
+    let forward = async {
+        while let Some(message) = changes.recv().await {
+            let message = message?;
+            // edge nodes pull bytes on request, the rest get them ahead of the change
+            // unless a later change deleted the version
+            if let Some(MessageType::Uploaded(uploaded)) = &message.message_type {
+                if !request.edge && !uploaded.deleted {
+                    put_version(app_state, &mut replica, uploaded).await?;
+                }
+            }
+            push_tx.send(message).await?;
+        }
+        anyhow::Ok(())
+    };
+    let ack = async {
+        while let Some(ack) = acks.message().await? {
+            let mut connection = db.connect().await?;
+            node.ack(&mut connection, ack.seq, None).await?;
+            sync.replicas_changed();
+        }
+        anyhow::Ok(())
+    };
+
+    let res = tokio::select! {
+        res = produce => res,
+        res = forward => res,
+        res = ack => res,
+    };
+
+    // the same node may be connected in pull mode in the meantime
+    if !sync.subscribers().iter().any(|s| s.url == node.url) {
+        node.disconnect(&mut *db.connect().await?, None).await?;
+        tracing::info!("Node {} disconnected", node.url);
+        sync.replicas_changed();
+    }
+
+    res
+}
+
+async fn put_version(
+    app_state: &AppState,
+    replica: &mut QcdnReplicaClient<Channel>,
+    uploaded: &UploadedVersion,
+) -> Result<()> {
+    let (dir, filename) = storage::version_location(
+        &uploaded.dir_id,
+        &uploaded.file_version_id,
+        uploaded.sha256.as_deref(),
+    );
+    let file = app_state.storage.read(&dir, &filename, None).await?;
+
+    let meta = PutVersionRequest {
+        request: Some(put_version_request::Request::Meta(uploaded.clone())),
+    };
+    // a read error ends the stream early, node rejects the short version
+    let (tx, rx) = mpsc::channel(PUT_VERSION_BUFFER);
+    tokio::spawn(async move {
+        let mut parts = ReaderStream::new(file);
+        while let Some(Ok(bytes)) = parts.next().await {
+            let part = PutVersionRequest {
+                request: Some(put_version_request::Request::Part(FilePart {
+                    bytes: bytes.into(),
+                })),
+            };
+            if tx.send(part).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    replica
+        .put_version(tokio_stream::once(meta).chain(ReceiverStream::new(rx)))
+        .await?;
+    tracing::debug!("Pushed bytes of {}", uploaded.file_version_id);
+
+    Ok(())
+}
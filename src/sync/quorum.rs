@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        files::search::file_version_replica_search::FileVersionReplicaSearch,
+        nodes::records::node_record::NodeRecord, Database,
+    },
+    grpc::{Consistency, UploadMeta},
+};
+
+use super::hub::SyncHub;
+
+/// Replicas a version has to reach before an upload is confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quorum {
+    Nodes(u32),
+    AllConnected,
+}
+
+impl Quorum {
+    pub fn from_meta(meta: &UploadMeta) -> Option<Self> {
+        match meta.consistency() {
+            Consistency::Async => None,
+            Consistency::Quorum => Some(Self::Nodes(meta.min_replicas.max(1))),
+            Consistency::All => Some(Self::AllConnected),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QuorumNotReached {
+    pub held: u32,
+    pub required: u32,
+}
+
+impl std::fmt::Display for QuorumNotReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "replicated to {} of {} required nodes",
+            self.held, self.required
+        )
+    }
+}
+
+impl std::error::Error for QuorumNotReached {}
+
+async fn count(db: &Database, file_version_id: &Uuid, quorum: Quorum) -> Result<(u32, u32)> {
+    let mut connection = db.connect().await?;
+    let held = NodeRecord::get_holding(&mut connection, file_version_id)
+        .await?
+        .len() as u32;
+    let required = match quorum {
+        Quorum::Nodes(n) => n,
+        Quorum::AllConnected => {
+            FileVersionReplicaSearch::count_connected_nodes(&mut connection).await?
+        }
+    };
+    Ok((held, required))
+}
+
+/// Waits until enough connected nodes acknowledged holding the version,
+/// fails with `QuorumNotReached` after `timeout`
+pub async fn wait(
+    db: &Database,
+    sync: &SyncHub,
+    file_version_id: &Uuid,
+    quorum: Quorum,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut changes = sync.watch_replicas();
+
+    loop {
+        // mark as seen before counting, so an ack racing the count still wakes us up
+        changes.borrow_and_update();
+        let (held, required) = count(db, file_version_id, quorum).await?;
+        tracing::debug!("Version {file_version_id} is on {held} of {required} required nodes");
+        if held >= required {
+            return Ok(());
+        }
+
+        match tokio::time::timeout_at(deadline, changes.changed()).await {
+            Ok(Ok(())) => continue,
+            _ => return Err(QuorumNotReached { held, required }.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        database::files::{
+            file_type::FileType,
+            records::{
+                change_log_record::{ChangeKind, ChangeLogRecord, LogPosition},
+                dir_record::DirRecord,
+                file_record::FileRecord,
+                file_version_record::{FileVersionRecord, FileVersionState},
+            },
+        },
+        grpc::ConnectionRequest,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Db holding one uploaded version at seq 1
+    async fn uploaded(name: &str) -> (Database, Uuid) {
+        let db = Database::temporary(name).await.unwrap();
+        let mut connection = db.connect().await.unwrap();
+        let dir = DirRecord::create(&mut connection, "dir", None)
+            .await
+            .unwrap();
+        let file = FileRecord::create(&mut connection, &dir.id, "a.txt", FileType::Text, None)
+            .await
+            .unwrap();
+        let file_version = FileVersionRecord::create(
+            &mut connection,
+            &file.id,
+            "1",
+            1,
+            FileVersionState::Ready,
+            None,
+        )
+        .await
+        .unwrap();
+        ChangeLogRecord::append(
+            &mut connection,
+            LogPosition::default(),
+            ChangeKind::Uploaded,
+            &file_version.id,
+            None,
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+        (db, file_version.id)
+    }
+
+    async fn node(db: &Database, url: &str, edge: bool) -> NodeRecord {
+        let request = ConnectionRequest {
+            url: url.to_string(),
+            edge,
+            ..Default::default()
+        };
+        NodeRecord::connect(&mut db.connect().await.unwrap(), &request, None)
+            .await
+            .unwrap()
+    }
+
+    async fn ack(db: &Database, sync: &SyncHub, node: &mut NodeRecord) {
+        node.ack(&mut db.connect().await.unwrap(), 1, None)
+            .await
+            .unwrap();
+        sync.replicas_changed();
+    }
+
+    fn not_reached(res: Result<()>) -> (u32, u32) {
+        let e = res.unwrap_err().downcast::<QuorumNotReached>().unwrap();
+        (e.held, e.required)
+    }
+
+    #[tokio::test]
+    async fn reached_once_enough_nodes_ack() {
+        let (db, file_version_id) = uploaded("quorum-reached").await;
+        let sync = SyncHub::new(16);
+        let mut a = node(&db, "http://a", false).await;
+        let mut b = node(&db, "http://b", false).await;
+
+        let acks = async {
+            ack(&db, &sync, &mut a).await;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            ack(&db, &sync, &mut b).await;
+        };
+        let timeout = Duration::from_secs(5);
+        let (res, _) = tokio::join!(
+            wait(&db, &sync, &file_version_id, Quorum::Nodes(2), timeout),
+            acks
+        );
+        res.unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_short_of_quorum() {
+        let (db, file_version_id) = uploaded("quorum-timeout").await;
+        let sync = SyncHub::new(16);
+        let mut a = node(&db, "http://a", false).await;
+        node(&db, "http://b", false).await;
+        ack(&db, &sync, &mut a).await;
+
+        let res = wait(&db, &sync, &file_version_id, Quorum::Nodes(2), TIMEOUT).await;
+        assert_eq!(not_reached(res), (1, 2));
+        let res = wait(&db, &sync, &file_version_id, Quorum::AllConnected, TIMEOUT).await;
+        assert_eq!(not_reached(res), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn edge_nodes_are_not_counted() {
+        let (db, file_version_id) = uploaded("quorum-edge").await;
+        let sync = SyncHub::new(16);
+        let mut a = node(&db, "http://a", false).await;
+        let mut edge = node(&db, "http://edge", true).await;
+        ack(&db, &sync, &mut a).await;
+        ack(&db, &sync, &mut edge).await;
+
+        // the edge node is not one of all connected nodes either
+        wait(&db, &sync, &file_version_id, Quorum::AllConnected, TIMEOUT)
+            .await
+            .unwrap();
+        let res = wait(&db, &sync, &file_version_id, Quorum::Nodes(2), TIMEOUT).await;
+        assert_eq!(not_reached(res), (1, 2));
+    }
+}
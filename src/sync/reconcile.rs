@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+
+use crate::{
+    database::files::sync::FileSync,
+    grpc::{sync_message::MessageType, DeletedVersion, DirDigest, SyncMessage, VersionDigest},
+};
+
+type DirVersions = BTreeMap<String, BTreeSet<String>>;
+
+/// Live versions with their tags grouped by dir, same on main server and nodes
+#[derive(Debug, Default)]
+pub struct Catalogue {
+    pub seq: u64,
+    dirs: BTreeMap<String, DirVersions>,
+    uploads: HashMap<String, SyncMessage>,
+    tags: HashMap<(String, String), SyncMessage>,
+}
+
+impl Catalogue {
+    pub async fn load(connection: &mut SqliteConnection) -> Result<Self> {
+        let (seq, items) = FileSync::snapshot(connection).await?;
+        Ok(Self::from_messages(
+            seq,
+            items.into_iter().map(SyncMessage::from),
+        ))
+    }
+
+    /// Tags are added once every upload is in, whatever order they are listed in
+    fn from_messages(seq: u64, messages: impl IntoIterator<Item = SyncMessage>) -> Self {
+        let mut catalogue = Self {
+            seq,
+            ..Default::default()
+        };
+        let (uploads, tags): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|message| matches!(message.message_type, Some(MessageType::Uploaded(_))));
+
+        let mut version_dirs = HashMap::new();
+        for message in uploads {
+            let Some(MessageType::Uploaded(uploaded)) = &message.message_type else {
+                continue;
+            };
+            let id = uploaded.file_version_id.clone();
+            catalogue
+                .dirs
+                .entry(uploaded.dir_id.clone())
+                .or_default()
+                .insert(id.clone(), BTreeSet::new());
+            version_dirs.insert(id.clone(), uploaded.dir_id.clone());
+            catalogue.uploads.insert(id, message);
+        }
+        for message in tags {
+            let Some(MessageType::Tagged(tagged)) = &message.message_type else {
+                continue;
+            };
+            let id = tagged.file_version_id.clone();
+            let Some(dir_id) = version_dirs.get(&id) else {
+                continue;
+            };
+            if let Some(tags) = catalogue
+                .dirs
+                .get_mut(dir_id)
+                .and_then(|versions| versions.get_mut(&id))
+            {
+                tags.insert(tagged.tag.clone());
+            }
+            catalogue.tags.insert((id, tagged.tag.clone()), message);
+        }
+
+        catalogue
+    }
+
+    pub fn uploads(&self) -> impl Iterator<Item = &SyncMessage> {
+        self.uploads.values()
+    }
+
+    /// Leaves versions out of digests, so they are reported as missing
+    pub fn exclude(&mut self, file_version_ids: &HashSet<String>) {
+        for versions in self.dirs.values_mut() {
+            versions.retain(|id, _| !file_version_ids.contains(id));
+        }
+        self.dirs.retain(|_, versions| !versions.is_empty());
+    }
+
+    /// Hash of every dir
+    pub fn digests(&self) -> Vec<DirDigest> {
+        self.dirs
+            .iter()
+            .map(|(dir_id, versions)| DirDigest {
+                dir_id: dir_id.clone(),
+                hash: hash(versions),
+                versions: vec![],
+            })
+            .collect()
+    }
+
+    /// Hash and versions of requested dirs, a dir missing here is sent without versions
+    pub fn detailed(&self, dir_ids: &[String]) -> Vec<DirDigest> {
+        dir_ids
+            .iter()
+            .map(|dir_id| {
+                let versions = self.dirs.get(dir_id).cloned().unwrap_or_default();
+                DirDigest {
+                    dir_id: dir_id.clone(),
+                    hash: hash(&versions),
+                    versions: versions
+                        .into_iter()
+                        .map(|(file_version_id, tags)| VersionDigest {
+                            file_version_id,
+                            tags: tags.into_iter().collect(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Dirs with different hash or present on one side only
+    pub fn mismatched(&self, remote: &[DirDigest]) -> Vec<String> {
+        let remote: HashMap<&str, &str> = remote
+            .iter()
+            .map(|d| (d.dir_id.as_str(), d.hash.as_str()))
+            .collect();
+
+        let mut mismatched: Vec<String> = self
+            .dirs
+            .iter()
+            .filter(|(dir_id, versions)| {
+                remote.get(dir_id.as_str()) != Some(&hash(versions).as_str())
+            })
+            .map(|(dir_id, _)| dir_id.clone())
+            .collect();
+        mismatched.extend(
+            remote
+                .keys()
+                .filter(|dir_id| !self.dirs.contains_key(**dir_id))
+                .map(|dir_id| dir_id.to_string()),
+        );
+        mismatched
+    }
+
+    /// Changes bringing remote dirs to this catalogue: deletes first, then uploads and tags
+    pub fn changes(&self, remote: &[DirDigest]) -> Vec<SyncMessage> {
+        let empty = DirVersions::new();
+        let mut deleted = vec![];
+        let mut uploaded = vec![];
+        let mut tagged = vec![];
+
+        for dir in remote {
+            let local = self.dirs.get(&dir.dir_id).unwrap_or(&empty);
+            if hash(local) == dir.hash {
+                continue;
+            }
+            let remote: HashMap<&str, &Vec<String>> = dir
+                .versions
+                .iter()
+                .map(|v| (v.file_version_id.as_str(), &v.tags))
+                .collect();
+
+            deleted.extend(
+                remote
+                    .keys()
+                    .filter(|id| !local.contains_key(**id))
+                    .map(|id| SyncMessage {
+                        message_type: Some(MessageType::Deleted(DeletedVersion {
+                            file_version_id: id.to_string(),
+                        })),
+                        ..Default::default()
+                    }),
+            );
+
+            for (id, tags) in local {
+                let remote_tags = remote.get(id.as_str());
+                if remote_tags.is_none() {
+                    uploaded.extend(self.uploads.get(id).cloned());
+                }
+                tagged.extend(
+                    tags.iter()
+                        .filter(|tag| !remote_tags.is_some_and(|t| t.contains(tag)))
+                        .filter_map(|tag| self.tags.get(&(id.clone(), tag.clone())).cloned()),
+                );
+            }
+        }
+
+        deleted.into_iter().chain(uploaded).chain(tagged).collect()
+    }
+}
+
+fn hash(versions: &DirVersions) -> String {
+    let mut hasher = Sha256::new();
+    for (id, tags) in versions {
+        hasher.update(id.as_bytes());
+        for tag in tags {
+            hasher.update(b"\t");
+            hasher.update(tag.as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::{UploadedVersion, VersionTagged};
+
+    fn uploaded(dir_id: &str, file_version_id: &str) -> SyncMessage {
+        SyncMessage {
+            message_type: Some(MessageType::Uploaded(UploadedVersion {
+                dir_id: dir_id.to_string(),
+                file_version_id: file_version_id.to_string(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn tagged(file_version_id: &str, tag: &str) -> SyncMessage {
+        SyncMessage {
+            message_type: Some(MessageType::Tagged(VersionTagged {
+                file_version_id: file_version_id.to_string(),
+                tag: tag.to_string(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn catalogue(messages: Vec<SyncMessage>) -> Catalogue {
+        Catalogue::from_messages(1, messages)
+    }
+
+    /// Changes main server sends a node, the way replica and nodes service exchange digests
+    fn changes(main: &Catalogue, node: &Catalogue) -> Vec<(&'static str, String)> {
+        let mismatched = main.mismatched(&node.digests());
+        main.changes(&node.detailed(&mismatched))
+            .into_iter()
+            .map(|message| {
+                let kind = message.kind();
+                let id = match message.message_type {
+                    Some(MessageType::Uploaded(m)) => m.file_version_id,
+                    Some(MessageType::Tagged(m)) => format!("{} {}", m.file_version_id, m.tag),
+                    Some(MessageType::Deleted(m)) => m.file_version_id,
+                    None => String::new(),
+                };
+                (kind, id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tags_listed_before_their_upload_are_kept() {
+        let catalogue = catalogue(vec![tagged("v1", "latest"), uploaded("d1", "v1")]);
+
+        let [dir] = &catalogue.detailed(&["d1".to_string()])[..] else {
+            panic!("one dir requested");
+        };
+        assert_eq!(dir.versions.len(), 1);
+        assert_eq!(dir.versions[0].tags, ["latest"]);
+        assert!(catalogue
+            .tags
+            .contains_key(&("v1".to_string(), "latest".to_string())));
+    }
+
+    #[test]
+    fn dirs_on_one_side_are_mismatched() {
+        let main = catalogue(vec![uploaded("d1", "v1"), uploaded("d2", "v2")]);
+        let node = catalogue(vec![uploaded("d2", "v2"), uploaded("d3", "v3")]);
+
+        let mut mismatched = main.mismatched(&node.digests());
+        mismatched.sort();
+        assert_eq!(mismatched, ["d1", "d3"]);
+        assert_eq!(
+            changes(&main, &node),
+            [
+                ("deleted", "v3".to_string()),
+                ("uploaded", "v1".to_string())
+            ]
+        );
+        assert!(changes(&main, &main).is_empty());
+    }
+
+    #[test]
+    fn tag_only_difference_sends_the_tag() {
+        let main = catalogue(vec![
+            uploaded("d1", "v1"),
+            tagged("v1", "latest"),
+            tagged("v1", "stable"),
+        ]);
+        let node = catalogue(vec![uploaded("d1", "v1"), tagged("v1", "stable")]);
+
+        assert_eq!(main.mismatched(&node.digests()), ["d1"]);
+        assert_eq!(changes(&main, &node), [("tagged", "v1 latest".to_string())]);
+    }
+
+    #[test]
+    fn deletes_come_before_uploads_and_tags() {
+        let main = catalogue(vec![
+            uploaded("d1", "v2"),
+            tagged("v2", "latest"),
+            uploaded("d2", "v4"),
+        ]);
+        let node = catalogue(vec![
+            uploaded("d1", "v1"),
+            tagged("v1", "latest"),
+            uploaded("d2", "v3"),
+        ]);
+
+        let changes = changes(&main, &node);
+        let kinds: Vec<_> = changes.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            kinds,
+            ["deleted", "deleted", "uploaded", "uploaded", "tagged"]
+        );
+        let mut deleted: Vec<_> = changes[..2].iter().map(|(_, id)| id.as_str()).collect();
+        deleted.sort();
+        assert_eq!(deleted, ["v1", "v3"]);
+        assert_eq!(changes[4].1, "v2 latest");
+    }
+}
@@ -0,0 +1,852 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tonic::transport::{Channel, Endpoint};
+use uuid::Uuid;
+
+use crate::{
+    database::files::{
+        records::{
+            blob_record::BlobRecord,
+            change_log_record::{ChangeKind, ChangeLogRecord, LogPosition},
+            dir_record::DirRecord,
+            file_record::FileRecord,
+            file_version_record::{FileVersionRecord, FileVersionState},
+            sync_cursor_record::SyncCursorRecord,
+        },
+        sync::FileSync,
+    },
+    entities::files::blobs,
+    grpc::{
+        qcdn_files_client::QcdnFilesClient, qcdn_nodes_client::QcdnNodesClient, snapshot_message,
+        sync_message::MessageType, timestamp_to_datetime, DeletedVersion, DownloadRequest,
+        GetFileVersionRequest, ReconcileRequest, SyncMessage, UploadedVersion, VersionTagged,
+    },
+    storage,
+    sync::{reconcile::Catalogue, status::ReplicationStatus},
+    AppState, DatabasePoolConnection,
+};
+
+const MAX_PEER_ATTEMPTS: usize = 3;
+/// Suffix of version bytes main server pushed ahead of the uploaded change
+pub const PUSHED_SUFFIX: &str = ".pushed";
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Replica {
+    app_state: Arc<AppState>,
+    // main server files api, none in push mode where main server pushes the bytes
+    files: Option<QcdnFilesClient<Channel>>,
+    status: ReplicationStatus,
+    cursor: u64,
+    // main server follower keeps leader seqs, so its log can be served after a failover
+    mirror: bool,
+}
+
+impl Replica {
+    pub async fn load(
+        app_state: Arc<AppState>,
+        files: Option<QcdnFilesClient<Channel>>,
+        status: ReplicationStatus,
+    ) -> Result<Self> {
+        let cursor = Self::stored_cursor(&app_state).await?;
+        Ok(Self {
+            app_state,
+            files,
+            status,
+            cursor,
+            mirror: false,
+        })
+    }
+
+    /// Replica of a main server follower, appends changes under leader seqs
+    /// and continues from its own change log
+    pub async fn mirror(
+        app_state: Arc<AppState>,
+        files: QcdnFilesClient<Channel>,
+        status: ReplicationStatus,
+    ) -> Result<Self> {
+        let mut connection = app_state.db.connect().await?;
+        let cursor = ChangeLogRecord::latest_seq(&mut connection).await?;
+        drop(connection);
+        Ok(Self {
+            app_state,
+            files: Some(files),
+            status,
+            cursor,
+            mirror: true,
+        })
+    }
+
+    /// Persisted sequence number, a fresh node starts from 0 and replays everything
+    pub async fn stored_cursor(app_state: &AppState) -> Result<u64> {
+        let mut connection = app_state.db.connect().await?;
+        let cursor = SyncCursorRecord::find(&mut connection)
+            .await?
+            .map(|c| c.seq)
+            .unwrap_or_default();
+        Ok(cursor)
+    }
+}
+
+impl Replica {
+    /// Sequence number of the last main server change applied on this node
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Applies the live catalogue of main server, the cursor is stored only once the whole
+    /// snapshot is applied, so an interrupted bootstrap starts over
+    pub async fn bootstrap(&mut self, nodes: &mut QcdnNodesClient<Channel>) -> Result<()> {
+        let mut stream = nodes.get_snapshot(()).await?.into_inner();
+
+        let mut applied = 0;
+        while let Some(message) = stream.message().await? {
+            let ts = timestamp_to_datetime(message.timestamp);
+            match message.item {
+                Some(snapshot_message::Item::Uploaded(uploaded)) => {
+                    self.uploaded(uploaded, LogPosition::default()).await?
+                }
+                Some(snapshot_message::Item::Tagged(tagged)) => {
+                    self.tagged(tagged, ts, LogPosition::default()).await?
+                }
+                None => {
+                    let mut connection = self.app_state.db.connect().await?;
+                    SyncCursorRecord::save(&mut connection, message.seq, None).await?;
+                    self.cursor = message.seq;
+                    self.status.applied(message.seq, message.seq);
+                    tracing::info!(
+                        "Bootstrapped {applied} items from snapshot at seq {}",
+                        message.seq
+                    );
+                    return Ok(());
+                }
+            }
+            applied += 1;
+        }
+
+        bail!("snapshot stream ended before completion")
+    }
+
+    pub async fn apply(&mut self, message: SyncMessage) -> Result<()> {
+        let ts = timestamp_to_datetime(message.timestamp);
+        let seq = message.seq;
+        let head_seq = message.head_seq;
+        if self.mirror {
+            // already in the log, appending it again would clash on seq
+            if seq <= self.cursor {
+                return Ok(());
+            }
+            self.change(message.message_type, ts, LogPosition::at(seq, message.term))
+                .await?;
+        } else {
+            self.change(message.message_type, ts, LogPosition::default())
+                .await?;
+        }
+        if seq > self.cursor {
+            let mut connection = self.app_state.db.connect().await?;
+            SyncCursorRecord::save(&mut connection, seq, None).await?;
+            self.cursor = seq;
+        }
+        self.status.applied(seq, head_seq);
+        Ok(())
+    }
+
+    async fn change(
+        &mut self,
+        message_type: Option<MessageType>,
+        ts: Option<DateTime<Utc>>,
+        position: LogPosition,
+    ) -> Result<()> {
+        match message_type {
+            Some(MessageType::Uploaded(uploaded)) => self.uploaded(uploaded, position).await,
+            Some(MessageType::Tagged(tagged)) => self.tagged(tagged, ts, position).await,
+            Some(MessageType::Deleted(deleted)) => self.deleted(deleted, ts, position).await,
+            None => {
+                tracing::warn!("Got sync message without type");
+                Ok(())
+            }
+        }
+    }
+
+    /// Compares catalogue digests with main server and applies the differences,
+    /// versions with missing or damaged bytes are downloaded again
+    pub async fn reconcile(&mut self, nodes: &mut QcdnNodesClient<Channel>) -> Result<()> {
+        let mut connection = self.app_state.db.connect().await?;
+        let mut catalogue = Catalogue::load(&mut connection).await?;
+        let damaged = self.damaged(&catalogue).await;
+        catalogue.exclude(&damaged);
+
+        let mismatched_dirs = nodes
+            .reconcile(ReconcileRequest {
+                seq: self.cursor,
+                partial: false,
+                dirs: catalogue.digests(),
+            })
+            .await?
+            .into_inner()
+            .mismatched_dirs;
+        if mismatched_dirs.is_empty() {
+            tracing::debug!("Catalogue is in sync at seq {}", self.cursor);
+            return Ok(());
+        }
+
+        let changes = nodes
+            .reconcile(ReconcileRequest {
+                seq: self.cursor,
+                partial: true,
+                dirs: catalogue.detailed(&mismatched_dirs),
+            })
+            .await?
+            .into_inner()
+            .changes;
+        tracing::warn!(
+            "Catalogue diverged in {} dirs, repairing {} changes",
+            mismatched_dirs.len(),
+            changes.len()
+        );
+        for message in changes {
+            match message.message_type {
+                Some(MessageType::Uploaded(uploaded))
+                    if damaged.contains(&uploaded.file_version_id) =>
+                {
+                    self.restore(uploaded).await?
+                }
+                message_type => {
+                    let ts = timestamp_to_datetime(message.timestamp);
+                    self.change(message_type, ts, LogPosition::default())
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ready versions with missing or resized files in storage
+    async fn damaged(&self, catalogue: &Catalogue) -> HashSet<String> {
+        let mut damaged = HashSet::new();
+        for message in catalogue.uploads() {
+            let Some(MessageType::Uploaded(uploaded)) = &message.message_type else {
+                continue;
+            };
+            let (dir, filename) = storage::version_location(
+                &uploaded.dir_id,
+                &uploaded.file_version_id,
+                uploaded.sha256.as_deref(),
+            );
+            if self
+                .app_state
+                .cache
+                .as_ref()
+                .is_some_and(|cache| !cache.contains(&filename))
+            {
+                continue;
+            }
+            let size = match self.app_state.storage.stat(&dir, &filename).await {
+                Ok(stat) => stat.map(|stat| stat.size),
+                Err(_) => None,
+            };
+            if size != Some(uploaded.size) {
+                tracing::warn!(
+                    "Stored bytes of {} are damaged, expected {} bytes, got {size:?}",
+                    uploaded.file_version_id,
+                    uploaded.size
+                );
+                damaged.insert(uploaded.file_version_id.clone());
+            }
+        }
+        damaged
+    }
+
+    /// Downloads bytes of a ready version again, records stay as they are
+    async fn restore(&mut self, uploaded: UploadedVersion) -> Result<()> {
+        let dir_id = Uuid::parse_str(&uploaded.dir_id)?;
+        let file_version_id = Uuid::parse_str(&uploaded.file_version_id)?;
+
+        self.restore_bytes(
+            &dir_id,
+            &file_version_id,
+            uploaded.size,
+            uploaded.sha256.as_deref(),
+        )
+        .await?;
+
+        tracing::info!("Restored bytes of {file_version_id}");
+
+        Ok(())
+    }
+
+    async fn restore_bytes(
+        &mut self,
+        dir_id: &Uuid,
+        file_version_id: &Uuid,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<()> {
+        self.fetch(dir_id, file_version_id, size, sha256).await?;
+
+        let (dir_id, file_version_id) = (dir_id.to_string(), file_version_id.to_string());
+        let (dir, filename) = storage::version_location(&dir_id, &file_version_id, sha256);
+        let _blobs = self.app_state.storage.lock_blobs().await;
+        self.app_state
+            .storage
+            .store(&dir_id, &file_version_id, &dir, &filename)
+            .await
+    }
+
+    /// Takes back the last change of a follower log the leader does not have,
+    /// bytes of a version it deleted are downloaded again when they are gone
+    pub async fn undo_last(&mut self) -> Result<()> {
+        let mut connection = self.app_state.db.connect().await?;
+        let Some(change) = ChangeLogRecord::find_by_seq(&mut connection, self.cursor).await? else {
+            bail!("change log has no change at seq {}", self.cursor)
+        };
+        let dir_id = match FileVersionRecord::find_by_id_in_any_state(
+            &mut connection,
+            &change.file_version_id,
+        )
+        .await?
+        {
+            Some(file_version) => FileRecord::find_by_id(&mut connection, &file_version.file_id)
+                .await?
+                .map(|file| file.dir_id),
+            None => None,
+        };
+
+        let reverted = {
+            let _blobs = self.app_state.storage.lock_blobs().await;
+            let reverted = FileSync::revert(&mut connection, &change).await?;
+            if let (ChangeKind::Uploaded, Some(file_version), Some(dir_id)) =
+                (change.kind, &reverted, &dir_id)
+            {
+                // shared bytes stay while another version references them
+                let referenced = match &file_version.sha256 {
+                    Some(sha256) => BlobRecord::find(&mut connection, sha256).await?.is_some(),
+                    None => false,
+                };
+                if !referenced {
+                    let (dir, filename) = storage::version_location(
+                        &dir_id.to_string(),
+                        &file_version.id.to_string(),
+                        file_version.sha256.as_deref(),
+                    );
+                    self.app_state
+                        .storage
+                        .remove_stored(&dir, &filename)
+                        .await
+                        .ok();
+                }
+            }
+            reverted
+        };
+        self.cursor = ChangeLogRecord::latest_seq(&mut connection).await?;
+        drop(connection);
+        tracing::warn!(
+            "Took back {:?} change of {} at seq {}, the leader does not have it",
+            change.kind,
+            change.file_version_id,
+            change.seq
+        );
+
+        if let (ChangeKind::Deleted, Some(file_version), Some(dir_id)) =
+            (change.kind, reverted, dir_id)
+        {
+            let (dir, filename) = storage::version_location(
+                &dir_id.to_string(),
+                &file_version.id.to_string(),
+                file_version.sha256.as_deref(),
+            );
+            if self
+                .app_state
+                .storage
+                .stat(&dir, &filename)
+                .await?
+                .is_none()
+            {
+                if let Err(e) = self
+                    .restore_bytes(
+                        &dir_id,
+                        &file_version.id,
+                        file_version.size,
+                        file_version.sha256.as_deref(),
+                    )
+                    .await
+                {
+                    tracing::warn!("Cannot restore bytes of {}: {e}", file_version.id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn uploaded(&mut self, uploaded: UploadedVersion, position: LogPosition) -> Result<()> {
+        let dir_id = Uuid::parse_str(&uploaded.dir_id)?;
+        let file_id = Uuid::parse_str(&uploaded.file_id)?;
+        let file_version_id = Uuid::parse_str(&uploaded.file_version_id)?;
+
+        let mut connection = self.app_state.db.connect().await?;
+
+        if let Some(existing) =
+            FileVersionRecord::find_by_id_in_any_state(&mut connection, &file_version_id).await?
+        {
+            if existing.state == FileVersionState::Ready {
+                if existing.deleted_at.is_none() || uploaded.deleted {
+                    tracing::debug!("File version {file_version_id} is already present");
+                    return Self::mirrored(
+                        &mut connection,
+                        position,
+                        ChangeKind::Uploaded,
+                        &file_version_id,
+                        None,
+                        existing.created_at,
+                    )
+                    .await;
+                }
+                return self.undelete(uploaded, existing, position).await;
+            }
+            tracing::info!("Restarting interrupted download of {file_version_id}");
+            self.app_state
+                .storage
+                .remove_file(&dir_id.to_string(), &file_version_id.to_string())
+                .await
+                .ok();
+            existing.unsafe_delete(&mut connection).await?;
+        }
+
+        let dir_record = match DirRecord::find_by_id(&mut connection, &dir_id).await? {
+            Some(dir) => dir,
+            None => {
+                DirRecord::create_with_id(
+                    &mut connection,
+                    &dir_id,
+                    &uploaded.dir,
+                    timestamp_to_datetime(uploaded.dir_created_at.clone()),
+                )
+                .await?
+            }
+        };
+
+        let file_record = match FileRecord::find_by_id(&mut connection, &file_id).await? {
+            Some(file) => file,
+            None => {
+                FileRecord::create_with_id(
+                    &mut connection,
+                    &file_id,
+                    &dir_record.id,
+                    &uploaded.name,
+                    uploaded.file_type().into(),
+                    timestamp_to_datetime(uploaded.file_created_at.clone()),
+                )
+                .await?
+            }
+        };
+
+        let mut file_version_record = FileVersionRecord::create_with_id(
+            &mut connection,
+            &file_version_id,
+            &file_record.id,
+            &uploaded.version,
+            uploaded.size,
+            FileVersionState::Downloading,
+            timestamp_to_datetime(uploaded.created_at),
+        )
+        .await?;
+
+        // edge nodes pull bytes only when requested, versions deleted later and blobs stored
+        // for another version need no download
+        let fetch = self.app_state.cache.is_none()
+            && !uploaded.deleted
+            && !self.has_blob(uploaded.sha256.as_deref()).await;
+        if fetch {
+            if let Err(e) = self
+                .fetch(
+                    &dir_record.id,
+                    &file_version_record.id,
+                    uploaded.size,
+                    uploaded.sha256.as_deref(),
+                )
+                .await
+            {
+                self.cleanup(
+                    &mut connection,
+                    &dir_record,
+                    &file_record,
+                    &file_version_record,
+                )
+                .await?;
+                bail!(e)
+            }
+        }
+
+        if let Some(sha256) = &uploaded.sha256 {
+            file_version_record
+                .update_sha256(&mut connection, sha256)
+                .await?;
+        }
+
+        if fetch {
+            blobs::commit_uploaded(
+                &self.app_state.storage,
+                &mut connection,
+                position,
+                &dir_record,
+                &file_record,
+                &mut file_version_record,
+            )
+            .await?;
+        } else {
+            FileSync::commit_uploaded(
+                &mut connection,
+                position,
+                &dir_record,
+                &file_record,
+                &mut file_version_record,
+            )
+            .await?;
+        }
+
+        tracing::info!(
+            "Replicated {}/{}@{} ({file_version_id})",
+            dir_record.name,
+            file_record.name,
+            file_version_record.version
+        );
+
+        Ok(())
+    }
+
+    /// Version deleted here but live on main server, its blob may be gone already
+    async fn undelete(
+        &mut self,
+        uploaded: UploadedVersion,
+        mut file_version_record: FileVersionRecord,
+        position: LogPosition,
+    ) -> Result<()> {
+        let mut connection = self.app_state.db.connect().await?;
+        let Some(file_record) =
+            FileRecord::find_by_id(&mut connection, &file_version_record.file_id).await?
+        else {
+            bail!(
+                "File of deleted version {} is missing",
+                file_version_record.id
+            )
+        };
+        let Some(dir_record) = DirRecord::find_by_id(&mut connection, &file_record.dir_id).await?
+        else {
+            bail!(
+                "Dir of deleted version {} is missing",
+                file_version_record.id
+            )
+        };
+
+        let fetch =
+            self.app_state.cache.is_none() && !self.has_blob(uploaded.sha256.as_deref()).await;
+        if fetch {
+            if let Err(e) = self
+                .fetch(
+                    &dir_record.id,
+                    &file_version_record.id,
+                    uploaded.size,
+                    uploaded.sha256.as_deref(),
+                )
+                .await
+            {
+                self.app_state
+                    .storage
+                    .remove_file(
+                        &dir_record.id.to_string(),
+                        &file_version_record.id.to_string(),
+                    )
+                    .await
+                    .ok();
+                bail!(e)
+            }
+            blobs::commit_uploaded(
+                &self.app_state.storage,
+                &mut connection,
+                position,
+                &dir_record,
+                &file_record,
+                &mut file_version_record,
+            )
+            .await?;
+        } else {
+            FileSync::commit_uploaded(
+                &mut connection,
+                position,
+                &dir_record,
+                &file_record,
+                &mut file_version_record,
+            )
+            .await?;
+        }
+
+        tracing::info!("Restored deleted version {}", file_version_record.id);
+
+        Ok(())
+    }
+
+    async fn has_blob(&self, sha256: Option<&str>) -> bool {
+        match sha256 {
+            Some(sha256) => self.app_state.storage.has_blob(sha256).await,
+            None => false,
+        }
+    }
+
+    /// Connected peers that acknowledged holding the version, in random order
+    async fn peers(
+        &self,
+        files: &mut QcdnFilesClient<Channel>,
+        file_version_id: &Uuid,
+    ) -> Vec<String> {
+        let replicas = match files
+            .get_file_version(GetFileVersionRequest {
+                id: file_version_id.to_string(),
+            })
+            .await
+        {
+            Ok(response) => response.into_inner().replicas,
+            Err(e) => {
+                tracing::debug!("Cannot list peers holding {file_version_id}: {e}");
+                return vec![];
+            }
+        };
+
+        let mut peers: Vec<String> = replicas
+            .into_iter()
+            .filter(|r| r.connected && r.url != self.app_state.config.base_url)
+            .filter_map(|r| r.grpc_url)
+            .collect();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(MAX_PEER_ATTEMPTS);
+        peers
+    }
+
+    /// Downloads from peers first to take load off main server, main server is the fallback
+    async fn fetch(
+        &mut self,
+        dir_id: &Uuid,
+        file_version_id: &Uuid,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<()> {
+        let Some(mut files) = self.files.clone() else {
+            return self.pushed(dir_id, file_version_id, size).await;
+        };
+
+        for peer in self.peers(&mut files, file_version_id).await {
+            let res = async {
+                let channel = Endpoint::from_shared(peer.clone())?
+                    .connect_timeout(PEER_CONNECT_TIMEOUT)
+                    .connect()
+                    .await?;
+                let mut client = QcdnFilesClient::new(channel);
+                self.download(&mut client, dir_id, file_version_id, size, sha256)
+                    .await
+            }
+            .await;
+            match res {
+                Ok(()) => {
+                    tracing::debug!("Fetched {file_version_id} from peer {peer}");
+                    return Ok(());
+                }
+                Err(e) => tracing::warn!("Fetching {file_version_id} from peer {peer} failed: {e}"),
+            }
+        }
+
+        self.download(&mut files, dir_id, file_version_id, size, sha256)
+            .await
+    }
+
+    /// Moves bytes main server pushed into place
+    async fn pushed(&self, dir_id: &Uuid, file_version_id: &Uuid, size: u64) -> Result<()> {
+        let dir_id = dir_id.to_string();
+        let file_version_id = file_version_id.to_string();
+        let pushed = format!("{file_version_id}{PUSHED_SUFFIX}");
+
+        let Ok(file) = self.app_state.storage.open_file(&dir_id, &pushed).await else {
+            bail!("bytes of {file_version_id} were not pushed")
+        };
+        let received_bytes = file.metadata().await?.len();
+        if received_bytes != size {
+            bail!("file transmission corrupted, expected {size} bytes, got {received_bytes}")
+        }
+
+        self.app_state
+            .storage
+            .rename_file(&dir_id, &pushed, &file_version_id)
+            .await
+    }
+
+    async fn download(
+        &self,
+        client: &mut QcdnFilesClient<Channel>,
+        dir_id: &Uuid,
+        file_version_id: &Uuid,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> Result<()> {
+        let mut stream = client
+            .download(DownloadRequest {
+                file_version_id: file_version_id.to_string(),
+            })
+            .await?
+            .into_inner();
+
+        let mut file = self
+            .app_state
+            .storage
+            .create_file(&dir_id.to_string(), &file_version_id.to_string())
+            .await?;
+
+        let mut hasher = Sha256::new();
+        let mut received_bytes = 0;
+        while let Some(part) = stream.message().await? {
+            received_bytes += part.bytes.len() as u64;
+            if received_bytes > size {
+                bail!("file transmission corrupted, got more than expected {size} bytes")
+            }
+            hasher.update(&part.bytes);
+            file.write_all(&part.bytes).await?;
+        }
+        file.flush().await?;
+
+        if received_bytes != size {
+            bail!("file transmission corrupted, expected {size} bytes, got {received_bytes}")
+        }
+        if let Some(expected) = sha256 {
+            let actual = hex::encode(hasher.finalize());
+            if !actual.eq_ignore_ascii_case(expected) {
+                bail!("file content corrupted, expected sha256 {expected}, got {actual}")
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup(
+        &self,
+        connection: &mut DatabasePoolConnection,
+        dir_record: &DirRecord,
+        file_record: &FileRecord,
+        file_version_record: &FileVersionRecord,
+    ) -> Result<()> {
+        self.app_state
+            .storage
+            .remove_file(
+                &dir_record.id.to_string(),
+                &file_version_record.id.to_string(),
+            )
+            .await
+            .ok();
+        file_version_record.unsafe_delete(connection).await?;
+        file_record.delete_if_no_versions_exists(connection).await?;
+        dir_record.delete_if_no_files_exists(connection).await?;
+        Ok(())
+    }
+
+    async fn tagged(
+        &mut self,
+        tagged: VersionTagged,
+        ts: Option<DateTime<Utc>>,
+        position: LogPosition,
+    ) -> Result<()> {
+        let file_version_id = Uuid::parse_str(&tagged.file_version_id)?;
+
+        let mut connection = self.app_state.db.connect().await?;
+
+        if FileVersionRecord::find_by_id(&mut connection, &file_version_id)
+            .await?
+            .is_none()
+        {
+            tracing::warn!("Cannot tag missing file version {file_version_id}");
+            return Self::mirrored(
+                &mut connection,
+                position,
+                ChangeKind::Tagged,
+                &file_version_id,
+                Some(&tagged.tag),
+                ts.unwrap_or_else(Utc::now),
+            )
+            .await;
+        }
+
+        FileSync::commit_tagged(&mut connection, position, &file_version_id, &tagged.tag, ts)
+            .await?;
+
+        tracing::info!("Tagged {file_version_id} as {}", tagged.tag);
+
+        Ok(())
+    }
+
+    async fn deleted(
+        &mut self,
+        deleted: DeletedVersion,
+        ts: Option<DateTime<Utc>>,
+        position: LogPosition,
+    ) -> Result<()> {
+        let file_version_id = Uuid::parse_str(&deleted.file_version_id)?;
+
+        let mut connection = self.app_state.db.connect().await?;
+
+        let Some(mut file_version) =
+            FileVersionRecord::find_by_id(&mut connection, &file_version_id).await?
+        else {
+            tracing::warn!("Cannot delete missing file version {file_version_id}");
+            return Self::mirrored(
+                &mut connection,
+                position,
+                ChangeKind::Deleted,
+                &file_version_id,
+                None,
+                ts.unwrap_or_else(Utc::now),
+            )
+            .await;
+        };
+
+        if file_version.deleted_at.is_some() {
+            return Self::mirrored(
+                &mut connection,
+                position,
+                ChangeKind::Deleted,
+                &file_version_id,
+                None,
+                ts.unwrap_or_else(Utc::now),
+            )
+            .await;
+        }
+
+        blobs::commit_deleted(
+            &self.app_state,
+            &mut connection,
+            position,
+            &mut file_version,
+            ts,
+        )
+        .await?;
+        tracing::info!("Deleted {file_version_id}");
+
+        Ok(())
+    }
+
+    /// Follower log keeps every leader seq, also of changes it has nothing to apply for,
+    /// a gap would make the leader turn its follow requests away
+    async fn mirrored(
+        connection: &mut DatabasePoolConnection,
+        position: LogPosition,
+        kind: ChangeKind,
+        file_version_id: &Uuid,
+        tag: Option<&str>,
+        ts: DateTime<Utc>,
+    ) -> Result<()> {
+        if position.seq.is_some() {
+            ChangeLogRecord::append(connection, position, kind, file_version_id, tag, ts).await?;
+        }
+        Ok(())
+    }
+}
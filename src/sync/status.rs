@@ -0,0 +1,115 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+
+use crate::grpc::{datetime_to_timestamp, ReplicationState, ReplicationStatusResponse};
+
+#[derive(Debug)]
+struct Inner {
+    state: ReplicationState,
+    seq: u64,
+    head_seq: u64,
+    attempts: u32,
+    last_error: Option<String>,
+    state_changed_at: DateTime<Utc>,
+    last_applied_at: Option<DateTime<Utc>>,
+}
+
+/// Connection state of a node to the main server, shared with the gRPC api
+#[derive(Debug, Clone)]
+pub struct ReplicationStatus {
+    inner: Arc<Mutex<Inner>>,
+    applied: Arc<watch::Sender<u64>>,
+}
+
+impl ReplicationStatus {
+    pub fn new(seq: u64) -> Self {
+        let inner = Arc::new(Mutex::new(Inner {
+            state: ReplicationState::Connecting,
+            seq,
+            head_seq: seq,
+            attempts: 0,
+            last_error: None,
+            state_changed_at: Utc::now(),
+            last_applied_at: None,
+        }));
+        Self {
+            inner,
+            applied: Arc::new(watch::channel(seq).0),
+        }
+    }
+}
+
+impl ReplicationStatus {
+    fn update(&self, f: impl FnOnce(&mut Inner)) {
+        let mut inner = self.inner.lock().expect("replication status lock poisoned");
+        f(&mut inner);
+    }
+
+    fn set_state(inner: &mut Inner, state: ReplicationState) {
+        if inner.state != state {
+            inner.state = state;
+            inner.state_changed_at = Utc::now();
+        }
+    }
+
+    pub fn connecting(&self, attempts: u32) {
+        self.update(|inner| {
+            inner.attempts = attempts;
+            Self::set_state(inner, ReplicationState::Connecting);
+        });
+    }
+
+    pub fn connected(&self) {
+        self.update(|inner| {
+            inner.attempts = 0;
+            inner.last_error = None;
+            Self::set_state(inner, ReplicationState::Connected);
+        });
+    }
+
+    pub fn disconnected(&self, error: Option<String>) {
+        self.update(|inner| {
+            inner.last_error = error;
+            Self::set_state(inner, ReplicationState::Disconnected);
+        });
+    }
+
+    pub fn applied(&self, seq: u64, head_seq: u64) {
+        self.update(|inner| {
+            inner.seq = inner.seq.max(seq);
+            inner.head_seq = inner.head_seq.max(head_seq).max(seq);
+            inner.last_applied_at = Some(Utc::now());
+        });
+        self.applied.send_if_modified(|applied| {
+            let modified = seq > *applied;
+            *applied = (*applied).max(seq);
+            modified
+        });
+    }
+
+    /// Waits until every change up to `seq` is applied, false on timeout
+    pub async fn wait_applied(&self, seq: u64, timeout: Duration) -> bool {
+        let mut applied = self.applied.subscribe();
+        let res = tokio::time::timeout(timeout, applied.wait_for(|applied| *applied >= seq)).await;
+        matches!(res, Ok(Ok(_)))
+    }
+
+    pub fn to_response(&self) -> ReplicationStatusResponse {
+        let inner = self.inner.lock().expect("replication status lock poisoned");
+        ReplicationStatusResponse {
+            state: inner.state.into(),
+            seq: inner.seq,
+            head_seq: inner.head_seq,
+            lag: inner.head_seq.saturating_sub(inner.seq),
+            attempts: inner.attempts,
+            last_error: inner.last_error.clone(),
+            state_changed_at: datetime_to_timestamp(inner.state_changed_at),
+            last_applied_at: inner.last_applied_at.and_then(datetime_to_timestamp),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use anyhow::Result;
 use listenfd::ListenFd;
 use tokio::net::TcpListener;
@@ -22,8 +24,17 @@ pub async fn run(config: &CliConfig, app_state: AppState) -> Result<()> {
 
     let listener = create_listener(config).await?;
 
-    tracing::info!("Starting on: http://{}", &listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    tracing::info!(
+        "Starting on: http://{} in {:?} mode",
+        &listener.local_addr()?,
+        config.web_mode
+    );
+    // client address is needed to find the closest node when redirecting
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,216 @@
+use std::time::SystemTime;
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    pub fn new(tag: &str, last_modified: DateTime<Utc>) -> Self {
+        Self {
+            etag: format!("\"{tag}\""),
+            last_modified,
+        }
+    }
+
+    pub fn last_modified_http(&self) -> String {
+        let ts: SystemTime = self.last_modified.into();
+        httpdate::fmt_http_date(ts)
+    }
+
+    pub fn headers(&self) -> [(header::HeaderName, String); 2] {
+        [
+            (header::ETAG, self.etag.clone()),
+            (header::LAST_MODIFIED, self.last_modified_http()),
+        ]
+    }
+
+    // precedence follows RFC 9110 section 13.2.2
+    pub fn evaluate(&self, headers: &HeaderMap) -> Option<StatusCode> {
+        if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+            if !self.matches(if_match, true) {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        } else if let Some(since) = header_date(headers, header::IF_UNMODIFIED_SINCE) {
+            if self.last_modified.timestamp() > since.timestamp() {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        }
+
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            if self.matches(if_none_match, false) {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        } else if let Some(since) = header_date(headers, header::IF_MODIFIED_SINCE) {
+            if self.last_modified.timestamp() <= since.timestamp() {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        }
+
+        None
+    }
+
+    pub fn if_range_allows(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = header_str(headers, header::IF_RANGE) else {
+            return true;
+        };
+        if value.starts_with('"') || value.starts_with("W/") {
+            return value == self.etag;
+        }
+        match httpdate::parse_http_date(value) {
+            Ok(date) => DateTime::<Utc>::from(date).timestamp() == self.last_modified.timestamp(),
+            Err(_) => false,
+        }
+    }
+
+    fn matches(&self, value: &str, strong: bool) -> bool {
+        value.split(',').map(str::trim).any(|candidate| {
+            if candidate == "*" {
+                return true;
+            }
+            match candidate.strip_prefix("W/") {
+                Some(weak) => !strong && weak == self.etag,
+                None => candidate == self.etag,
+            }
+        })
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<DateTime<Utc>> {
+    header_str(headers, name)
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .map(DateTime::<Utc>::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators::new("v1", Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap())
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    const BEFORE: &str = "Tue, 09 Jan 2024 12:00:00 GMT";
+    const SAME: &str = "Wed, 10 Jan 2024 12:00:00 GMT";
+
+    #[test]
+    fn no_conditions_pass() {
+        assert_eq!(validators().evaluate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn if_none_match_hits_not_modified() {
+        let v = validators();
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_NONE_MATCH, "\"v1\"")])),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_NONE_MATCH, "W/\"v1\"")])),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_NONE_MATCH, "\"v0\", *")])),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_NONE_MATCH, "\"v0\"")])),
+            None
+        );
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        let v = validators();
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_MODIFIED_SINCE, SAME)])),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_MODIFIED_SINCE, BEFORE)])),
+            None
+        );
+    }
+
+    #[test]
+    fn if_none_match_takes_priority_over_if_modified_since() {
+        let v = validators();
+        assert_eq!(
+            v.evaluate(&headers(&[
+                (header::IF_NONE_MATCH, "\"v0\""),
+                (header::IF_MODIFIED_SINCE, SAME),
+            ])),
+            None
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[
+                (header::IF_NONE_MATCH, "\"v1\""),
+                (header::IF_MODIFIED_SINCE, BEFORE),
+            ])),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+    }
+
+    #[test]
+    fn if_match_needs_strong_etag() {
+        let v = validators();
+        assert_eq!(v.evaluate(&headers(&[(header::IF_MATCH, "\"v1\"")])), None);
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_MATCH, "W/\"v1\"")])),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn if_match_takes_priority_over_if_unmodified_since() {
+        let v = validators();
+        assert_eq!(
+            v.evaluate(&headers(&[
+                (header::IF_MATCH, "\"v1\""),
+                (header::IF_UNMODIFIED_SINCE, BEFORE),
+            ])),
+            None
+        );
+        assert_eq!(
+            v.evaluate(&headers(&[(header::IF_UNMODIFIED_SINCE, BEFORE)])),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn if_range_matches_strong_etag_only() {
+        let v = validators();
+        assert!(v.if_range_allows(&HeaderMap::new()));
+        assert!(v.if_range_allows(&headers(&[(header::IF_RANGE, "\"v1\"")])));
+        assert!(!v.if_range_allows(&headers(&[(header::IF_RANGE, "W/\"v1\"")])));
+        assert!(!v.if_range_allows(&headers(&[(header::IF_RANGE, "\"v0\"")])));
+    }
+
+    #[test]
+    fn if_range_matches_exact_date() {
+        let v = validators();
+        assert!(v.if_range_allows(&headers(&[(header::IF_RANGE, SAME)])));
+        assert!(!v.if_range_allows(&headers(&[(header::IF_RANGE, BEFORE)])));
+        assert!(!v.if_range_allows(&headers(&[(header::IF_RANGE, "garbage")])));
+    }
+}
@@ -0,0 +1,75 @@
+use mime_guess::mime;
+
+use crate::database::files::file_type::FileType;
+
+fn is_textual(mime: &mime::Mime) -> bool {
+    mime.type_() == mime::TEXT
+        || matches!(mime.subtype().as_str(), "javascript" | "json" | "xml")
+        || matches!(mime.suffix().map(|s| s.as_str()), Some("json" | "xml"))
+}
+
+pub fn content_type(name: &str, file_type: &FileType) -> String {
+    let mime = mime_guess::from_path(name).first().unwrap_or_else(|| {
+        file_type
+            .mime()
+            .parse()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+    });
+
+    if is_textual(&mime) && mime.get_param(mime::CHARSET).is_none() {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_from_extension() {
+        for (name, expected) in [
+            ("style.css", "text/css; charset=utf-8"),
+            ("app.js", "text/javascript; charset=utf-8"),
+            ("data.json", "application/json; charset=utf-8"),
+            ("feed.atom", "application/atom+xml; charset=utf-8"),
+            ("icon.svg", "image/svg+xml; charset=utf-8"),
+            ("page.HTML", "text/html; charset=utf-8"),
+            ("logo.png", "image/png"),
+            ("font.woff2", "font/woff2"),
+            ("archive.tar.gz", "application/gzip"),
+        ] {
+            assert_eq!(content_type(name, &FileType::Other), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn extension_wins_over_file_type() {
+        assert_eq!(content_type("logo.png", &FileType::Text), "image/png");
+        assert_eq!(
+            content_type("style.css", &FileType::Javascript),
+            "text/css; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_file_type() {
+        for (file_type, expected) in [
+            (FileType::Other, "application/octet-stream"),
+            (FileType::Stylesheets, "text/css; charset=utf-8"),
+            (FileType::Javascript, "text/javascript; charset=utf-8"),
+            (FileType::Image, "application/octet-stream"),
+            (FileType::Font, "application/octet-stream"),
+            (FileType::Text, "text/plain; charset=utf-8"),
+        ] {
+            for name in ["LICENSE", "bundle.unknownext"] {
+                assert_eq!(
+                    content_type(name, &file_type),
+                    expected,
+                    "{name} {file_type:?}"
+                );
+            }
+        }
+    }
+}
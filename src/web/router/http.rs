@@ -1,14 +1,405 @@
-use crate::app_state::AppState;
-use axum::{http::Method, routing::get, Router};
+use std::sync::Arc;
+
+use crate::{
+    app_state::AppState,
+    cache::EdgeCache,
+    config::CliConfig,
+    constants::LATEST_TAG,
+    database::files::records::{
+        dir_record::DirRecord, file_record::FileRecord, file_version_record::FileVersionRecord,
+        file_version_tag_record::FileVersionTagRecord,
+    },
+    DatabaseConnection, Storage,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use sqlx::SqliteConnection;
 use tower_http::cors::{self, CorsLayer};
 
+use super::{
+    conditional::Validators,
+    content_type,
+    range::{self, ByteRanges},
+    redirect::Redirector,
+};
+
+type HttpError = (StatusCode, String);
+
+fn internal_error(e: anyhow::Error) -> HttpError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn not_found(what: &str) -> HttpError {
+    (StatusCode::NOT_FOUND, format!("{what} not found"))
+}
+
 async fn health() -> &'static str {
     "OK"
 }
 
+struct ResolvedVersion {
+    file: FileRecord,
+    file_version: FileVersionRecord,
+    validators: Validators,
+    // pinned urls always point to the same bytes, tag urls may move
+    pinned: bool,
+}
+
+impl ResolvedVersion {
+    fn pinned(file: FileRecord, file_version: FileVersionRecord) -> Self {
+        let validators = Validators::new(&file_version.id.to_string(), file_version.created_at);
+        Self {
+            file,
+            file_version,
+            validators,
+            pinned: true,
+        }
+    }
+
+    fn moving(
+        file: FileRecord,
+        file_version: FileVersionRecord,
+        last_modified: DateTime<Utc>,
+    ) -> Self {
+        let validators = Validators::new(&file_version.id.to_string(), last_modified);
+        Self {
+            file,
+            file_version,
+            validators,
+            pinned: false,
+        }
+    }
+
+    fn cache_control(&self, config: &CliConfig) -> String {
+        if self.pinned {
+            "public, max-age=31536000, immutable".to_string()
+        } else {
+            format!("public, max-age={}", config.tag_cache_ttl)
+        }
+    }
+}
+
+async fn resolve_version(
+    connection: &mut SqliteConnection,
+    dir: &str,
+    name: &str,
+    version_or_tag: &str,
+) -> Result<ResolvedVersion, HttpError> {
+    let dir = DirRecord::find_by_name(connection, dir)
+        .await
+        .map_err(internal_error)?
+        .ok_or(not_found("Dir"))?;
+
+    let file = FileRecord::find_by_name(connection, &dir.id, name)
+        .await
+        .map_err(internal_error)?
+        .ok_or(not_found("File"))?;
+
+    if let Some(version) = FileVersionRecord::find_by_version(connection, &file.id, version_or_tag)
+        .await
+        .map_err(internal_error)?
+    {
+        return Ok(ResolvedVersion::pinned(file, version));
+    }
+
+    if let Some(version) = FileVersionRecord::find_by_tag(connection, &file.id, version_or_tag)
+        .await
+        .map_err(internal_error)?
+    {
+        // the tag may move to an older version, so it must not look unmodified since the move
+        let activated_at =
+            FileVersionTagRecord::find_by_name(connection, &version.id, version_or_tag)
+                .await
+                .map_err(internal_error)?
+                .map(|tag| tag.activated_at)
+                .unwrap_or(version.created_at)
+                .max(version.created_at);
+        return Ok(ResolvedVersion::moving(file, version, activated_at));
+    }
+
+    if version_or_tag == LATEST_TAG {
+        if let Some(version) = FileVersionRecord::find_latest(connection, &file.id)
+            .await
+            .map_err(internal_error)?
+        {
+            let created_at = version.created_at;
+            return Ok(ResolvedVersion::moving(file, version, created_at));
+        }
+    }
+
+    Err(not_found("File version"))
+}
+
+async fn serve_version(
+    connection: &mut SqliteConnection,
+    storage: &Storage,
+    cache: Option<&EdgeCache>,
+    config: &CliConfig,
+    method: &Method,
+    headers: &HeaderMap,
+    resolved: ResolvedVersion,
+) -> Result<Response, HttpError> {
+    if resolved.file_version.deleted_at.is_some() {
+        return Err(not_found("File version"));
+    }
+
+    let cache_control = resolved.cache_control(config);
+    let ResolvedVersion {
+        file,
+        file_version,
+        validators,
+        ..
+    } = resolved;
+
+    let content_type = content_type::content_type(&file.name, &file.file_type);
+    let content_type = content_type.as_str();
+    let size = file_version.size;
+
+    if let Some(status) = validators.evaluate(headers) {
+        return Ok((
+            status,
+            [(header::CACHE_CONTROL, cache_control)],
+            validators.headers(),
+        )
+            .into_response());
+    }
+
+    let ranges = if method == Method::GET && validators.if_range_allows(headers) {
+        ByteRanges::parse(
+            headers.get(header::RANGE).and_then(|v| v.to_str().ok()),
+            size,
+        )
+    } else {
+        ByteRanges::Full
+    };
+
+    let common_headers = (
+        [
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        validators.headers(),
+    );
+
+    if ranges == ByteRanges::Unsatisfiable {
+        return Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            common_headers,
+            [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+        )
+            .into_response());
+    }
+
+    if method == Method::HEAD {
+        return Ok((
+            common_headers,
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (header::CONTENT_LENGTH, size.to_string()),
+            ],
+        )
+            .into_response());
+    }
+
+    let (dir, filename) = file_version
+        .path(connection)
+        .await
+        .map_err(internal_error)?;
+
+    // a miss on edge node is pulled from main server, ranges are served once it is cached
+    let miss = cache.filter(|cache| !cache.touch(&filename));
+    let ranges = match (miss, ranges) {
+        (Some(cache), ByteRanges::Partial(_)) if !cache.fits(size) => ByteRanges::Full,
+        (Some(cache), ranges @ ByteRanges::Partial(_)) => {
+            cache
+                .fill(
+                    &dir,
+                    &filename,
+                    &file_version.id.to_string(),
+                    size,
+                    file_version.sha256.as_deref(),
+                )
+                .await
+                .map_err(internal_error)?;
+            ranges
+        }
+        (_, ranges) => ranges,
+    };
+
+    let response = match ranges {
+        ByteRanges::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            let body = range::single_part_body(storage, &dir, &filename, range)
+                .await
+                .map_err(internal_error)?;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                common_headers,
+                [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (
+                        header::CONTENT_LENGTH,
+                        (range.end - range.start).to_string(),
+                    ),
+                    (header::CONTENT_RANGE, range::content_range(range, size)),
+                ],
+                body,
+            )
+                .into_response()
+        }
+        ByteRanges::Partial(ranges) => {
+            let multipart =
+                range::multipart_body(storage, &dir, &filename, &ranges, content_type, size)
+                    .await
+                    .map_err(internal_error)?;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                common_headers,
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        format!("multipart/byteranges; boundary={}", multipart.boundary),
+                    ),
+                    (header::CONTENT_LENGTH, multipart.content_length.to_string()),
+                ],
+                multipart.body,
+            )
+                .into_response()
+        }
+        _ => {
+            let body = match miss {
+                Some(cache) => cache.stream(
+                    &dir,
+                    &filename,
+                    &file_version.id.to_string(),
+                    size,
+                    file_version.sha256.as_deref(),
+                ),
+                None => range::single_part_body(storage, &dir, &filename, &(0..size))
+                    .await
+                    .map_err(internal_error)?,
+            };
+            (
+                common_headers,
+                [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (header::CONTENT_LENGTH, size.to_string()),
+                ],
+                body,
+            )
+                .into_response()
+        }
+    };
+
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_by_id(
+    Path(file_version_id): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    State(config): State<Arc<CliConfig>>,
+    DatabaseConnection(mut connection): DatabaseConnection,
+    storage: Storage,
+    State(cache): State<Option<EdgeCache>>,
+    redirector: Redirector,
+) -> Result<Response, HttpError> {
+    let file_version_id =
+        uuid::Uuid::parse_str(&file_version_id).map_err(|_| not_found("File version"))?;
+
+    let file_version = FileVersionRecord::find_by_id(&mut connection, &file_version_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(not_found("File version"))?;
+
+    if let Some(redirect) = redirector
+        .redirect(&mut connection, &file_version)
+        .await
+        .map_err(internal_error)?
+    {
+        return Ok(redirect);
+    }
+
+    let file = FileRecord::find_by_id(&mut connection, &file_version.file_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(not_found("File"))?;
+
+    serve_version(
+        &mut connection,
+        &storage,
+        cache.as_ref(),
+        &config,
+        &method,
+        &headers,
+        ResolvedVersion::pinned(file, file_version),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_by_path(
+    Path(path): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    State(config): State<Arc<CliConfig>>,
+    DatabaseConnection(mut connection): DatabaseConnection,
+    storage: Storage,
+    State(cache): State<Option<EdgeCache>>,
+    redirector: Redirector,
+) -> Result<Response, HttpError> {
+    let (dir, file) = path.rsplit_once('/').ok_or(not_found("File"))?;
+    let (name, version_or_tag) = file.rsplit_once('@').unwrap_or((file, LATEST_TAG));
+
+    let resolved = resolve_version(&mut connection, dir, name, version_or_tag).await?;
+
+    if let Some(redirect) = redirector
+        .redirect(&mut connection, &resolved.file_version)
+        .await
+        .map_err(internal_error)?
+    {
+        return Ok(redirect);
+    }
+
+    serve_version(
+        &mut connection,
+        &storage,
+        cache.as_ref(),
+        &config,
+        &method,
+        &headers,
+        resolved,
+    )
+    .await
+}
+
 pub fn create_router() -> Router<AppState> {
     let cors = CorsLayer::default()
-        .allow_methods([Method::GET])
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers([
+            header::RANGE,
+            header::IF_RANGE,
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+        ])
+        .expose_headers([
+            header::ACCEPT_RANGES,
+            header::CONTENT_RANGE,
+            header::CONTENT_LENGTH,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ])
         .allow_origin(cors::Any);
-    Router::new().route("/health", get(health)).layer(cors)
+    Router::new()
+        .route("/health", get(health))
+        .route("/v/:file_version_id", get(download_by_id))
+        .route("/f/*path", get(download_by_path))
+        .layer(cors)
 }
@@ -1,4 +1,8 @@
+mod conditional;
+mod content_type;
 pub mod http;
+mod range;
+mod redirect;
 
 use crate::app_state::AppState;
 use axum::Router;
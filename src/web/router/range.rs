@@ -0,0 +1,235 @@
+use std::{io, ops::Range, pin::Pin};
+
+use anyhow::Result;
+use axum::body::Body;
+use bytes::Bytes;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+
+use crate::Storage;
+
+const MAX_RANGES: usize = 16;
+
+type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges {
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+impl ByteRanges {
+    // malformed headers are ignored and the whole representation is served, RFC 9110 section 14.2
+    pub fn parse(value: Option<&str>, size: u64) -> Self {
+        let Some(specs) = value.and_then(|v| v.trim().strip_prefix("bytes=")) else {
+            return Self::Full;
+        };
+
+        let mut ranges = Vec::new();
+        for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((start, end)) = spec.split_once('-') else {
+                return Self::Full;
+            };
+            let range = match (start.trim(), end.trim()) {
+                ("", "") => return Self::Full,
+                ("", suffix) => match suffix.parse::<u64>() {
+                    Ok(0) => continue,
+                    Ok(suffix) => size.saturating_sub(suffix)..size,
+                    Err(_) => return Self::Full,
+                },
+                (start, end) => {
+                    let Ok(start) = start.parse::<u64>() else {
+                        return Self::Full;
+                    };
+                    let end = match end {
+                        "" => size,
+                        end => match end.parse::<u64>() {
+                            Ok(end) if end >= start => end.saturating_add(1).min(size),
+                            _ => return Self::Full,
+                        },
+                    };
+                    start..end
+                }
+            };
+            if range.start < size {
+                ranges.push(range);
+            }
+        }
+
+        if ranges.len() > MAX_RANGES {
+            return Self::Full;
+        }
+        if ranges.is_empty() {
+            return Self::Unsatisfiable;
+        }
+        Self::Partial(ranges)
+    }
+}
+
+pub fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+async fn open_range(
+    storage: &Storage,
+    dir: &str,
+    filename: &str,
+    range: &Range<u64>,
+) -> Result<BodyStream> {
+    let reader = storage.read(dir, filename, Some(range.clone())).await?;
+    Ok(Box::pin(ReaderStream::new(reader)))
+}
+
+pub async fn single_part_body(
+    storage: &Storage,
+    dir: &str,
+    filename: &str,
+    range: &Range<u64>,
+) -> Result<Body> {
+    let stream = open_range(storage, dir, filename, range).await?;
+    Ok(Body::from_stream(stream))
+}
+
+pub struct Multipart {
+    pub boundary: String,
+    pub content_length: u64,
+    pub body: Body,
+}
+
+pub async fn multipart_body(
+    storage: &Storage,
+    dir: &str,
+    filename: &str,
+    ranges: &[Range<u64>],
+    content_type: &str,
+    size: u64,
+) -> Result<Multipart> {
+    let boundary = uuid::Uuid::now_v7().simple().to_string();
+
+    let mut content_length = 0;
+    let mut stream: BodyStream = Box::pin(tokio_stream::empty());
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range, size)
+        );
+        content_length += part_header.len() as u64 + (range.end - range.start);
+
+        let part = open_range(storage, dir, filename, range).await?;
+        stream = Box::pin(
+            stream
+                .chain(tokio_stream::once(Ok(Bytes::from(part_header))))
+                .chain(part),
+        );
+    }
+    let trailer = format!("\r\n--{boundary}--\r\n");
+    content_length += trailer.len() as u64;
+    stream = Box::pin(stream.chain(tokio_stream::once(Ok(Bytes::from(trailer)))));
+
+    Ok(Multipart {
+        boundary,
+        content_length,
+        body: Body::from_stream(stream),
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounded_range() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=0-499"), 1000),
+            ByteRanges::Partial(vec![0..500])
+        );
+    }
+
+    #[test]
+    fn clamps_range_end_to_size() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=900-2000"), 1000),
+            ByteRanges::Partial(vec![900..1000])
+        );
+    }
+
+    #[test]
+    fn parses_open_ended_range() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=500-"), 1000),
+            ByteRanges::Partial(vec![500..1000])
+        );
+    }
+
+    #[test]
+    fn parses_suffix_range() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=-200"), 1000),
+            ByteRanges::Partial(vec![800..1000])
+        );
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=-5000"), 1000),
+            ByteRanges::Partial(vec![0..1000])
+        );
+    }
+
+    #[test]
+    fn parses_multiple_ranges() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=0-9, 20-29,-5"), 100),
+            ByteRanges::Partial(vec![0..10, 20..30, 95..100])
+        );
+    }
+
+    #[test]
+    fn drops_unsatisfiable_ranges_of_a_set() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=0-9,5000-"), 100),
+            ByteRanges::Partial(vec![0..10])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_when_no_range_fits() {
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=1000-"), 1000),
+            ByteRanges::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=-0"), 1000),
+            ByteRanges::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRanges::parse(Some("bytes=0-"), 0),
+            ByteRanges::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn malformed_header_serves_full() {
+        assert_eq!(ByteRanges::parse(None, 1000), ByteRanges::Full);
+        assert_eq!(ByteRanges::parse(Some("items=0-1"), 1000), ByteRanges::Full);
+        assert_eq!(ByteRanges::parse(Some("bytes=-"), 1000), ByteRanges::Full);
+        assert_eq!(ByteRanges::parse(Some("bytes=9-1"), 1000), ByteRanges::Full);
+        assert_eq!(ByteRanges::parse(Some("bytes=a-b"), 1000), ByteRanges::Full);
+    }
+
+    #[test]
+    fn too_many_ranges_serve_full() {
+        let specs = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            ByteRanges::parse(Some(&format!("bytes={specs}")), 1000),
+            ByteRanges::Full
+        );
+    }
+
+    #[test]
+    fn formats_content_range() {
+        assert_eq!(content_range(&(0..500), 1000), "bytes 0-499/1000");
+    }
+}
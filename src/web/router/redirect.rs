@@ -0,0 +1,100 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use sqlx::SqliteConnection;
+
+use crate::{
+    config::WebMode,
+    database::{
+        files::records::file_version_record::FileVersionRecord,
+        nodes::records::node_record::NodeRecord,
+    },
+    geo::GeoIp,
+    AppState,
+};
+
+/// Sends clients of a redirecting web server to the closest node holding the version
+#[derive(Debug)]
+pub struct Redirector {
+    geoip: Option<GeoIp>,
+    client_ip: Option<IpAddr>,
+    path: String,
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    let value = headers.get("x-forwarded-for")?.to_str().ok()?;
+    // the first entry is the original client, the rest are proxies
+    value.split(',').next()?.trim().parse().ok()
+}
+
+impl Redirector {
+    pub async fn redirect(
+        &self,
+        connection: &mut SqliteConnection,
+        file_version: &FileVersionRecord,
+    ) -> anyhow::Result<Option<Response>> {
+        let (Some(geoip), Some(client_ip)) = (&self.geoip, self.client_ip) else {
+            return Ok(None);
+        };
+        if file_version.deleted_at.is_some() {
+            return Ok(None);
+        }
+
+        let nodes = NodeRecord::get_holding(connection, &file_version.id).await?;
+        let Some(node) = geoip.closest_node(client_ip, nodes) else {
+            return Ok(None);
+        };
+
+        let location = format!("{}{}", node.url.trim_end_matches('/'), self.path);
+        tracing::debug!("Redirecting {client_ip} to {location}");
+
+        let response = (
+            StatusCode::FOUND,
+            [
+                (header::LOCATION, location),
+                // the target depends on the client address
+                (header::CACHE_CONTROL, "no-store".to_string()),
+            ],
+        )
+            .into_response();
+
+        Ok(Some(response))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Redirector {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let geoip = match state.config.web_mode {
+            WebMode::Serve => None,
+            WebMode::Redirect => state.geoip.clone(),
+        };
+        let client_ip = forwarded_for(&parts.headers).or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| parts.uri.path().to_string());
+
+        Ok(Self {
+            geoip,
+            client_ip,
+            path,
+        })
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    future::Future,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use clap::Parser;
+use qcdn::{
+    config::CliConfig,
+    database::Database,
+    grpc::{
+        qcdn_cluster_client::QcdnClusterClient, qcdn_files_client::QcdnFilesClient, upload_request,
+        ClusterRole, ClusterStatusResponse, DownloadRequest, FilePart, FileType, UploadMeta,
+        UploadRequest,
+    },
+    manager,
+};
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+use tonic::Status;
+
+const WAIT: Duration = Duration::from_secs(20);
+
+/// Main server running on its own runtime, so it can be killed without the rest of the test
+struct Member {
+    url: String,
+    config: CliConfig,
+    runtime: Option<Runtime>,
+}
+
+impl Member {
+    fn new(root: &Path, port: u16, peers: Vec<String>, election_timeout: u64) -> Self {
+        let dir = root.join(port.to_string());
+        let config = CliConfig::try_parse_from([
+            "manager".to_string(),
+            format!("--port={port}"),
+            format!("--db-path={}", dir.join("qcdn.db").display()),
+            format!("--storage-dir={}", dir.join("storage").display()),
+            format!("--cluster-peers={}", peers.join(",")),
+            format!("--election-timeout={election_timeout}"),
+            "--replication-timeout=5".to_string(),
+        ])
+        .unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        Self {
+            url: config.cluster_url(),
+            config,
+            runtime: None,
+        }
+    }
+
+    fn start(&mut self) {
+        let runtime = Runtime::new().unwrap();
+        let config = self.config.clone();
+        runtime.spawn(async move {
+            // the port of a killed member may not be released yet
+            while let Err(e) = manager::run(&config).await {
+                eprintln!("Main server on {} failed: {e}", config.port);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        self.runtime = Some(runtime);
+    }
+
+    fn kill(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+
+    async fn status(&self) -> Option<ClusterStatusResponse> {
+        let mut client = QcdnClusterClient::connect(self.url.clone()).await.ok()?;
+        let status = client.get_cluster_status(()).await.ok()?;
+        Some(status.into_inner())
+    }
+
+    /// Seq, term, kind and version of every change in the log
+    async fn change_log(&self) -> Vec<(i64, i64, i64, String)> {
+        let db = Database::create(&self.config.db_path).await.unwrap();
+        sqlx::query_as("SELECT seq, term, kind, file_version_id FROM change_log ORDER BY seq")
+            .fetch_all(&db.0)
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Pull mode node process, falling back to the cluster peers when its main server is gone
+struct Node {
+    url: String,
+    child: Child,
+}
+
+impl Node {
+    fn start(root: &Path, main_server_url: &str, peers: &[String]) -> Self {
+        let dir = root.join("node");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (port, grpc_port) = (free_port(), free_port());
+        let child = Command::new(env!("CARGO_BIN_EXE_node"))
+            .current_dir(&dir)
+            .env("FS_DB_PATH", dir.join("qcdn.db"))
+            .env("FS_STORAGE_DIR", dir.join("storage"))
+            .env("FS_PORT", port.to_string())
+            .env("FS_GRPC_PORT", grpc_port.to_string())
+            .env("FS_BASE_URL", format!("http://127.0.0.1:{port}"))
+            .env("FS_MAIN_SERVER_URL", main_server_url)
+            .env("FS_CLUSTER_PEERS", peers.join(","))
+            .env("FS_RECONNECT_MIN_DELAY", "100")
+            .env("FS_RECONNECT_MAX_DELAY", "500")
+            .stdout(std::fs::File::create(dir.join("node.log")).unwrap())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Self {
+            url: format!("http://127.0.0.1:{grpc_port}"),
+            child,
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// Three members knowing each other, started
+fn cluster(root: &Path, election_timeout: u64) -> Vec<Member> {
+    let ports = [free_port(), free_port(), free_port()];
+    let urls = ports.map(|port| format!("http://127.0.0.1:{port}"));
+    let mut members: Vec<_> = ports
+        .iter()
+        .map(|&port| {
+            let peers = urls
+                .iter()
+                .filter(|url| !url.ends_with(&format!(":{port}")))
+                .cloned()
+                .collect();
+            Member::new(root, port, peers, election_timeout)
+        })
+        .collect();
+    for member in &mut members {
+        member.start();
+    }
+    members
+}
+
+fn temp_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("qcdn-{name}-{}", std::process::id()));
+    std::fs::remove_dir_all(&root).ok();
+    root
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn eventually<T, F, Fut>(what: &str, mut f: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    tokio::time::timeout(WAIT, async {
+        loop {
+            if let Some(value) = f().await {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {what}"))
+}
+
+/// Index of the leader every running member agrees on, elected after `term`
+async fn leader(members: &[Member], running: &[usize], term: u64) -> usize {
+    eventually("leader", || async {
+        let mut urls = vec![];
+        for &i in running {
+            let status = members[i].status().await?;
+            if status.term <= term {
+                return None;
+            }
+            urls.push(status.leader_url?);
+        }
+        urls.dedup();
+        let [leader_url] = &urls[..] else {
+            return None;
+        };
+        running
+            .iter()
+            .copied()
+            .find(|&i| &members[i].url == leader_url)
+    })
+    .await
+}
+
+/// Waits until `member` holds the same log as `leader`, the seq alone may match
+/// before a tail the leader lacks is taken back
+async fn caught_up(members: &[Member], member: usize, leader: usize) {
+    let log = members[leader].change_log().await;
+    eventually("member to catch up", || async {
+        (members[member].change_log().await == log).then_some(())
+    })
+    .await;
+}
+
+async fn upload(url: &str, name: &str, bytes: &[u8]) -> Result<String, Status> {
+    let requests = vec![
+        UploadRequest {
+            request: Some(upload_request::Request::Meta(UploadMeta {
+                dir: "assets".to_string(),
+                name: name.to_string(),
+                file_type: FileType::Text.into(),
+                version: "1".to_string(),
+                size: bytes.len() as u64,
+                ..Default::default()
+            })),
+        },
+        UploadRequest {
+            request: Some(upload_request::Request::Part(FilePart {
+                bytes: bytes.to_vec(),
+            })),
+        },
+    ];
+    let response = QcdnFilesClient::connect(url.to_string())
+        .await
+        .unwrap()
+        .upload(tokio_stream::iter(requests))
+        .await?;
+    Ok(response.into_inner().file_version_id)
+}
+
+async fn download(url: &str, file_version_id: &str) -> Option<Vec<u8>> {
+    let mut files = QcdnFilesClient::connect(url.to_string()).await.ok()?;
+    let mut parts = files
+        .download(DownloadRequest {
+            file_version_id: file_version_id.to_string(),
+        })
+        .await
+        .ok()?
+        .into_inner();
+    let mut bytes = vec![];
+    while let Some(part) = parts.next().await {
+        bytes.extend(part.ok()?.bytes);
+    }
+    Some(bytes)
+}
+
+#[test]
+fn leader_failover() {
+    let root = temp_root("cluster-failover");
+    let mut members = cluster(&root, 300);
+
+    let client = Runtime::new().unwrap();
+    client.block_on(async {
+        let first = leader(&members, &[0, 1, 2], 0).await;
+        let term = members[first].status().await.unwrap().term;
+        let follower = (first + 1) % 3;
+
+        let peers: Vec<_> = (0..3)
+            .filter(|&i| i != first)
+            .map(|i| members[i].url.clone())
+            .collect();
+        let node = Node::start(&root, &members[first].url, &peers);
+
+        // a follower forwards writes to the leader
+        let before = upload(&members[follower].url, "before.txt", b"before failover")
+            .await
+            .unwrap();
+        let downloaded = eventually("node to replicate", || download(&node.url, &before)).await;
+        assert_eq!(downloaded, b"before failover");
+
+        members[first].kill();
+        let running: Vec<_> = (0..3).filter(|&i| i != first).collect();
+        let second = leader(&members, &running, term).await;
+        let status = members[second].status().await.unwrap();
+        assert_eq!(status.role(), ClusterRole::Leader);
+
+        let follower = running.iter().copied().find(|&i| i != second).unwrap();
+        let after = upload(&members[follower].url, "after.txt", b"after failover")
+            .await
+            .unwrap();
+
+        // the node lost its main server and follows the new leader
+        let downloaded = eventually("node to follow the new leader", || {
+            download(&node.url, &after)
+        })
+        .await;
+        assert_eq!(downloaded, b"after failover");
+
+        // old leader comes back as follower of the new one with every change
+        members[first].start();
+        caught_up(&members, first, second).await;
+        let status = members[first].status().await.unwrap();
+        assert_eq!(status.role(), ClusterRole::Follower);
+        assert_eq!(status.leader_url.as_ref(), Some(&members[second].url));
+
+        for (id, bytes) in [
+            (&before, &b"before failover"[..]),
+            (&after, &b"after failover"[..]),
+        ] {
+            let downloaded =
+                eventually("bytes on old leader", || download(&members[first].url, id)).await;
+            assert_eq!(downloaded, bytes);
+        }
+    });
+
+    drop(members);
+    std::fs::remove_dir_all(root).ok();
+}
+
+#[test]
+fn uncommitted_tail_is_taken_back() {
+    let root = temp_root("cluster-tail");
+    // long enough for a write to reach the leader log before it notices it is alone
+    let mut members = cluster(&root, 1000);
+
+    let client = Runtime::new().unwrap();
+    client.block_on(async {
+        let first = leader(&members, &[0, 1, 2], 0).await;
+        let committed = upload(&members[first].url, "committed.txt", b"committed")
+            .await
+            .unwrap();
+        let term = members[first].status().await.unwrap().term;
+
+        // the leader appends a change no follower gets
+        let followers: Vec<_> = (0..3).filter(|&i| i != first).collect();
+        for &i in &followers {
+            members[i].kill();
+        }
+        let lost = upload(&members[first].url, "lost.txt", b"lost").await;
+        assert!(lost.is_err());
+        let tail = members[first].change_log().await;
+        assert_eq!(tail.len(), 2);
+        let lost = tail[1].3.clone();
+        members[first].kill();
+
+        // the followers go on without it and write at the same seq
+        for &i in &followers {
+            members[i].start();
+        }
+        let second = leader(&members, &followers, term).await;
+        let kept = upload(&members[second].url, "kept.txt", b"kept")
+            .await
+            .unwrap();
+
+        members[first].start();
+        caught_up(&members, first, second).await;
+
+        // the tail is taken back and the seq is used again by the change of the new leader
+        let log = members[first].change_log().await;
+        let seqs: Vec<_> = log.iter().map(|change| change.0).collect();
+        assert_eq!(seqs, (1..=log.len() as i64).collect::<Vec<_>>());
+        assert!(log.iter().all(|change| change.3 != lost));
+
+        assert!(download(&members[first].url, &lost).await.is_none());
+        for (id, bytes) in [(&committed, &b"committed"[..]), (&kept, &b"kept"[..])] {
+            let downloaded =
+                eventually("bytes on old leader", || download(&members[first].url, id)).await;
+            assert_eq!(downloaded, bytes);
+        }
+    });
+
+    drop(members);
+    std::fs::remove_dir_all(root).ok();
+}
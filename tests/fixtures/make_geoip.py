@@ -0,0 +1,91 @@
+"""Writes geoip-city.mmdb, a tiny MaxMind DB with city locations for three IPv4 /8 networks.
+
+Usage: python3 make_geoip.py
+"""
+import os
+import struct
+
+NETWORKS = [
+    ("1.0.0.0", 8, 52.52, 13.405),  # Berlin
+    ("2.0.0.0", 8, 40.7128, -74.006),  # New York
+    ("3.0.0.0", 8, 35.6762, 139.6503),  # Tokyo
+]
+
+
+def control(kind, size):
+    if kind <= 7:
+        return bytes([kind << 5 | size])
+    return bytes([size, kind - 7])
+
+
+def uint(kind, value):
+    data = value.to_bytes((value.bit_length() + 7) // 8, "big") if value else b""
+    return control(kind, len(data)) + data
+
+
+def encode(value):
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, float):
+        return control(3, 8) + struct.pack(">d", value)
+    if isinstance(value, dict):
+        return control(7, len(value)) + b"".join(encode(k) + encode(v) for k, v in value.items())
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(v) for v in value)
+    raise TypeError(value)
+
+
+def bits(ip, prefix):
+    n = struct.unpack(">I", bytes(int(p) for p in ip.split(".")))[0]
+    return [(n >> (31 - i)) & 1 for i in range(prefix)]
+
+
+data = b""
+leaves = []
+for ip, prefix, latitude, longitude in NETWORKS:
+    leaves.append((bits(ip, prefix), len(data)))
+    data += encode({"location": {"latitude": latitude, "longitude": longitude}})
+
+# trie of [left, right] where an int is a node index and a tuple marks a data offset
+nodes = [[None, None]]
+for path, offset in leaves:
+    node = 0
+    for bit in path[:-1]:
+        if nodes[node][bit] is None:
+            nodes.append([None, None])
+            nodes[node][bit] = len(nodes) - 1
+        node = nodes[node][bit]
+    nodes[node][path[-1]] = ("data", offset)
+
+node_count = len(nodes)
+
+
+def record(value):
+    if value is None:
+        return node_count
+    if isinstance(value, tuple):
+        return node_count + 16 + value[1]
+    return value
+
+
+tree = b"".join(
+    record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big") for left, right in nodes
+)
+
+metadata = (
+    control(7, 9)
+    + encode("binary_format_major_version") + uint(5, 2)
+    + encode("binary_format_minor_version") + uint(5, 0)
+    + encode("build_epoch") + uint(9, 1704067200)
+    + encode("database_type") + encode("GeoIP2-City")
+    + encode("description") + encode({"en": "qcdn test fixture"})
+    + encode("ip_version") + uint(5, 4)
+    + encode("languages") + encode(["en"])
+    + encode("node_count") + uint(6, node_count)
+    + encode("record_size") + uint(5, 24)
+)
+
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "geoip-city.mmdb")
+with open(path, "wb") as f:
+    f.write(tree + b"\0" * 16 + data + b"\xab\xcd\xefMaxMind.com" + metadata)